@@ -0,0 +1,105 @@
+use crate::transport::ConversationId;
+
+/// Commands the seller can send from the admin chat.
+/// Chats are given as `tg:<id>` or `web:<id>`, a bare number is a Telegram chat.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AdminCommand {
+    Help,
+    /// Stop replying to buyers
+    Pause,
+    Resume,
+    /// Lift the mute of a chat the injection guard muted
+    Unmute(ConversationId),
+    /// Send a message to a chat as the bot
+    Say(ConversationId, String),
+    /// Bypass the LLM in a chat, relaying the seller's messages instead
+    Takeover(ConversationId),
+    /// Hand the chat taken over back to the LLM
+    Release,
+    /// List the offers waiting for approval
+    Pending,
+    /// Sign a pending offer and send it to the buyer
+    Approve(u64),
+    /// Turn down a pending offer
+    Reject(u64),
+    /// Starts with a `/` but isn't an admin command, or its arguments don't parse
+    Invalid,
+}
+
+/// Usage of the admin commands.
+pub const ADMIN_HELP: &str = "Admin commands:
+/pause - stop replying to buyers
+/resume - start replying to buyers again
+/unmute <chat> - unmute a chat muted for manipulation attempts
+/say <chat> <text> - send a message to a chat
+/takeover <chat> - answer a chat yourself, your messages get relayed to the buyer
+/release - hand the chat back to the bot
+/pending - list the offers waiting for your approval
+/approve <id> - sign a pending offer and send it to the buyer
+/reject <id> - turn down a pending offer";
+
+impl AdminCommand {
+    /// Parses an admin command, `None` if the message isn't a command.
+    pub fn parse(text: &str) -> Option<AdminCommand> {
+        let rest = text.trim().strip_prefix('/')?;
+        let (name, args) = match rest.split_once(char::is_whitespace) {
+            Some((name, args)) => (name, args.trim()),
+            None => (rest, ""),
+        };
+        let name = name.split('@').next().unwrap_or_default().to_lowercase();
+        let (chat, text) = match args.split_once(char::is_whitespace) {
+            Some((chat, text)) => (chat.parse::<ConversationId>().ok(), text.trim()),
+            None => (args.parse::<ConversationId>().ok(), ""),
+        };
+        let id = args.parse::<u64>().ok();
+        Some(match (name.as_str(), chat, id) {
+            ("start" | "help", _, _) => AdminCommand::Help,
+            ("pause", _, _) => AdminCommand::Pause,
+            ("resume", _, _) => AdminCommand::Resume,
+            ("unmute", Some(chat), _) => AdminCommand::Unmute(chat),
+            ("say", Some(chat), _) if !text.is_empty() => AdminCommand::Say(chat, text.to_string()),
+            ("takeover", Some(chat), _) => AdminCommand::Takeover(chat),
+            ("release", _, _) => AdminCommand::Release,
+            ("pending", _, _) => AdminCommand::Pending,
+            ("approve", _, Some(id)) => AdminCommand::Approve(id),
+            ("reject", _, Some(id)) => AdminCommand::Reject(id),
+            _ => AdminCommand::Invalid,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_admin_commands() {
+        assert_eq!(AdminCommand::parse("/pause"), Some(AdminCommand::Pause));
+        assert_eq!(AdminCommand::parse("/resume@auction_bot"), Some(AdminCommand::Resume));
+        assert_eq!(
+            AdminCommand::parse("/unmute web:7"),
+            Some(AdminCommand::Unmute(ConversationId::Web(7)))
+        );
+        assert_eq!(
+            AdminCommand::parse("/say 42  Hello there "),
+            Some(AdminCommand::Say(ConversationId::Telegram(42), "Hello there".to_string()))
+        );
+        assert_eq!(
+            AdminCommand::parse("/takeover tg:-100"),
+            Some(AdminCommand::Takeover(ConversationId::Telegram(-100)))
+        );
+        assert_eq!(AdminCommand::parse("/approve 3"), Some(AdminCommand::Approve(3)));
+        assert_eq!(AdminCommand::parse("/reject 4"), Some(AdminCommand::Reject(4)));
+        assert_eq!(AdminCommand::parse("hello"), None);
+    }
+
+    #[test]
+    fn rejects_missing_arguments() {
+        assert_eq!(AdminCommand::parse("/unmute"), Some(AdminCommand::Invalid));
+        assert_eq!(AdminCommand::parse("/approve web:7"), Some(AdminCommand::Invalid));
+        assert_eq!(AdminCommand::parse("/say 42"), Some(AdminCommand::Invalid));
+        assert_eq!(AdminCommand::parse("/takeover sms:1"), Some(AdminCommand::Invalid));
+        assert_eq!(AdminCommand::parse("/approve one"), Some(AdminCommand::Invalid));
+        assert_eq!(AdminCommand::parse("/unknown"), Some(AdminCommand::Invalid));
+    }
+}
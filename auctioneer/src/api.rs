@@ -0,0 +1,67 @@
+//! Typed API for other processes on the node, like portfolio or treasury apps.
+//! Requests need a capability issued by the auctioneer with `{"admin": true}` as params,
+//! which apps get by requesting it in their manifest.
+
+use crate::protocol::SignedOffer;
+use crate::structs::{AddNFTArgs, NFTKey, NFTListing, UpdateListingArgs};
+use crate::transport::ConversationId;
+use crate::usage::SaleRecord;
+use alloy_primitives::U256;
+use kinode_process_lib::{Address, Capability};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum AuctioneerRequest {
+    AddListing(AddNFTArgs),
+    UpdateListing(UpdateListingArgs),
+    RemoveListing(NFTKey),
+    ListListings,
+    /// The completed sales, oldest first
+    GetSales,
+    /// Pauses replying to buyers with `true`, resumes with `false`
+    PauseSelling(bool),
+    IssueOffer(IssueOfferArgs),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum AuctioneerResponse {
+    AddListing(Result<NFTKey, String>),
+    UpdateListing(Result<(), String>),
+    RemoveListing(Result<(), String>),
+    ListListings(Vec<Listing>),
+    GetSales(Vec<SaleRecord>),
+    PauseSelling { paused: bool },
+    IssueOffer(Result<SignedOffer, String>),
+    /// The request wasn't handled, like when it lacks the capability
+    Error(String),
+}
+
+/// An offer made on the seller's behalf, without negotiation or approval.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct IssueOfferArgs {
+    pub nft_key: NFTKey,
+    /// In wei
+    pub price: U256,
+    pub buyer_address: String,
+    /// The conversation of the buyer, who gets sent the offer and has the NFT reserved for them.
+    /// Without one the offer is only returned, the NFT is still reserved until it expires.
+    #[serde(default)]
+    pub conversation: Option<ConversationId>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Listing {
+    pub nft_key: NFTKey,
+    pub listing: NFTListing,
+    /// Human-readable state of the sale, as buyers see it
+    pub status: String,
+}
+
+/// Whether the request carries the admin capability issued by our process.
+pub fn authorized(our: &Address, capabilities: &[Capability]) -> bool {
+    capabilities.iter().any(|capability| {
+        capability.issuer == *our
+            && serde_json::from_str::<serde_json::Value>(&capability.params).ok()
+                == Some(serde_json::json!({ "admin": true }))
+    })
+}
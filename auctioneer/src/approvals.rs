@@ -0,0 +1,51 @@
+use crate::structs::{FinalizedOfferCommand, NFTKey};
+use crate::transport::ConversationId;
+use serde::{Deserialize, Serialize};
+
+/// An offer waiting for the seller's approval before it gets signed.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PendingOffer {
+    pub id: u64,
+    pub chat_id: ConversationId,
+    pub offer: FinalizedOfferCommand,
+    pub requested_at: u64,
+}
+
+/// Offers waiting for the seller's approval, in the order they came in.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ApprovalQueue {
+    next_id: u64,
+    pub pending: Vec<PendingOffer>,
+}
+
+impl ApprovalQueue {
+    /// Queues an offer, returning its id. A chat's earlier pending offer for the same NFT is replaced.
+    pub fn push(&mut self, chat_id: ConversationId, offer: FinalizedOfferCommand, now: u64) -> u64 {
+        self.pending
+            .retain(|pending| pending.chat_id != chat_id || pending.offer.nft_key != offer.nft_key);
+        self.next_id += 1;
+        self.pending.push(PendingOffer {
+            id: self.next_id,
+            chat_id,
+            offer,
+            requested_at: now,
+        });
+        self.next_id
+    }
+
+    /// Drops the pending offers of a chat, when the buyer withdraws them.
+    pub fn drop_chat(&mut self, chat_id: ConversationId) {
+        self.pending.retain(|pending| pending.chat_id != chat_id);
+    }
+
+    /// Drops the pending offers for an NFT that got sold or removed.
+    pub fn drop_nft(&mut self, nft_key: &NFTKey) {
+        self.pending.retain(|pending| &pending.offer.nft_key != nft_key);
+    }
+
+    /// Takes the pending offer out of the queue, to approve or reject it.
+    pub fn take(&mut self, id: u64) -> Option<PendingOffer> {
+        let index = self.pending.iter().position(|pending| pending.id == id)?;
+        Some(self.pending.remove(index))
+    }
+}
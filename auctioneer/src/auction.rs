@@ -0,0 +1,275 @@
+use alloy_primitives::U256;
+use serde::{Deserialize, Serialize};
+use crate::transport::ConversationId;
+
+/// How a listing gets sold.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub enum SaleMode {
+    /// One-on-one haggling in every chat, bounded by the negotiation policy
+    #[default]
+    Negotiation,
+    /// Timed ascending auction with one highest bid shared by all chats
+    English(EnglishAuction),
+    /// Descending price, the first buyer to accept the current price gets it
+    Dutch(DutchAuction),
+    /// One hidden bid per chat until the deadline, the highest bid wins
+    Sealed(SealedAuction),
+}
+
+impl SaleMode {
+    /// When the auction has to be closed, `None` if there's nothing to close.
+    pub fn deadline(&self) -> Option<u64> {
+        match self {
+            SaleMode::English(auction) if !auction.settled => Some(auction.ends_at),
+            SaleMode::Sealed(auction) if !auction.settled => Some(auction.ends_at),
+            _ => None,
+        }
+    }
+
+    /// Whether the auction has been closed, its bids are the final standings then.
+    pub fn settled(&self) -> bool {
+        match self {
+            SaleMode::English(auction) => auction.settled,
+            SaleMode::Sealed(auction) => auction.settled,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EnglishAuction {
+    /// Unix timestamp at which the auction closes
+    pub ends_at: u64,
+    /// Amount a new bid has to exceed the highest bid by
+    pub min_increment: U256,
+    /// Whether the auction has been closed and the winner settled
+    pub settled: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DutchAuction {
+    /// Price the auction starts at, it decays towards the floor of the listing from there
+    pub start_price: U256,
+    /// Unix timestamp at which the auction started
+    pub starts_at: u64,
+    pub decay: PriceDecay,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SealedAuction {
+    /// Unix timestamp after which no more bids are taken
+    pub ends_at: u64,
+    pub pricing: SealedPricing,
+    /// Whether the auction has been closed and the winner settled
+    pub settled: bool,
+}
+
+/// What the winner of a sealed-bid auction pays.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub enum SealedPricing {
+    /// The winner pays their own bid
+    #[default]
+    FirstPrice,
+    /// The winner pays the second highest bid (Vickrey auction)
+    SecondPrice,
+}
+
+/// How the asking price of a Dutch auction decays over time.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum PriceDecay {
+    /// Drops linearly from the start price to the floor over `duration` seconds
+    Linear { duration: u64 },
+    /// Drops by `step_bps` basis points of the start price every `step_secs` seconds
+    Stepwise { step_secs: u64, step_bps: u64 },
+    /// Halves the distance to the floor every `half_life` seconds
+    Exponential { half_life: u64 },
+}
+
+/// A bid placed in an auction.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Bid {
+    pub chat_id: ConversationId,
+    pub buyer_address: String,
+    pub amount: U256,
+    pub placed_at: u64,
+}
+
+impl EnglishAuction {
+    /// Whether the auction still takes bids.
+    pub fn is_open(&self, now: u64) -> bool {
+        !self.settled && now < self.ends_at
+    }
+
+    /// The lowest amount the next bid can be, the reserve price opens the auction.
+    pub fn min_next_bid(&self, reserve_price: U256, highest_bid: Option<&Bid>) -> U256 {
+        match highest_bid {
+            Some(bid) => bid.amount.saturating_add(self.min_increment),
+            None => reserve_price,
+        }
+    }
+
+    /// Whether the amount can be bid next. It has to beat the highest bid even without an increment,
+    /// so an equal bid doesn't take the lead.
+    pub fn accepts(&self, reserve_price: U256, highest_bid: Option<&Bid>, amount: U256) -> bool {
+        let beats_highest = highest_bid.map_or(true, |bid| amount > bid.amount);
+        beats_highest && amount >= self.min_next_bid(reserve_price, highest_bid)
+    }
+}
+
+impl SealedAuction {
+    /// Whether the auction still takes bids.
+    pub fn is_open(&self, now: u64) -> bool {
+        !self.settled && now < self.ends_at
+    }
+
+    /// Picks the winning bid and the price it pays.
+    /// Bids below the reserve price don't count, and a second price never goes below it.
+    pub fn winner(&self, reserve_price: U256, bids: &[Bid]) -> Option<(Bid, U256)> {
+        let mut valid: Vec<&Bid> = bids
+            .iter()
+            .filter(|bid| bid.amount >= reserve_price)
+            .collect();
+        // highest first, earlier bids win ties
+        valid.sort_by(|a, b| b.amount.cmp(&a.amount).then(a.placed_at.cmp(&b.placed_at)));
+        let winner = (*valid.first()?).clone();
+        let price = match self.pricing {
+            SealedPricing::FirstPrice => winner.amount,
+            SealedPricing::SecondPrice => valid
+                .get(1)
+                .map(|bid| bid.amount)
+                .unwrap_or(reserve_price),
+        };
+        Some((winner, price))
+    }
+}
+
+impl DutchAuction {
+    /// The asking price at the given time, never below the floor.
+    pub fn current_price(&self, floor: U256, now: u64) -> U256 {
+        if self.start_price <= floor {
+            return floor;
+        }
+        let elapsed = now.saturating_sub(self.starts_at);
+        let range = self.start_price - floor;
+        let drop = match self.decay {
+            PriceDecay::Linear { duration } => {
+                if duration == 0 || elapsed >= duration {
+                    range
+                } else {
+                    range * U256::from(elapsed) / U256::from(duration)
+                }
+            }
+            PriceDecay::Stepwise {
+                step_secs,
+                step_bps,
+            } => {
+                let steps = elapsed.checked_div(step_secs).unwrap_or(u64::MAX);
+                let step = self.start_price.saturating_mul(U256::from(step_bps)) / U256::from(10_000);
+                step.saturating_mul(U256::from(steps)).min(range)
+            }
+            PriceDecay::Exponential { half_life } => {
+                if half_life == 0 {
+                    range
+                } else {
+                    let halvings = elapsed / half_life;
+                    let remaining = if halvings >= 256 {
+                        U256::ZERO
+                    } else {
+                        range >> (halvings as usize)
+                    };
+                    // interpolate linearly between two halvings
+                    let partial =
+                        remaining * U256::from(elapsed % half_life) / U256::from(2 * half_life);
+                    range - (remaining - partial)
+                }
+            }
+        };
+        self.start_price - drop
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloy_primitives::utils::parse_units;
+
+    fn eth(amount: &str) -> U256 {
+        parse_units(amount, "ether").unwrap().into()
+    }
+
+    fn bid(chat: i64, amount: &str, placed_at: u64) -> Bid {
+        Bid {
+            chat_id: ConversationId::Telegram(chat),
+            buyer_address: "0x0".to_string(),
+            amount: eth(amount),
+            placed_at,
+        }
+    }
+
+    #[test]
+    fn english_bids_have_to_beat_the_highest_bid() {
+        let mut auction = EnglishAuction {
+            ends_at: 100,
+            min_increment: U256::ZERO,
+            settled: false,
+        };
+        assert!(!auction.accepts(eth("1"), None, eth("0.9")));
+        assert!(auction.accepts(eth("1"), None, eth("1")));
+
+        let highest = bid(1, "2", 0);
+        assert!(!auction.accepts(eth("1"), Some(&highest), eth("2")));
+        assert!(auction.accepts(eth("1"), Some(&highest), eth("2.01")));
+
+        auction.min_increment = eth("0.1");
+        assert!(!auction.accepts(eth("1"), Some(&highest), eth("2.05")));
+        assert!(auction.accepts(eth("1"), Some(&highest), eth("2.1")));
+        assert!(!auction.is_open(100));
+    }
+
+    #[test]
+    fn sealed_auctions_pick_the_highest_bid() {
+        let bids = [bid(1, "1.5", 10), bid(2, "3", 20), bid(3, "3", 5), bid(4, "0.5", 1)];
+        let first_price = SealedAuction {
+            ends_at: 100,
+            pricing: SealedPricing::FirstPrice,
+            settled: false,
+        };
+        let (winner, price) = first_price.winner(eth("1"), &bids).unwrap();
+        // the earlier of the equal bids wins
+        assert_eq!(winner.chat_id, ConversationId::Telegram(3));
+        assert_eq!(price, eth("3"));
+
+        let second_price = SealedAuction {
+            pricing: SealedPricing::SecondPrice,
+            ..first_price
+        };
+        assert_eq!(second_price.winner(eth("1"), &bids).unwrap().1, eth("3"));
+        assert_eq!(second_price.winner(eth("1"), &bids[..1]).unwrap().1, eth("1"));
+        assert!(second_price.winner(eth("1"), &bids[3..]).is_none());
+    }
+
+    #[test]
+    fn dutch_prices_decay_to_the_floor() {
+        let auction = |decay| DutchAuction {
+            start_price: eth("3"),
+            starts_at: 1000,
+            decay,
+        };
+        let linear = auction(PriceDecay::Linear { duration: 100 });
+        assert_eq!(linear.current_price(eth("1"), 1000), eth("3"));
+        assert_eq!(linear.current_price(eth("1"), 1050), eth("2"));
+        assert_eq!(linear.current_price(eth("1"), 5000), eth("1"));
+
+        let stepwise = auction(PriceDecay::Stepwise {
+            step_secs: 10,
+            step_bps: 1_000,
+        });
+        assert_eq!(stepwise.current_price(eth("1"), 1019), eth("2.7"));
+        assert_eq!(stepwise.current_price(eth("1"), 5000), eth("1"));
+
+        let exponential = auction(PriceDecay::Exponential { half_life: 100 });
+        assert_eq!(exponential.current_price(eth("1"), 1100), eth("2"));
+        assert_eq!(exponential.current_price(eth("1"), 1200), eth("1.5"));
+        assert_eq!(exponential.current_price(eth("1"), 1_000_000), eth("1"));
+    }
+}
@@ -0,0 +1,103 @@
+use frankenstein::BotCommand;
+
+/// Commands the bot answers without the LLM, with their description for Telegram's command menu.
+pub const COMMANDS: [(&str, &str); 9] = [
+    ("start", "Start chatting with the auctioneer"),
+    ("help", "Show what the bot can do"),
+    ("list", "List the NFTs for sale"),
+    ("item", "Show the details of an NFT: /item <name>"),
+    ("myoffers", "Show your offers, bids and reservations"),
+    ("address", "Set your ETH address: /address <0x..>"),
+    ("bid", "Place a sealed bid: /bid <amount> <name>"),
+    ("cancel", "Withdraw your pending offers"),
+    ("reset", "Start the conversation over"),
+];
+
+/// A command sent by a buyer, parsed from a message starting with `/`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Start,
+    Help,
+    List,
+    Item(String),
+    MyOffers,
+    Address(String),
+    Bid(String),
+    Cancel,
+    Reset,
+    /// Starts with a `/` but isn't one of ours
+    Unknown,
+}
+
+impl Command {
+    /// Parses a command with its arguments, `None` if the message isn't a command.
+    /// Commands addressed to a bot by name, like `/help@some_bot`, are accepted as well.
+    pub fn parse(text: &str) -> Option<Command> {
+        let text = text.trim();
+        let rest = text.strip_prefix('/')?;
+        let (name, args) = match rest.split_once(char::is_whitespace) {
+            Some((name, args)) => (name, args.trim().to_string()),
+            None => (rest, String::new()),
+        };
+        let name = name.split('@').next().unwrap_or_default().to_lowercase();
+        Some(match name.as_str() {
+            "start" => Command::Start,
+            "help" => Command::Help,
+            "list" => Command::List,
+            "item" => Command::Item(args),
+            "myoffers" => Command::MyOffers,
+            "address" => Command::Address(args),
+            "bid" => Command::Bid(args),
+            "cancel" => Command::Cancel,
+            "reset" => Command::Reset,
+            _ => Command::Unknown,
+        })
+    }
+}
+
+/// The commands in the shape `setMyCommands` takes them.
+pub fn bot_commands() -> Vec<BotCommand> {
+    COMMANDS
+        .iter()
+        .map(|(command, description)| BotCommand {
+            command: command.to_string(),
+            description: description.to_string(),
+        })
+        .collect()
+}
+
+/// Lists the commands with their descriptions.
+pub fn help_text() -> String {
+    let lines: Vec<String> = COMMANDS
+        .iter()
+        .map(|(command, description)| format!("/{} - {}", command, description))
+        .collect();
+    format!(
+        "Just tell me what you'd like to buy and make me an offer. You can also use these commands:\n{}",
+        lines.join("\n")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(Command::parse("/start"), Some(Command::Start));
+        assert_eq!(Command::parse("  /HELP  "), Some(Command::Help));
+        assert_eq!(Command::parse("/list@auction_bot"), Some(Command::List));
+        assert_eq!(Command::parse("/item  Bored Ape "), Some(Command::Item("Bored Ape".to_string())));
+        assert_eq!(Command::parse("/item"), Some(Command::Item(String::new())));
+        assert_eq!(Command::parse("/bid 1.5 Ape"), Some(Command::Bid("1.5 Ape".to_string())));
+        assert_eq!(Command::parse("/address 0xabc"), Some(Command::Address("0xabc".to_string())));
+        assert_eq!(Command::parse("/myoffers"), Some(Command::MyOffers));
+        assert_eq!(Command::parse("/frobnicate"), Some(Command::Unknown));
+    }
+
+    #[test]
+    fn leaves_messages_alone() {
+        assert_eq!(Command::parse("I'd pay 2 ETH"), None);
+        assert_eq!(Command::parse("1/2 of the price?"), None);
+    }
+}
@@ -1,29 +1,180 @@
-use llm_interface::openai::ChatParams;
 use llm_interface::openai::Message;
-use llm_interface::api::openai::OpenaiApi;
+use crate::llm::{Answer, Usage};
 use crate::AddNFTArgs;
 use alloy_primitives::{
     utils::{format_ether, parse_units},
     U256,
 };
+use kinode_process_lib::println;
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, VecDeque};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::OnceLock;
 use crate::structs::*;
+use crate::helpers::now;
+use crate::policy::{self, ApprovalPolicy, NegotiationPolicy, PolicyDecision};
+use crate::auction::{Bid, DutchAuction, EnglishAuction, SaleMode, SealedAuction};
+use crate::templates::{PromptTemplate, TemplateStore, TemplateVars};
+use crate::guard::{self, FlaggedMessage, LeakIncident, Secrets};
+use crate::usage::UsageLedger;
+use crate::render::{ListingCard, Rendered};
+use crate::protocol::InventoryItem;
+use crate::api::Listing;
+use crate::transport::{ChatTransport, ConversationId};
 
-/// The maximum number of messages to keep in the chat history buffer
+/// The default maximum number of messages to keep in the chat history buffer
 const BUFFER_CAPACITY: usize = 4;
+/// The smallest chat history buffer, it has to hold at least the buyer's message and the reply
+const MIN_BUFFER_CAPACITY: usize = 2;
+/// The default maximum number of entries per section of the summary of evicted messages
+const SUMMARY_LENGTH: usize = 6;
 
-/// The passkey used when parsing LLM output to link an address given by a user
+/// Legacy passkey used when parsing LLM output to link an address given by a user.
+/// Only used as a fallback when the reply contains no tool calls.
 const ADDRESS_PASSKEY: &str = "Thank you, reserving offer for ";
-/// The passkey used when parsing LLM output to initiate the sale of an NFT
+/// Legacy passkey used when parsing LLM output to initiate the sale of an NFT.
+/// Only used as a fallback when the reply contains no tool calls.
 const SOLD_PASSKEY: &str = "SOLD <name_of_item> for <amount> ETH!";
 
-/// Telegram chat id
-type ChatId = i64;
+/// Persona the system prompt opens with, unless a template sets its own
+pub const DEFAULT_PERSONA: &str = "You are a a chatbot auctioneer selling NFTs.";
+/// Instruction for when the buyer has to give us their address
+const ADDRESS_PROMPT: &str = "The buyer you're chatting with has bought an NFT from you, but you don't have their ETH address. Please ask them for their public address and do not relent. Don't talk about anything else but their address. Iff they give something resembling a ETH address to you, call the link_address tool with it.";
+/// Instruction for how to treat the custom rules of a listing
+const CUSTOM_RULES_PROMPT: &str = "Iff the user is talking about a specific nft, follow custom rules, even disregarding general rules. Only follow one custom rule at a time.";
+/// The general rules of negotiating
+const RULES_PROMPT: &str = "Never reveal the min bid required to the user, only sell if minimum price is bid. Only reveal the address, chain id and id of the nft when specifically asked for it. If someone bids more, don't go back down for that nft.";
+/// Reply sent instead of an LLM reply that kept disclosing secrets
+const SAFE_REPLY: &str = "Let's keep it simple, what's your offer?";
+/// Instruction for how to close a sale
+const OFFER_PROMPT: &str = "Iff a price is reached, call the make_offer tool with the nft and the agreed price.";
+
+/// Reply to a message that was kept from the LLM
+const QUARANTINE_REPLY: &str = "I can only talk about the NFTs I'm selling.";
+/// Reply to every message of a muted chat
+const MUTED_REPLY: &str = "This chat has been muted for repeated attempts to manipulate the bot.";
+/// Maximum number of flagged messages and leak incidents kept for the seller
+const MAX_FLAGGED: usize = 200;
+
+/// How long a signed offer, and with it the reservation of the NFT, stays valid, in seconds
+const OFFER_VALIDITY: u64 = 3600;
+/// Counter-offers a buyer agent gets per NFT, after that the asking price stops coming down
+const MAX_OFFER_ROUNDS: u32 = 10;
+/// NFTs a buyer agent can hold signed offers for at once
+const MAX_PEER_RESERVATIONS: usize = 3;
+/// Reply to buyers while the seller has paused the bot
+const PAUSED_REPLY: &str = "We're closed for a moment, please come back a bit later!";
+/// Reply sent instead of asking the LLM once the budget is used up
+const BUDGET_REPLY: &str = "I'm taking a short break, please come back a bit later!";
+/// Reply sent when the LLM couldn't be reached or returned something unusable
+const FAILED_REPLY: &str = "Sorry, I couldn't answer that just now, could you say it again?";
+/// Reply sent when the LLM only called tools, so the buttons under the reply still show
+pub const EMPTY_REPLY: &str = "Anything else I can help you with?";
+
+/// Conversation id, qualified by its transport
+type ChatId = ConversationId;
 /// Map of chat ids to chat contexts
 type Contexts = HashMap<ChatId, Context>;
 
 
+/// Typed tools the LLM can call instead of writing passkey strings.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "name", content = "arguments", rename_all = "snake_case")]
+pub enum ToolCall {
+    /// The buyer agreed on a price for an NFT
+    MakeOffer { nft_key: NFTKey, price: ToolPrice },
+    /// The buyer gave us the address the offer should be signed for
+    LinkAddress { address: String },
+    /// The buyer bids on an NFT that is being auctioned
+    PlaceBid { nft_key: NFTKey, price: ToolPrice },
+}
+
+/// Price argument of a tool call, the model sends either a JSON number or a string.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum ToolPrice {
+    Number(f64),
+    Text(String),
+}
+
+impl ToolPrice {
+    fn to_wei(&self) -> Option<U256> {
+        match self {
+            ToolPrice::Number(number) => parse_eth_amount(&number.to_string()),
+            ToolPrice::Text(text) => parse_eth_amount(text),
+        }
+    }
+}
+
+impl ToolCall {
+    /// What the bot committed to with the call, remembered in the summary of the chat.
+    fn commitment(&self) -> Option<String> {
+        match self {
+            ToolCall::MakeOffer { nft_key, price } => price
+                .to_wei()
+                .map(|price| format!("offered NFT {} for {} ETH", nft_key.id, format_ether(price))),
+            ToolCall::PlaceBid { nft_key, price } => price
+                .to_wei()
+                .map(|price| format!("placed a bid of {} ETH on NFT {}", format_ether(price), nft_key.id)),
+            ToolCall::LinkAddress { address } => Some(format!("noted buyer address {}", address)),
+        }
+    }
+}
+
+/// The chatbot's reply, the text for the buyer and the tools it called.
+#[derive(Debug, Clone, Default)]
+pub struct LlmReply {
+    pub text: String,
+    pub tool_calls: Vec<ToolCall>,
+}
+
+impl LlmReply {
+    /// A reply that didn't come from the LLM.
+    fn plain(text: &str) -> Self {
+        Self {
+            text: text.to_string(),
+            tool_calls: Vec::new(),
+        }
+    }
+
+    /// Reads the tool calls of the LLM's answer, ignoring unknown tools and malformed arguments.
+    fn from_answer(answer: Answer) -> Self {
+        let tool_calls = answer
+            .tool_calls
+            .into_iter()
+            .filter_map(|call| {
+                let arguments: serde_json::Value = serde_json::from_str(&call.arguments).ok()?;
+                serde_json::from_value(serde_json::json!({
+                    "name": call.name,
+                    "arguments": arguments,
+                }))
+                .ok()
+            })
+            .collect();
+        Self {
+            text: answer.text.trim().to_string(),
+            tool_calls,
+        }
+    }
+
+    /// The text of the reply as a message of the chat, `None` if the LLM only called tools.
+    fn message(&self) -> Option<Message> {
+        (!self.text.is_empty()).then(|| Message {
+            role: "assistant".into(),
+            content: self.text.clone(),
+        })
+    }
+
+    /// The first valid address passed to the link_address tool.
+    fn given_address(&self) -> Option<String> {
+        self.tool_calls.iter().find_map(|tool_call| match tool_call {
+            ToolCall::LinkAddress { address } if is_eth_address(address.trim()) => {
+                Some(address.trim().to_string())
+            }
+            _ => None,
+        })
+    }
+}
+
 /// Represents a chat context for a single user chat
 #[derive(Serialize, Deserialize, Debug, Clone)]
 struct Context {
@@ -33,6 +184,130 @@ struct Context {
     pub buyer_address: Option<String>,
     /// Small chat history buffer, kept small for saving $$$
     chat_history: Buffer<Message>,
+    /// Compact summary of the messages evicted from the chat history buffer
+    summary: ChatSummary,
+    /// The NFT the buyer mentioned last
+    focus: Option<NFTKey>,
+    /// The counter-offer made in the last reply, the buyer can answer it with buttons
+    counter_offer: Option<(NFTKey, U256)>,
+    /// The NFTs whose picture the chat got shown
+    shown_images: HashSet<NFTKey>,
+}
+
+/// How much of a conversation is kept, configurable per deployment.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MemoryConfig {
+    /// Number of messages kept verbatim in the chat history buffer
+    pub history_capacity: usize,
+    /// Maximum number of entries per section of the summary of evicted messages
+    pub summary_length: usize,
+}
+
+impl Default for MemoryConfig {
+    fn default() -> Self {
+        Self {
+            history_capacity: BUFFER_CAPACITY,
+            summary_length: SUMMARY_LENGTH,
+        }
+    }
+}
+
+/// Structured summary of the messages evicted from the chat history buffer,
+/// so the bot doesn't forget earlier bids and promises.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+struct ChatSummary {
+    /// Prices mentioned, with who mentioned them
+    prices: VecDeque<String>,
+    /// Names of the NFTs discussed
+    items: VecDeque<String>,
+    /// Offers and bids the bot committed to
+    commitments: VecDeque<String>,
+    /// Maximum number of entries per section
+    max_entries: usize,
+}
+
+/// What a chat context needs to know about the other chats and the seller's settings.
+#[derive(Debug, Default)]
+struct MarketView {
+    /// The highest bid accepted for each NFT across all chats
+    accepted_bids: HashMap<NFTKey, U256>,
+    /// NFTs reserved for a buyer in another chat
+    reserved_elsewhere: HashSet<NFTKey>,
+    /// The highest bid of each running auction
+    highest_bids: HashMap<NFTKey, U256>,
+    /// The seller's template for the system prompt, the built-in prompt is used if not set
+    template: Option<PromptTemplate>,
+}
+
+/// A message to push to a chat outside of the regular reply.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Notification {
+    pub chat_id: ChatId,
+    pub text: String,
+}
+
+/// What a buyer sent while their chat had a turn in flight.
+#[derive(Debug, Clone)]
+pub enum QueuedInput {
+    Message(String),
+    /// The callback data of a button they pressed
+    Button(String),
+}
+
+/// What became of an offer a buyer agent made over the barter protocol.
+#[derive(Debug, Clone)]
+pub enum OfferDecision {
+    /// Accepted at this price and reserved for the buyer, ready to be signed
+    Accepted(FinalizedOfferCommand),
+    /// Not accepted, the seller asks for this price instead
+    Countered(U256),
+    /// Not accepted, and why
+    Rejected(String),
+}
+
+/// What a chat turn needs next.
+pub enum TurnStep {
+    /// The LLM has to complete these messages for the turn with this id, it may call the tools declared
+    Ask {
+        turn_id: u64,
+        messages: Vec<Message>,
+        tools: Vec<serde_json::Value>,
+    },
+    /// The turn is over, with the chatbot's reply
+    Done(LlmReply),
+}
+
+/// A chat turn in progress, it can take several completions until the reply is final.
+#[derive(Debug, Clone)]
+struct ChatTurn {
+    id: u64,
+    /// The user's message, as passed to the LLM
+    text: String,
+    /// The messages the turn started with
+    messages: Vec<Message>,
+    stage: TurnStage,
+    /// Tokens spent so far
+    usage: Usage,
+}
+
+/// What the last completion of a turn was asked for.
+#[derive(Debug, Clone)]
+enum TurnStage {
+    /// The first reply to the user's message
+    Draft,
+    /// Wording the policy's decisions, keeping the tool calls of the draft
+    Policy { tool_calls: Vec<ToolCall> },
+    /// Rewriting a reply that disclosed secrets
+    LeakRetry {
+        incident: LeakIncident,
+        tool_calls: Vec<ToolCall>,
+    },
+}
+
+/// What the chat context needs next, the retries of a turn only ask for text.
+enum TurnProgress {
+    Ask(Vec<Message>),
+    Done(LlmReply, Option<LeakIncident>),
 }
 
 /// Manages NFT listings and chat contexts for different users.
@@ -40,6 +315,42 @@ struct Context {
 pub struct ContextManager {
     pub nft_listings: HashMap<NFTKey, NFTListing>,
     contexts: Contexts,
+    /// NFTs with a signed offer out, shared by all chats
+    reservations: HashMap<NFTKey, Reservation>,
+    /// Bids of the auctions, shared by all chats, in ascending order
+    bids: HashMap<NFTKey, Vec<Bid>>,
+    /// Messages waiting to be pushed to chats
+    notifications: Vec<Notification>,
+    memory: MemoryConfig,
+    /// Seller-editable system prompt templates
+    pub templates: TemplateStore,
+    /// Number of prompt injection attempts per chat
+    strikes: HashMap<ChatId, u32>,
+    /// Chats that made too many injection attempts, they don't reach the LLM anymore
+    muted: HashSet<ChatId>,
+    /// Buyer messages that matched injection patterns, for the seller to review
+    flagged: VecDeque<FlaggedMessage>,
+    /// Replies that disclosed secrets, for the seller to review
+    leaks: VecDeque<LeakIncident>,
+    /// Tokens spent on the LLM and what they cost
+    pub usage: UsageLedger,
+    /// Addresses buyers bought with before, offered back to them after a reset
+    addresses: HashMap<ChatId, String>,
+    /// Whether the seller paused replying to buyers
+    pub paused: bool,
+    /// Chat the seller answers themselves, bypassing the LLM
+    taken_over: Option<ChatId>,
+    /// Messages waiting to be pushed to the seller's admin chat
+    admin_notifications: Vec<String>,
+    /// Chat turns waiting on the LLM, they don't survive a restart
+    #[serde(skip)]
+    turns: HashMap<ChatId, ChatTurn>,
+    /// Messages and button presses that arrived while their chat had a turn in flight, handled in order once it's done
+    #[serde(skip)]
+    queued: HashMap<ChatId, VecDeque<QueuedInput>>,
+    /// Id of the last turn started
+    #[serde(skip)]
+    last_turn_id: u64,
 }
 
 impl ContextManager {
@@ -60,17 +371,39 @@ impl ContextManager {
                     description: None,
                     custom_prompt: None,
                     min_price: *price,
+                    policy: NegotiationPolicy::default(),
+                    mode: SaleMode::Negotiation,
+                    template: None,
+                    image: None,
+                    approval: ApprovalPolicy::default(),
                 },
             );
         }
         Self {
             nft_listings,
             contexts: HashMap::new(),
+            reservations: HashMap::new(),
+            bids: HashMap::new(),
+            notifications: Vec::new(),
+            memory: MemoryConfig::default(),
+            templates: TemplateStore::default(),
+            strikes: HashMap::new(),
+            muted: HashSet::new(),
+            flagged: VecDeque::new(),
+            leaks: VecDeque::new(),
+            usage: UsageLedger::default(),
+            addresses: HashMap::new(),
+            paused: false,
+            taken_over: None,
+            admin_notifications: Vec::new(),
+            turns: HashMap::new(),
+            queued: HashMap::new(),
+            last_turn_id: 0,
         }
     }
 
     /// Adds a new NFT to the auction list and updates all downstream chat contexts with this new NFT.
-    pub fn add_nft(&mut self, args: AddNFTArgs) {
+    pub fn add_nft(&mut self, args: AddNFTArgs) -> Result<NFTKey, String> {
         let AddNFTArgs {
             nft_name,
             nft_address,
@@ -79,9 +412,53 @@ impl ContextManager {
             nft_description,
             sell_prompt,
             min_price,
+            opening_price,
+            max_concession_bps,
+            min_counter_offers,
+            auction_ends_at,
+            min_increment,
+            dutch_start_price,
+            dutch_decay,
+            sealed_ends_at,
+            sealed_pricing,
+            template,
+            image,
+            approval,
         } = args;
-        let Ok(min_price) = parse_units(&min_price, "ether") else {
-            return;
+        if !is_eth_address(&nft_address) {
+            return Err(format!("{} isn't the address of an NFT contract", nft_address));
+        }
+        self.check_template_selection(template.as_deref(), None)?;
+        // the same amounts as updates and chats take, like "1,5" or "1,500 ETH"
+        let Some(min_price) = parse_eth_amount(&min_price) else {
+            return Err(format!("{} isn't a price in ETH", min_price));
+        };
+        let default_policy = NegotiationPolicy::default();
+        let policy = NegotiationPolicy {
+            opening_price: opening_price.and_then(|price| parse_eth_amount(&price)),
+            max_concession_bps: max_concession_bps.unwrap_or(default_policy.max_concession_bps),
+            min_counter_offers: min_counter_offers.unwrap_or(default_policy.min_counter_offers),
+        };
+        let dutch_start_price = dutch_start_price.and_then(|price| parse_eth_amount(&price));
+        let mode = match (auction_ends_at, sealed_ends_at, dutch_start_price, dutch_decay) {
+            (Some(ends_at), _, _, _) => SaleMode::English(EnglishAuction {
+                ends_at,
+                min_increment: min_increment
+                    .and_then(|increment| parse_eth_amount(&increment))
+                    .unwrap_or_default(),
+                settled: false,
+            }),
+            (None, Some(ends_at), _, _) => SaleMode::Sealed(SealedAuction {
+                ends_at,
+                pricing: sealed_pricing.unwrap_or_default(),
+                settled: false,
+            }),
+            (None, None, Some(start_price), Some(decay)) => SaleMode::Dutch(DutchAuction {
+                start_price,
+                starts_at: now(),
+                decay,
+            }),
+            _ => SaleMode::Negotiation,
         };
         let key = NFTKey {
             id: nft_id,
@@ -93,7 +470,12 @@ impl ContextManager {
             address: nft_address,
             description: nft_description,
             custom_prompt: sell_prompt,
-            min_price: min_price.into(),
+            min_price,
+            policy,
+            mode,
+            template,
+            image,
+            approval: approval.unwrap_or_default(),
         };
 
         self.nft_listings.insert(key.clone(), listing.clone());
@@ -103,47 +485,1314 @@ impl ContextManager {
                 state: NFTState::default(),
             });
         }
+        Ok(key)
+    }
+
+    /// Changes the details and negotiation rules of a listing, in the chat contexts as well.
+    pub fn update_nft(&mut self, args: UpdateListingArgs) -> Result<(), String> {
+        let parse = |price: Option<String>| match price {
+            Some(price) => parse_eth_amount(&price)
+                .map(Some)
+                .ok_or_else(|| format!("{} isn't a price in ETH", price)),
+            None => Ok(None),
+        };
+        let min_price = parse(args.min_price)?;
+        let opening_price = parse(args.opening_price)?;
+        let Some(listing) = self.nft_listings.get_mut(&args.nft_key) else {
+            return Err("That NFT isn't listed.".to_string());
+        };
+        if let Some(name) = args.nft_name {
+            listing.name = name;
+        }
+        if let Some(description) = args.nft_description {
+            listing.description = Some(description);
+        }
+        if let Some(sell_prompt) = args.sell_prompt {
+            listing.custom_prompt = Some(sell_prompt);
+        }
+        if let Some(min_price) = min_price {
+            listing.min_price = min_price;
+        }
+        if let Some(opening_price) = opening_price {
+            listing.policy.opening_price = Some(opening_price);
+        }
+        if let Some(max_concession_bps) = args.max_concession_bps {
+            listing.policy.max_concession_bps = max_concession_bps;
+        }
+        if let Some(min_counter_offers) = args.min_counter_offers {
+            listing.policy.min_counter_offers = min_counter_offers;
+        }
+        if let Some(image) = args.image {
+            listing.image = Some(image);
+        }
+        if let Some(approval) = args.approval {
+            listing.approval = approval;
+        }
+        let listing = listing.clone();
+        for context in self.contexts.values_mut() {
+            if let Some(data) = context.nfts.get_mut(&args.nft_key) {
+                data.listing = listing.clone();
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the chat has a turn waiting on the LLM.
+    pub fn busy(&self, chat_id: ChatId) -> bool {
+        self.turns.contains_key(&chat_id)
+    }
+
+    /// Whether the chat has a turn waiting on the LLM, in which case the input is queued behind it.
+    pub fn queue_if_busy(&mut self, chat_id: ChatId, input: &QueuedInput) -> bool {
+        if !self.busy(chat_id) {
+            return false;
+        }
+        self.queued
+            .entry(chat_id)
+            .or_default()
+            .push_back(input.clone());
+        true
+    }
+
+    /// The next input that was queued while the chat was busy.
+    pub fn next_queued(&mut self, chat_id: ChatId) -> Option<QueuedInput> {
+        let queue = self.queued.get_mut(&chat_id)?;
+        let input = queue.pop_front();
+        if queue.is_empty() {
+            self.queued.remove(&chat_id);
+        }
+        input
+    }
+
+    /// The reply to anything the buyer sends while their chat is muted, or the seller paused the bot.
+    pub fn refusal(&self, chat_id: ChatId) -> Option<&'static str> {
+        if self.muted.contains(&chat_id) {
+            Some(MUTED_REPLY)
+        } else if self.paused {
+            Some(PAUSED_REPLY)
+        } else {
+            None
+        }
     }
 
-    /// Handles a chat message from a user by finding or creating the chat context, processing the message, and returning the chatbot's response.
-    pub fn chat(
+    /// Starts a chat turn with a message from a user, finding or creating the chat context.
+    /// Messages that don't get to the LLM are answered right away.
+    pub fn begin_turn(&mut self, chat_id: ChatId, text: &str) -> TurnStep {
+        if let Some(reply) = self.refusal(chat_id) {
+            return TurnStep::Done(LlmReply::plain(reply));
+        }
+        if !self.contexts.contains_key(&chat_id) {
+            self.notify_admin(format!("New conversation in chat {}: {}", chat_id, text));
+        }
+        let screening = guard::screen(text, &[ADDRESS_PASSKEY]);
+        let text = if screening.score > 0 {
+            if self.flag(chat_id, text, &screening) {
+                return TurnStep::Done(LlmReply::plain(QUARANTINE_REPLY));
+            }
+            screening.sanitized.as_str()
+        } else {
+            text
+        };
+
+        if self.usage.over_budget(now()) {
+            return TurnStep::Done(LlmReply::plain(BUDGET_REPLY));
+        }
+
+        self.chat_context(chat_id).update_focus(text);
+        let market = self.market_view(chat_id);
+        let messages = self.chat_context(chat_id).begin_turn(text, &market);
+        self.last_turn_id += 1;
+        self.turns.insert(
+            chat_id,
+            ChatTurn {
+                id: self.last_turn_id,
+                text: text.to_string(),
+                messages: messages.clone(),
+                stage: TurnStage::Draft,
+                usage: Usage::default(),
+            },
+        );
+        TurnStep::Ask {
+            turn_id: self.last_turn_id,
+            messages,
+            tools: tool_declarations(),
+        }
+    }
+
+    /// Whether the turn is the one the chat has in flight.
+    /// It isn't once the chat got reset or taken over, its completion is stale then.
+    pub fn turn_in_flight(&self, chat_id: ChatId, turn_id: u64) -> bool {
+        self.turns
+            .get(&chat_id)
+            .is_some_and(|turn| turn.id == turn_id)
+    }
+
+    /// Continues the chat turn with the completion the LLM returned, returning the chatbot's response once it's done.
+    /// A failed completion ends the turn with an apology, so the buyer isn't left without a reply.
+    pub fn advance_turn(
         &mut self,
         chat_id: ChatId,
-        text: &str,
-        openai_api: &OpenaiApi,
-    ) -> anyhow::Result<String> {
-        let context = self.chat_context(chat_id);
-        let message = context.chat(openai_api, text)?;
-        Ok(message.content)
+        completion: anyhow::Result<(Answer, Usage)>,
+    ) -> anyhow::Result<TurnStep> {
+        let Some(mut turn) = self.turns.remove(&chat_id) else {
+            return Err(anyhow::anyhow!("no turn in flight for chat {}", chat_id));
+        };
+        let (answer, usage) = match completion {
+            Ok(completion) => completion,
+            Err(e) => {
+                println!("LLM failed in chat {}: {:?}", chat_id, e);
+                self.end_turn(chat_id, &turn);
+                return Ok(TurnStep::Done(LlmReply::plain(FAILED_REPLY)));
+            }
+        };
+        turn.usage.add(&usage);
+
+        let market = self.market_view(chat_id);
+        let reply = LlmReply::from_answer(answer);
+        match self.chat_context(chat_id).advance_turn(chat_id, &mut turn, reply, &market) {
+            TurnProgress::Ask(messages) => {
+                let turn_id = turn.id;
+                self.turns.insert(chat_id, turn);
+                Ok(TurnStep::Ask {
+                    turn_id,
+                    messages,
+                    tools: Vec::new(),
+                })
+            }
+            TurnProgress::Done(reply, leak_incident) => {
+                self.end_turn(chat_id, &turn);
+                if let Some(leak_incident) = leak_incident {
+                    self.leaks.push_back(leak_incident);
+                    while self.leaks.len() > MAX_FLAGGED {
+                        self.leaks.pop_front();
+                    }
+                }
+                Ok(TurnStep::Done(reply))
+            }
+        }
+    }
+
+    /// Records what the turn cost, calls that went through cost money even if the turn failed.
+    fn end_turn(&mut self, chat_id: ChatId, turn: &ChatTurn) {
+        let nft_keys = self.chat_context(chat_id).nfts_in_context();
+        self.usage.record(chat_id, &nft_keys, &turn.usage, now());
+    }
+
+    /// Records a message that matched injection patterns, handing out strikes and muting repeat offenders.
+    /// Returns whether the message is quarantined, i.e. kept from the LLM.
+    fn flag(&mut self, chat_id: ChatId, text: &str, screening: &guard::Screening) -> bool {
+        let quarantined = screening.score >= guard::QUARANTINE_SCORE;
+        if screening.score >= guard::STRIKE_SCORE {
+            let strikes = self.strikes.entry(chat_id).or_default();
+            *strikes += 1;
+            if *strikes >= guard::MAX_STRIKES && self.muted.insert(chat_id) {
+                self.notify_admin(format!(
+                    "Chat {} got muted for manipulation attempts, /unmute {} to lift the mute.",
+                    chat_id, chat_id
+                ));
+            }
+        }
+        println!(
+            "flagged message in chat {} with score {}: {:?}",
+            chat_id, screening.score, screening.labels
+        );
+        self.flagged.push_back(FlaggedMessage {
+            chat_id,
+            text: text.to_string(),
+            score: screening.score,
+            labels: screening.labels.clone(),
+            quarantined,
+            flagged_at: now(),
+        });
+        while self.flagged.len() > MAX_FLAGGED {
+            self.flagged.pop_front();
+        }
+        quarantined
+    }
+
+    /// The flagged messages, with the strikes of their chats and whether they're muted, for the seller.
+    pub fn flagged(&self) -> (&VecDeque<FlaggedMessage>, &HashMap<ChatId, u32>, &HashSet<ChatId>) {
+        (&self.flagged, &self.strikes, &self.muted)
+    }
+
+    /// The replies that disclosed secrets, for the seller.
+    pub fn leaks(&self) -> &VecDeque<LeakIncident> {
+        &self.leaks
+    }
+
+    /// Lets a muted chat talk to the LLM again, clearing its strikes.
+    pub fn unmute(&mut self, chat_id: ChatId) {
+        self.muted.remove(&chat_id);
+        self.strikes.remove(&chat_id);
+    }
+
+    /// Collects the state of the other chats that's relevant for the given chat.
+    fn market_view(&self, chat_id: ChatId) -> MarketView {
+        let now = now();
+        MarketView {
+            accepted_bids: self.accepted_bids(),
+            reserved_elsewhere: self
+                .reservations
+                .iter()
+                .filter(|(_, reservation)| reservation.chat_id != chat_id && reservation.expires_at > now)
+                .map(|(key, _)| key.clone())
+                .collect(),
+            template: self.template_for(chat_id).cloned(),
+            // sealed bids are never shared
+            highest_bids: self
+                .bids
+                .iter()
+                .filter(|(key, _)| {
+                    matches!(
+                        self.nft_listings.get(*key).map(|listing| &listing.mode),
+                        Some(SaleMode::English(_))
+                    )
+                })
+                .filter_map(|(key, bids)| Some((key.clone(), bids.last()?.amount)))
+                .collect(),
+        }
+    }
+
+    /// The template for the system prompt of a chat.
+    /// The template of the NFT the chat is about takes precedence over the global one.
+    fn template_for(&self, chat_id: ChatId) -> Option<&PromptTemplate> {
+        let listing_template = self
+            .contexts
+            .get(&chat_id)
+            .and_then(|context| context.first_tentative_offer().or(context.focus.clone()))
+            .and_then(|key| self.nft_listings.get(&key))
+            .and_then(|listing| listing.template.as_deref());
+        self.templates.resolve(listing_template)
+    }
+
+    /// Checks that the template exists, and the listing to select it for if one is given.
+    pub fn check_template_selection(&self, name: Option<&str>, nft_key: Option<&NFTKey>) -> Result<(), String> {
+        if let Some(name) = name {
+            if !self.templates.templates.contains_key(name) {
+                return Err(format!("There's no template named {}.", name));
+            }
+        }
+        if let Some(nft_key) = nft_key {
+            if !self.nft_listings.contains_key(nft_key) {
+                return Err("That NFT isn't listed.".to_string());
+            }
+        }
+        Ok(())
+    }
+
+    /// Selects a template for a listing, or globally if no listing is given.
+    /// Selecting `None` goes back to the built-in prompt.
+    pub fn select_template(&mut self, name: Option<String>, nft_key: Option<NFTKey>) -> Result<(), String> {
+        self.check_template_selection(name.as_deref(), nft_key.as_ref())?;
+        let Some(nft_key) = nft_key else {
+            self.templates.global = name;
+            return Ok(());
+        };
+        self.set_listing_template(&nft_key, name);
+        Ok(())
+    }
+
+    /// Removes a template, the listings that selected it go back to the global one.
+    pub fn remove_template(&mut self, name: &str) {
+        self.templates.remove(name);
+        let selecting: Vec<NFTKey> = self
+            .nft_listings
+            .iter()
+            .filter(|(_, listing)| listing.template.as_deref() == Some(name))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for nft_key in selecting {
+            self.set_listing_template(&nft_key, None);
+        }
+    }
+
+    fn set_listing_template(&mut self, nft_key: &NFTKey, name: Option<String>) {
+        if let Some(listing) = self.nft_listings.get_mut(nft_key) {
+            listing.template = name.clone();
+        }
+        for context in self.contexts.values_mut() {
+            if let Some(data) = context.nfts.get_mut(nft_key) {
+                data.listing.template = name.clone();
+            }
+        }
+    }
+
+    /// The highest bid accepted for each NFT across all chats.
+    fn accepted_bids(&self) -> HashMap<NFTKey, U256> {
+        let mut accepted_bids: HashMap<NFTKey, U256> = HashMap::new();
+        for context in self.contexts.values() {
+            for (key, data) in &context.nfts {
+                if !data.state.tentative_offer {
+                    continue;
+                }
+                let bid = accepted_bids.entry(key.clone()).or_default();
+                if *bid < data.state.highest_bid {
+                    *bid = data.state.highest_bid;
+                }
+            }
+        }
+        accepted_bids
     }
 
     /// Processes the chatbot's response to potentially finalize an NFT offer based on the chat context and the response content.
     /// This can also involve the linking of an address, or the changing of an NFTState in a context.
-    pub fn act(&mut self, chat_id: ChatId, llm_response: &str) -> Option<FinalizedOfferCommand> {
-        let offered_nft_key = {
+    /// Returns the finalized offer, if any, and the outcomes of the bids the chatbot placed,
+    /// which stand in for its reply as it worded them before knowing whether they'd go through.
+    pub fn act(&mut self, chat_id: ChatId, reply: &LlmReply) -> (Option<FinalizedOfferCommand>, Vec<String>) {
+        self.release_expired_reservations();
+        let bid_outcomes = self.place_bids(chat_id, &reply.tool_calls);
+        let (offered_nft_key, new_offers) = {
             let context = self.chat_context(chat_id);
-            context.process_llm_response(llm_response)
+            let offered_before = context.tentative_offers();
+            let offered_nft_key = context.process_llm_response(reply);
+            let new_offers: Vec<String> = context
+                .tentative_offers()
+                .into_iter()
+                .filter(|offer| !offered_before.contains(offer))
+                .collect();
+            (offered_nft_key, new_offers)
         };
+        for offer in new_offers {
+            self.notify_admin(format!("Chat {} agreed on {}", chat_id, offer));
+        }
+
+        let finalized_offer = offered_nft_key.and_then(|nft_key| self.finalize_offer(chat_id, &nft_key));
+        (finalized_offer, bid_outcomes)
+    }
+
+    /// Whether the offer needs the seller's approval before it gets signed, as set by its listing.
+    pub fn needs_approval(&self, offer: &FinalizedOfferCommand) -> bool {
+        self.nft_listings
+            .get(&offer.nft_key)
+            .map(|listing| listing.approval.requires_approval(listing.min_price, offer.price))
+            .unwrap_or_default()
+    }
+
+    /// Renews the validity of an offer that waited for approval, and its reservation along with it.
+    /// Fails if the NFT is gone, or another chat reserved it in the meantime.
+    pub fn renew_offer(&mut self, chat_id: ChatId, offer: &mut FinalizedOfferCommand) -> bool {
+        if !self.nft_listings.contains_key(&offer.nft_key) {
+            return false;
+        }
+        self.release_expired_reservations();
+        offer.valid_until = now() + OFFER_VALIDITY;
+        self.reserve(chat_id, offer)
+    }
 
-        // Re-acquire the context to access the buyer address and potentially finalize the offer.
+    /// Notes that the signed offer for the chat's reservation went out, so the reservation holds until it expires.
+    pub fn mark_signed(&mut self, chat_id: ChatId, nft_key: &NFTKey) {
+        if let Some(reservation) = self
+            .reservations
+            .get_mut(nft_key)
+            .filter(|reservation| reservation.chat_id == chat_id)
+        {
+            reservation.signed = true;
+        }
+    }
+
+    /// Releases the chat's reservation of the NFT, after its offer got rejected.
+    /// The price that got accepted is withdrawn with it, the buyer has to negotiate again.
+    pub fn release_reservation(&mut self, chat_id: ChatId, nft_key: &NFTKey) {
+        if self
+            .reservations
+            .get(nft_key)
+            .map(|reservation| reservation.chat_id == chat_id)
+            .unwrap_or_default()
+        {
+            self.reservations.remove(nft_key);
+        }
+        if let Some(data) = self
+            .contexts
+            .get_mut(&chat_id)
+            .and_then(|context| context.nfts.get_mut(nft_key))
+        {
+            data.state.tentative_offer = false;
+            data.state.accepted_price = None;
+        }
+    }
+
+    /// Finalizes the offer for the NFT if the chat has a buyer address, reserving the NFT for it.
+    fn finalize_offer(&mut self, chat_id: ChatId, nft_key: &NFTKey) -> Option<FinalizedOfferCommand> {
         let context = self.chat_context(chat_id);
-        match (&offered_nft_key, &context.buyer_address) {
-            (Some(offered_nft_key), Some(buyer_address)) => Some(FinalizedOfferCommand {
-                nft_key: offered_nft_key.clone(),
-                buyer_address: buyer_address.clone(),
-                price: context
-                    .nfts
-                    .get(offered_nft_key)
-                    .map(|data| data.state.highest_bid)
-                    .unwrap_or_default(),
-            }),
-            _ => None,
+        let finalized_offer = FinalizedOfferCommand {
+            nft_key: nft_key.clone(),
+            buyer_address: context.buyer_address.clone()?,
+            price: context
+                .nfts
+                .get(nft_key)
+                .map(|data| data.state.highest_bid)
+                .unwrap_or_default(),
+            valid_until: now() + OFFER_VALIDITY,
+        };
+
+        if !self.reserve(chat_id, &finalized_offer) {
+            return None;
+        }
+        self.addresses
+            .insert(chat_id, finalized_offer.buyer_address.clone());
+        Some(finalized_offer)
+    }
+
+    /// The counter-offer made in the last reply of the chat, if it still stands.
+    pub fn counter_offer(&self, chat_id: ChatId) -> Option<U256> {
+        let context = self.contexts.get(&chat_id)?;
+        let (nft_key, price) = context.counter_offer.as_ref()?;
+        context.nfts.contains_key(nft_key).then_some(*price)
+    }
+
+    /// Accepts the counter-offer of the chat from its button, as if the buyer had agreed on the price.
+    /// The policy still gets the final word, as another buyer may have bid more in the meantime.
+    pub fn accept_counter(&mut self, chat_id: ChatId) -> (String, Option<FinalizedOfferCommand>) {
+        self.release_expired_reservations();
+        let accepted_bids = self.accepted_bids();
+        let context = self.chat_context(chat_id);
+        let Some((nft_key, price)) = context.counter_offer.take() else {
+            return ("That offer isn't on the table anymore, what's your offer?".to_string(), None);
+        };
+        let Some(data) = context.nfts.get_mut(&nft_key) else {
+            return ("That NFT isn't for sale anymore.".to_string(), None);
+        };
+        let accepted_bid = accepted_bids.get(&nft_key).copied().unwrap_or_default();
+        let PolicyDecision::Accept(price) =
+            policy::evaluate(&data.listing, &mut data.state, accepted_bid, price)
+        else {
+            return ("That offer isn't on the table anymore, what's your offer?".to_string(), None);
+        };
+        data.state.tentative_offer = true;
+        if data.state.highest_bid < price {
+            data.state.highest_bid = price;
+        }
+        let name = data.listing.name.clone();
+        self.notify_admin(format!(
+            "Chat {} accepted the counter-offer for {} at {} ETH",
+            chat_id,
+            name,
+            format_ether(price)
+        ));
+        let context = self.chat_context(chat_id);
+
+        if context.buyer_address.is_none() {
+            let reply = format!(
+                "Deal, {} is yours for {} ETH! Please send me your public Ethereum address so I can reserve it for you.",
+                name,
+                format_ether(price)
+            );
+            return (reply, None);
+        }
+        match self.finalize_offer(chat_id, &nft_key) {
+            Some(offer) => (format!("Deal, {} is yours for {} ETH!", name, format_ether(price)), Some(offer)),
+            None => (
+                format!(
+                    "{} is currently reserved for another buyer, if their offer expires I'll be able to sell it to you.",
+                    name
+                ),
+                None,
+            ),
+        }
+    }
+
+    /// Declines the counter-offer of the chat from its button.
+    pub fn decline_counter(&mut self, chat_id: ChatId) -> String {
+        if let Some(context) = self.contexts.get_mut(&chat_id) {
+            context.counter_offer = None;
+        }
+        "No problem, let me know if you change your mind.".to_string()
+    }
+
+    /// The address the buyer used before, if the chat needs one for its pending offer.
+    pub fn suggested_address(&self, chat_id: ChatId) -> Option<String> {
+        let context = self.contexts.get(&chat_id)?;
+        if !context.has_offer_item_without_buyer() {
+            return None;
+        }
+        self.addresses.get(&chat_id).cloned()
+    }
+
+    /// The address the buyer used before.
+    pub fn known_address(&self, chat_id: ChatId) -> Option<String> {
+        self.addresses.get(&chat_id).cloned()
+    }
+
+    /// Sets the buyer address of the chat from an `/address` command.
+    /// Finalizes the pending offer if there is one, returning it along with the reply.
+    pub fn set_buyer_address(
+        &mut self,
+        chat_id: ChatId,
+        address: &str,
+    ) -> (String, Option<FinalizedOfferCommand>) {
+        if !is_eth_address(address.trim()) {
+            return ("To set your address, write /address <0x...>".to_string(), None);
+        }
+        self.release_expired_reservations();
+        let context = self.chat_context(chat_id);
+        context.buyer_address = Some(address.trim().to_string());
+        let Some(nft_key) = context.first_tentative_offer() else {
+            return ("Got it, I'll use that address for your purchases.".to_string(), None);
+        };
+        let name = context.nfts[&nft_key].listing.name.clone();
+        match self.finalize_offer(chat_id, &nft_key) {
+            Some(offer) => ("Got it!".to_string(), Some(offer)),
+            None => (
+                format!(
+                    "Got it. {} is currently reserved for another buyer, if their offer expires I'll be able to sell it to you.",
+                    name
+                ),
+                None,
+            ),
+        }
+    }
+
+    /// Withdraws the chat's pending offers and releases the NFTs reserved for it. Bids stay, they're binding.
+    /// So do signed offers, the buyer can still use them, so their NFTs stay reserved until they expire.
+    pub fn cancel_offers(&mut self, chat_id: ChatId) -> String {
+        let now = now();
+        let signed: HashSet<NFTKey> = self
+            .reservations
+            .iter()
+            .filter(|(_, reservation)| {
+                reservation.chat_id == chat_id && reservation.signed && reservation.expires_at > now
+            })
+            .map(|(key, _)| key.clone())
+            .collect();
+        let mut names = Vec::new();
+        let mut kept = Vec::new();
+        if let Some(context) = self.contexts.get_mut(&chat_id) {
+            for (key, data) in context.nfts.iter_mut() {
+                if signed.contains(key) {
+                    kept.push(data.listing.name.clone());
+                    continue;
+                }
+                if data.state.tentative_offer || data.state.accepted_price.is_some() {
+                    names.push(data.listing.name.clone());
+                }
+                data.state.tentative_offer = false;
+                data.state.accepted_price = None;
+            }
+        }
+        self.reservations
+            .retain(|key, reservation| reservation.chat_id != chat_id || signed.contains(key));
+        self.queued.remove(&chat_id);
+
+        let mut lines = Vec::new();
+        if !names.is_empty() {
+            lines.push(format!("Withdrawn your offers for: {}", names.join(", ")));
+        }
+        if !kept.is_empty() {
+            lines.push(format!(
+                "Your signed offers for {} stay valid until they expire.",
+                kept.join(", ")
+            ));
+        }
+        if lines.is_empty() {
+            "You don't have any pending offers.".to_string()
+        } else {
+            lines.join("\n")
+        }
+    }
+
+    /// Negotiates an offer a buyer agent made over the barter protocol, under the same policies as the chats.
+    /// The offer comes with the buyer's address, so an accepted offer gets finalized right away.
+    pub fn structured_offer(
+        &mut self,
+        chat_id: ChatId,
+        nft_key: &NFTKey,
+        price: U256,
+        buyer_address: &str,
+    ) -> OfferDecision {
+        if !is_eth_address(buyer_address.trim()) {
+            return OfferDecision::Rejected("The buyer address isn't a valid Ethereum address.".to_string());
+        }
+        self.release_expired_reservations();
+        let held = self
+            .reservations
+            .iter()
+            .filter(|(key, reservation)| reservation.chat_id == chat_id && *key != nft_key)
+            .count();
+        if held >= MAX_PEER_RESERVATIONS {
+            return OfferDecision::Rejected(format!(
+                "You hold offers for {} NFTs already, buy them or let them expire first.",
+                held
+            ));
+        }
+        let market = self.market_view(chat_id);
+        let context = self.chat_context(chat_id);
+        let Some(data) = context.nfts.get_mut(nft_key) else {
+            return OfferDecision::Rejected("That NFT isn't for sale.".to_string());
+        };
+        if market.reserved_elsewhere.contains(nft_key) {
+            return OfferDecision::Rejected(format!(
+                "{} is reserved for another buyer right now.",
+                data.listing.name
+            ));
+        }
+        let decision = match &data.listing.mode {
+            SaleMode::Negotiation => {
+                // agents could otherwise haggle the asking price down to the floor by sheer repetition
+                if let Some(ask) = data.state.asking_price {
+                    if data.state.counter_offers >= MAX_OFFER_ROUNDS && price < ask {
+                        return OfferDecision::Rejected(format!(
+                            "The negotiation for {} is over, it's {} ETH.",
+                            data.listing.name,
+                            format_ether(ask)
+                        ));
+                    }
+                }
+                let accepted_bid = market.accepted_bids.get(nft_key).copied().unwrap_or_default();
+                policy::evaluate(&data.listing, &mut data.state, accepted_bid, price)
+            }
+            SaleMode::English(_) | SaleMode::Sealed(_) => {
+                return OfferDecision::Rejected(format!(
+                    "{} is sold by auction, it can't be bought by offer.",
+                    data.listing.name
+                ));
+            }
+            SaleMode::Dutch(auction) => {
+                let current_price = auction.current_price(data.listing.min_price, now());
+                if price < current_price {
+                    data.state.accepted_price = None;
+                    PolicyDecision::Counter(current_price)
+                } else {
+                    data.state.accepted_price = Some(current_price);
+                    PolicyDecision::Accept(current_price)
+                }
+            }
+        };
+        let price = match decision {
+            PolicyDecision::Accept(price) => price,
+            PolicyDecision::Counter(ask) => {
+                context.counter_offer = Some((nft_key.clone(), ask));
+                return OfferDecision::Countered(ask);
+            }
+            PolicyDecision::Reject => {
+                return OfferDecision::Rejected(format!(
+                    "The offer for {} is too low.",
+                    data.listing.name
+                ));
+            }
+        };
+        data.state.tentative_offer = true;
+        if data.state.highest_bid < price {
+            data.state.highest_bid = price;
+        }
+        let name = data.listing.name.clone();
+        context.counter_offer = None;
+        context.buyer_address = Some(buyer_address.trim().to_string());
+        self.notify_admin(format!(
+            "Chat {} agreed on {} for {} ETH",
+            chat_id,
+            name,
+            format_ether(price)
+        ));
+
+        match self.finalize_offer(chat_id, nft_key) {
+            Some(offer) => OfferDecision::Accepted(offer),
+            None => OfferDecision::Rejected(format!("{} is reserved for another buyer right now.", name)),
+        }
+    }
+
+    /// Makes an offer on the seller's behalf, bypassing negotiation and approval.
+    /// The NFT gets reserved for the chat, `ConversationId::Api` for buyers without one,
+    /// which needs it not to be reserved for another. Nothing gets signed while selling is paused.
+    pub fn issue_offer(
+        &mut self,
+        chat_id: ChatId,
+        nft_key: &NFTKey,
+        price: U256,
+        buyer_address: &str,
+    ) -> Result<FinalizedOfferCommand, String> {
+        if self.paused {
+            return Err("Selling is paused.".to_string());
+        }
+        if !is_eth_address(buyer_address.trim()) {
+            return Err("The buyer address isn't a valid Ethereum address.".to_string());
+        }
+        let Some(name) = self.nft_listings.get(nft_key).map(|listing| listing.name.clone()) else {
+            return Err("That NFT isn't listed.".to_string());
+        };
+        let offer = FinalizedOfferCommand {
+            nft_key: nft_key.clone(),
+            buyer_address: buyer_address.trim().to_string(),
+            price,
+            valid_until: now() + OFFER_VALIDITY,
+        };
+        self.release_expired_reservations();
+        if !self.reserve(chat_id, &offer) {
+            return Err(format!("{} is reserved for another buyer right now.", name));
+        }
+        // the chat knows about the deal, as if the buyer had agreed on it
+        let context = self.chat_context(chat_id);
+        context.buyer_address = Some(offer.buyer_address.clone());
+        if let Some(data) = context.nfts.get_mut(nft_key) {
+            data.state.tentative_offer = true;
+            data.state.highest_bid = price;
+        }
+        self.addresses.insert(chat_id, offer.buyer_address.clone());
+        Ok(offer)
+    }
+
+    /// The listings for the `/list` command sorted by name, without anything the LLM keeps secret.
+    pub fn listing_cards(&self) -> Vec<ListingCard> {
+        let mut cards: Vec<ListingCard> = self
+            .nft_listings
+            .iter()
+            .map(|(key, listing)| self.listing_card(key, listing))
+            .collect();
+        cards.sort_by(|a, b| a.name.cmp(&b.name));
+        cards
+    }
+
+    /// The listings with everything the seller set, sorted by name.
+    pub fn listings(&self) -> Vec<Listing> {
+        let mut listings: Vec<Listing> = self
+            .nft_listings
+            .iter()
+            .map(|(key, listing)| Listing {
+                nft_key: key.clone(),
+                listing: listing.clone(),
+                status: self.sale_status(key, listing),
+            })
+            .collect();
+        listings.sort_by(|a, b| a.listing.name.cmp(&b.listing.name));
+        listings
+    }
+
+    /// The listings for buyer agents sorted by name, with the prices they can act on.
+    pub fn inventory(&self) -> Vec<InventoryItem> {
+        let now = now();
+        let mut items: Vec<InventoryItem> = self
+            .nft_listings
+            .iter()
+            .map(|(key, listing)| {
+                let (sale_mode, price) = match &listing.mode {
+                    SaleMode::Negotiation => ("negotiation", listing.policy.opening_price),
+                    SaleMode::English(_) => (
+                        "english",
+                        self.bids.get(key).and_then(|bids| bids.last()).map(|bid| bid.amount),
+                    ),
+                    SaleMode::Sealed(_) => ("sealed", None),
+                    SaleMode::Dutch(auction) => {
+                        ("dutch", Some(auction.current_price(listing.min_price, now)))
+                    }
+                };
+                InventoryItem {
+                    nft: key.clone(),
+                    name: listing.name.clone(),
+                    description: listing.description.clone(),
+                    sale_mode: sale_mode.to_string(),
+                    price,
+                    reserved: self
+                        .reservations
+                        .get(key)
+                        .map(|reservation| reservation.expires_at > now)
+                        .unwrap_or_default(),
+                    status: self.sale_status(key, listing),
+                }
+            })
+            .collect();
+        items.sort_by(|a, b| a.name.cmp(&b.name));
+        items
+    }
+
+    /// The listing for the `/item` command, or what to answer if there's none by that name.
+    pub fn item_card(&self, name: &str) -> Result<ListingCard, String> {
+        let name = name.trim();
+        if name.is_empty() {
+            return Err("Write /item <name>, /list shows what's for sale.".to_string());
+        }
+        self.nft_listings
+            .iter()
+            .find(|(_, listing)| listing.name.eq_ignore_ascii_case(name))
+            .map(|(key, listing)| self.listing_card(key, listing))
+            .ok_or_else(|| format!("I'm not selling anything called {}, /list shows what's for sale.", name))
+    }
+
+    fn listing_card(&self, key: &NFTKey, listing: &NFTListing) -> ListingCard {
+        ListingCard {
+            name: listing.name.clone(),
+            chain: key.chain,
+            address: key.address.clone(),
+            id: key.id,
+            description: listing.description.clone(),
+            status: self.sale_status(key, listing),
+        }
+    }
+
+    /// The VFS path of the picture of a listing, if it has one.
+    pub fn item_image(&self, name: &str) -> Option<String> {
+        self.nft_listings
+            .values()
+            .find(|listing| listing.name.eq_ignore_ascii_case(name.trim()))
+            .and_then(|listing| listing.image.clone())
+    }
+
+    /// The pictures of the NFTs a reply presents that the chat hasn't been shown yet, which count as shown from now on.
+    pub fn images_to_present(&mut self, chat_id: ChatId, reply: &str) -> Vec<String> {
+        let lowercase = reply.to_lowercase();
+        let Some(context) = self.contexts.get_mut(&chat_id) else {
+            return Vec::new();
+        };
+        let mut images = Vec::new();
+        for (key, data) in &context.nfts {
+            let Some(image) = &data.listing.image else {
+                continue;
+            };
+            if lowercase.contains(&data.listing.name.to_lowercase()) && context.shown_images.insert(key.clone()) {
+                images.push(image.clone());
+            }
+        }
+        images
+    }
+
+    /// Notes that the chat got shown the picture of the listing, like with /item.
+    pub fn mark_image_shown(&mut self, chat_id: ChatId, name: &str) {
+        let Some(key) = self
+            .nft_listings
+            .iter()
+            .find(|(_, listing)| listing.name.eq_ignore_ascii_case(name.trim()))
+            .map(|(key, _)| key.clone())
+        else {
+            return;
+        };
+        self.chat_context(chat_id).shown_images.insert(key);
+    }
+
+    /// How a listing is being sold, and where the sale stands.
+    fn sale_status(&self, key: &NFTKey, listing: &NFTListing) -> String {
+        let now = now();
+        let reserved = self
+            .reservations
+            .get(key)
+            .map(|reservation| reservation.expires_at > now)
+            .unwrap_or_default();
+        let status = match &listing.mode {
+            SaleMode::Negotiation => match listing.policy.opening_price {
+                Some(price) => format!("asking {} ETH, open to offers", format_ether(price)),
+                None => "open to offers".to_string(),
+            },
+            SaleMode::English(auction) if auction.is_open(now) => {
+                let highest_bid = self.bids.get(key).and_then(|bids| bids.last());
+                format!(
+                    "auction ending in {} minutes, {}",
+                    auction.ends_at.saturating_sub(now) / 60,
+                    match highest_bid {
+                        Some(bid) => format!("highest bid {} ETH", format_ether(bid.amount)),
+                        None => "no bids yet".to_string(),
+                    }
+                )
+            }
+            SaleMode::Sealed(auction) if auction.is_open(now) => format!(
+                "sealed-bid auction ending in {} minutes, bid with /bid <amount> {}",
+                auction.ends_at.saturating_sub(now) / 60,
+                listing.name
+            ),
+            SaleMode::Dutch(auction) => format!(
+                "price dropping, currently {} ETH",
+                format_ether(auction.current_price(listing.min_price, now))
+            ),
+            _ => "auction ended".to_string(),
+        };
+        if reserved {
+            format!("{} (reserved)", status)
+        } else {
+            status
+        }
+    }
+
+    /// The chat's offers, bids and reservations for the `/myoffers` command.
+    pub fn my_offers(&self, chat_id: ChatId) -> String {
+        let now = now();
+        let mut lines = Vec::new();
+        if let Some(context) = self.contexts.get(&chat_id) {
+            for (key, data) in &context.nfts {
+                let reserved = self
+                    .reservations
+                    .get(key)
+                    .filter(|reservation| reservation.chat_id == chat_id && reservation.expires_at > now);
+                if let Some(reservation) = reserved {
+                    lines.push(format!(
+                        "- {}: reserved for you at {} ETH for {} more minutes",
+                        data.listing.name,
+                        format_ether(reservation.price),
+                        reservation.expires_at.saturating_sub(now) / 60
+                    ));
+                } else if data.state.tentative_offer {
+                    lines.push(format!(
+                        "- {}: agreed at {} ETH, waiting for your address",
+                        data.listing.name,
+                        format_ether(data.state.highest_bid)
+                    ));
+                } else if let Some(price) = data.state.accepted_price {
+                    lines.push(format!(
+                        "- {}: {} ETH accepted",
+                        data.listing.name,
+                        format_ether(price)
+                    ));
+                }
+            }
+        }
+        for (key, bids) in &self.bids {
+            let Some(bid) = bids.iter().rev().find(|bid| bid.chat_id == chat_id) else {
+                continue;
+            };
+            let Some(listing) = self.nft_listings.get(key) else {
+                continue;
+            };
+            let ended = if listing.mode.settled() { ", the auction has ended" } else { "" };
+            lines.push(format!(
+                "- {}: your bid of {} ETH{}",
+                listing.name,
+                format_ether(bid.amount),
+                ended
+            ));
+        }
+
+        let address = self
+            .contexts
+            .get(&chat_id)
+            .and_then(|context| context.buyer_address.clone())
+            .unwrap_or_else(|| "not set, use /address <0x...>".to_string());
+        if lines.is_empty() {
+            format!("You don't have any offers yet.\nYour address: {}", address)
+        } else {
+            lines.sort();
+            format!("{}\nYour address: {}", lines.join("\n"), address)
+        }
+    }
+
+    /// Reserves the NFT of the offer for the chat, unless another chat holds an active reservation.
+    /// Returns whether the reservation succeeded.
+    fn reserve(&mut self, chat_id: ChatId, offer: &FinalizedOfferCommand) -> bool {
+        if self.reserved_by_other(chat_id, &offer.nft_key) {
+            return false;
+        }
+        self.reservations.insert(
+            offer.nft_key.clone(),
+            Reservation {
+                chat_id,
+                buyer_address: offer.buyer_address.clone(),
+                price: offer.price,
+                expires_at: offer.valid_until,
+                signed: false,
+            },
+        );
+        true
+    }
+
+    /// Whether another chat holds an active reservation for the NFT.
+    fn reserved_by_other(&self, chat_id: ChatId, nft_key: &NFTKey) -> bool {
+        self.reservations
+            .get(nft_key)
+            .map(|reservation| reservation.chat_id != chat_id && reservation.expires_at > now())
+            .unwrap_or_default()
+    }
+
+    /// Places the bids the LLM called place_bid for on running auctions, returning their outcomes for the bidder.
+    /// Outbid buyers get notified.
+    fn place_bids(&mut self, chat_id: ChatId, tool_calls: &[ToolCall]) -> Vec<String> {
+        let mut outcomes = Vec::new();
+        for tool_call in tool_calls {
+            let ToolCall::PlaceBid { nft_key, price } = tool_call else {
+                continue;
+            };
+            let Some(amount) = price.to_wei() else {
+                continue;
+            };
+            outcomes.push(self.place_english_bid(chat_id, nft_key, amount));
+        }
+        outcomes
+    }
+
+    /// Places a bid on an English auction, returning the outcome for the bidder.
+    fn place_english_bid(&mut self, chat_id: ChatId, nft_key: &NFTKey, amount: U256) -> String {
+        let Some(listing) = self.nft_listings.get(nft_key) else {
+            return "This NFT isn't listed anymore.".to_string();
+        };
+        let SaleMode::English(auction) = &listing.mode else {
+            return format!("{} isn't sold by open auction.", listing.name);
+        };
+        let now = now();
+        if !auction.is_open(now) {
+            return format!("The auction for {} has ended.", listing.name);
+        }
+        let Some(buyer_address) = self
+            .contexts
+            .get(&chat_id)
+            .and_then(|context| context.buyer_address.clone())
+        else {
+            return "Please send me your public Ethereum address before bidding, so I can settle the auction with you if you win.".to_string();
+        };
+
+        let bids = self.bids.entry(nft_key.clone()).or_default();
+        if !auction.accepts(listing.min_price, bids.last(), amount) {
+            let min_bid = auction.min_next_bid(listing.min_price, bids.last());
+            let bound = match bids.last() {
+                Some(highest) if min_bid == highest.amount => "more than",
+                _ => "at least",
+            };
+            return format!(
+                "Your bid is too low, the next bid for {} has to be {} {} ETH.",
+                listing.name,
+                bound,
+                format_ether(min_bid)
+            );
+        }
+
+        let outbid_chat = bids.last().map(|bid| bid.chat_id).filter(|id| *id != chat_id);
+        bids.push(Bid {
+            chat_id,
+            buyer_address,
+            amount,
+            placed_at: now,
+        });
+        let name = listing.name.clone();
+        let ends_at = auction.ends_at;
+        if let Some(outbid_chat) = outbid_chat {
+            self.notify(
+                outbid_chat,
+                format!(
+                    "You've been outbid on {}, the highest bid is now {} ETH.",
+                    name,
+                    format_ether(amount)
+                ),
+            );
+        }
+        format!(
+            "Your bid of {} ETH for {} is the highest bid. The auction ends in {} minutes.",
+            format_ether(amount),
+            name,
+            ends_at.saturating_sub(now) / 60
+        )
+    }
+
+    /// Places a binding sealed bid from a `/bid <amount> [name]` command, returning the outcome for the bidder.
+    /// The name can be left out if only one sealed-bid auction is running.
+    pub fn place_sealed_bid(&mut self, chat_id: ChatId, args: &str) -> String {
+        let (amount, name) = match args.trim().split_once(' ') {
+            Some((amount, name)) => (amount, name.trim()),
+            None => (args.trim(), ""),
+        };
+        let Some(amount) = parse_eth_amount(amount) else {
+            return "To bid, write /bid <amount in ETH> <name of the NFT>".to_string();
+        };
+
+        let now = now();
+        let open_auctions: Vec<(NFTKey, String)> = self
+            .nft_listings
+            .iter()
+            .filter(|(_, listing)| matches!(&listing.mode, SaleMode::Sealed(auction) if auction.is_open(now)))
+            .filter(|(_, listing)| name.is_empty() || listing.name.eq_ignore_ascii_case(name))
+            .map(|(key, listing)| (key.clone(), listing.name.clone()))
+            .collect();
+        let (nft_key, name) = match open_auctions.as_slice() {
+            [] => return "There's no sealed-bid auction running for that.".to_string(),
+            [(nft_key, name)] => (nft_key.clone(), name.clone()),
+            _ => {
+                let names: Vec<&str> = open_auctions.iter().map(|(_, name)| name.as_str()).collect();
+                return format!(
+                    "Several sealed-bid auctions are running, write /bid <amount> <name> with one of: {}",
+                    names.join(", ")
+                );
+            }
+        };
+
+        let Some(buyer_address) = self
+            .contexts
+            .get(&chat_id)
+            .and_then(|context| context.buyer_address.clone())
+        else {
+            return "Please send me your public Ethereum address before bidding, so I can settle the auction with you if you win.".to_string();
+        };
+        let bids = self.bids.entry(nft_key).or_default();
+        if bids.iter().any(|bid| bid.chat_id == chat_id) {
+            return format!("You've already placed your bid for {}, bids are binding.", name);
+        }
+        bids.push(Bid {
+            chat_id,
+            buyer_address,
+            amount,
+            placed_at: now,
+        });
+        format!(
+            "Your sealed bid of {} ETH for {} is in. You'll hear from me when the auction ends.",
+            format_ether(amount),
+            name
+        )
+    }
+
+    /// Closes the auctions that have ended, returning the offers to settle with their winners.
+    /// The winning NFT gets reserved for the winner, the other bidders get notified.
+    pub fn close_auctions(&mut self) -> Vec<(ChatId, FinalizedOfferCommand)> {
+        // they get closed once selling resumes
+        if self.paused {
+            return Vec::new();
+        }
+        let now = now();
+        let mut settlements = Vec::new();
+        let ended: Vec<NFTKey> = self
+            .nft_listings
+            .iter()
+            .filter(|(_, listing)| {
+                listing
+                    .mode
+                    .deadline()
+                    .map(|deadline| deadline <= now)
+                    .unwrap_or_default()
+            })
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        for nft_key in ended {
+            let Some(listing) = self.nft_listings.get_mut(&nft_key) else {
+                continue;
+            };
+            // the bids stay as the final standings, the settled auction doesn't take new ones
+            let bids = self.bids.get(&nft_key).cloned().unwrap_or_default();
+            let winner = match &mut listing.mode {
+                SaleMode::English(auction) => {
+                    auction.settled = true;
+                    bids.last().cloned().map(|bid| {
+                        let price = bid.amount;
+                        (bid, price)
+                    })
+                }
+                SaleMode::Sealed(auction) => {
+                    auction.settled = true;
+                    auction.winner(listing.min_price, &bids)
+                }
+                _ => continue,
+            };
+            let name = listing.name.clone();
+
+            let winner_chat = winner.as_ref().map(|(bid, _)| bid.chat_id);
+            let losers: HashSet<ChatId> = bids
+                .iter()
+                .map(|bid| bid.chat_id)
+                .filter(|chat_id| Some(*chat_id) != winner_chat)
+                .collect();
+            for loser in losers {
+                self.notify(
+                    loser,
+                    format!("The auction for {} has ended, and your bid didn't win.", name),
+                );
+            }
+
+            let Some((winner, price)) = winner else {
+                continue;
+            };
+            let offer = FinalizedOfferCommand {
+                nft_key: nft_key.clone(),
+                buyer_address: winner.buyer_address.clone(),
+                price,
+                valid_until: now + OFFER_VALIDITY,
+            };
+            self.reservations.insert(
+                nft_key,
+                Reservation {
+                    chat_id: winner.chat_id,
+                    buyer_address: winner.buyer_address,
+                    price,
+                    expires_at: offer.valid_until,
+                    signed: false,
+                },
+            );
+            settlements.push((winner.chat_id, offer));
+        }
+        settlements
+    }
+
+    /// The end times of the auctions that haven't been settled yet.
+    pub fn auction_deadlines(&self) -> Vec<u64> {
+        self.nft_listings
+            .values()
+            .filter_map(|listing| listing.mode.deadline())
+            .collect()
+    }
+
+    /// The bids placed in all running auctions, for the seller.
+    pub fn bids(&self) -> &HashMap<NFTKey, Vec<Bid>> {
+        &self.bids
+    }
+
+    fn notify(&mut self, chat_id: ChatId, text: String) {
+        self.notifications.push(Notification { chat_id, text });
+    }
+
+    /// Queues a message for the seller's admin chat.
+    pub fn notify_admin(&mut self, text: String) {
+        self.admin_notifications.push(text);
+    }
+
+    /// Lets the seller answer the chat themselves, the LLM is bypassed until it's released.
+    /// The turn in flight is dropped along with what queued up behind it, the seller answers those now.
+    pub fn take_over(&mut self, chat_id: ChatId) {
+        self.taken_over = Some(chat_id);
+        self.turns.remove(&chat_id);
+        self.queued.remove(&chat_id);
+    }
+
+    /// Hands the chat taken over back to the LLM, returning it.
+    pub fn release(&mut self) -> Option<ChatId> {
+        self.taken_over.take()
+    }
+
+    pub fn taken_over(&self) -> Option<ChatId> {
+        self.taken_over
+    }
+
+    /// Adds a message relayed between the buyer and the seller to the chat history,
+    /// so the LLM knows what was said once the chat is released.
+    pub fn remember_relayed(&mut self, chat_id: ChatId, from_buyer: bool, text: &str) {
+        let role = if from_buyer { "user" } else { "assistant" };
+        self.chat_context(chat_id).remember(Message {
+            role: role.into(),
+            content: text.into(),
+        });
+    }
+
+    /// Pushes the messages waiting for the admin chat and other chats over the transport.
+    /// Without an admin chat, the seller's messages are dropped.
+    pub fn deliver_notifications(&mut self, transport: &mut dyn ChatTransport, admin_chat: Option<ChatId>) {
+        let admin_notifications = std::mem::take(&mut self.admin_notifications);
+        if let Some(admin_chat) = admin_chat {
+            for text in admin_notifications {
+                if let Err(e) = transport.send(admin_chat, Rendered::text(text), None) {
+                    println!("failed to notify the admin: {:?}", e);
+                }
+            }
         }
+        for notification in std::mem::take(&mut self.notifications) {
+            if let Err(e) = transport.send(notification.chat_id, Rendered::text(notification.text), None) {
+                println!("failed to send notification: {:?}", e);
+            }
+        }
+    }
+
+    /// Drops the reservations whose signed offers have expired.
+    fn release_expired_reservations(&mut self) {
+        let now = now();
+        self.reservations
+            .retain(|_, reservation| reservation.expires_at > now);
     }
 
     /// Check whether the user has offered an nft, and if so, check if they have a buyer address.
     /// If not, ask them for their address.
+    /// If the NFT is reserved for a buyer in another chat, tell them instead.
     pub fn additional_text(&mut self, chat_id: ChatId) -> Option<String> {
+        let reserved_nft_name = {
+            let context = self.chat_context(chat_id);
+            context
+                .first_tentative_offer()
+                .map(|key| (context.nfts[&key].listing.name.clone(), key))
+        }
+        .filter(|(_, key)| self.reserved_by_other(chat_id, key))
+        .map(|(name, _)| name);
+        if let Some(name) = reserved_nft_name {
+            return Some(format!(
+                "\n{} is currently reserved for another buyer. If their offer expires, I'll be able to sell it to you.",
+                name
+            ));
+        }
+
         let context = self.chat_context(chat_id);
         if context.tentative_offer_exists() && context.buyer_address.is_none() {
             return Some(
@@ -157,12 +1806,26 @@ impl ContextManager {
     fn chat_context(&mut self, chat_id: ChatId) -> &mut Context {
         self.contexts
             .entry(chat_id)
-            .or_insert_with(|| Self::new_context(self.nft_listings.clone()))
+            .or_insert_with(|| Self::new_context(self.nft_listings.clone(), &self.memory))
+    }
+
+    /// Sets how much of the conversations is kept, applying it to the existing chats as well.
+    /// The history keeps at least two messages, whatever is configured.
+    pub fn configure_memory(&mut self, mut memory: MemoryConfig) {
+        memory.history_capacity = memory.history_capacity.max(MIN_BUFFER_CAPACITY);
+        for context in self.contexts.values_mut() {
+            context.chat_history.capacity = memory.history_capacity;
+            context.summary.max_entries = memory.summary_length;
+        }
+        self.memory = memory;
     }
 
     /// Removes an NFT from the auction list and updates all downstream chat contexts with this removed NFT.
+    /// Also releases its reservation, which happens when the NFT got purchased.
     pub fn remove_nft(&mut self, nft_key: &NFTKey) {
         self.nft_listings.remove(nft_key);
+        self.reservations.remove(nft_key);
+        self.bids.remove(nft_key);
         for (_, value) in self.contexts.iter_mut() {
             value.nfts.remove(nft_key);
         }
@@ -170,18 +1833,16 @@ impl ContextManager {
 
     pub fn clear(&mut self, chat_id: ChatId) {
         self.contexts.remove(&chat_id);
+        self.turns.remove(&chat_id);
+        self.queued.remove(&chat_id);
     }
 
-    fn new_context(nft_listings: HashMap<NFTKey, NFTListing>) -> Context {
+    fn new_context(nft_listings: HashMap<NFTKey, NFTListing>, memory: &MemoryConfig) -> Context {
         let mut nft_data = HashMap::new();
         for (nft_key, listing) in nft_listings {
-            let nft_state = NFTState {
-                highest_bid: U256::ZERO,
-                tentative_offer: false,
-            };
             let data = NFTData {
                 listing: listing.clone(),
-                state: nft_state,
+                state: NFTState::default(),
             };
             nft_data.insert(nft_key, data);
         }
@@ -189,43 +1850,414 @@ impl ContextManager {
         Context {
             nfts: nft_data,
             buyer_address: None,
-            chat_history: Buffer::new(BUFFER_CAPACITY),
+            chat_history: Buffer::new(memory.history_capacity),
+            summary: ChatSummary {
+                max_entries: memory.summary_length,
+                ..Default::default()
+            },
+            focus: None,
+            counter_offer: None,
+            shown_images: HashSet::new(),
         }
     }
 }
 
 impl Context {
-    /// Processes a user's chat message, updates the chat history, and generates a response using openai API.
-    pub fn chat(&mut self, openai_api: &OpenaiApi, text: &str) -> anyhow::Result<Message> {
-        self.chat_history.push(Message {
+    /// Starts a turn with a user's chat message, returning the messages for the LLM to complete.
+    fn begin_turn(&mut self, text: &str, market: &MarketView) -> Vec<Message> {
+        // a new message answers the counter-offer, its buttons don't anymore
+        self.counter_offer = None;
+        self.remember(Message {
             role: "user".into(),
             content: text.into(),
         });
+        self.create_message_context(market)
+    }
+
+    /// Takes the LLM's answer in the turn, and either asks for another completion or finishes the turn.
+    /// Every price the LLM proposes to sell at goes through the negotiation policy first,
+    /// if the policy doesn't accept it, the LLM is asked to word the policy's decision instead.
+    /// The reply is checked for leaked secrets before it's returned, along with the leak if there was one.
+    fn advance_turn(
+        &mut self,
+        chat_id: ChatId,
+        turn: &mut ChatTurn,
+        reply: LlmReply,
+        market: &MarketView,
+    ) -> TurnProgress {
+        let reply = match &turn.stage {
+            TurnStage::Draft => {
+                let decisions = self.apply_policy(&reply, market);
+                if !decisions.is_empty() {
+                    let mut messages = turn.messages.clone();
+                    messages.extend(reply.message());
+                    messages.push(Message {
+                        role: "system".into(),
+                        content: format!("The offer was not accepted. {} Tell the buyer.", decisions.join(" ")),
+                    });
+                    turn.stage = TurnStage::Policy {
+                        tool_calls: reply.tool_calls,
+                    };
+                    return TurnProgress::Ask(messages);
+                }
+                reply
+            }
+            // keep the original tool calls, offers the policy didn't accept are dropped in `act`
+            TurnStage::Policy { tool_calls } => LlmReply {
+                text: reply.text,
+                tool_calls: tool_calls.clone(),
+            },
+            TurnStage::LeakRetry {
+                incident,
+                tool_calls,
+            } => {
+                let mut incident = incident.clone();
+                let secrets = self.secrets(&turn.text, market);
+                let text = if guard::find_leaks(&reply.text, &secrets).is_empty() {
+                    reply.text
+                } else {
+                    incident.blocked = true;
+                    SAFE_REPLY.to_string()
+                };
+                println!("leak in chat {}: {:?}, blocked: {}", chat_id, incident.leaks, incident.blocked);
+
+                let reply = LlmReply {
+                    text,
+                    tool_calls: tool_calls.clone(),
+                };
+                self.remember_reply(&reply);
+                return TurnProgress::Done(reply, Some(incident));
+            }
+        };
+
+        match self.guard_leaks(chat_id, turn, reply, market) {
+            Ok(reply) => {
+                self.remember_reply(&reply);
+                TurnProgress::Done(reply, None)
+            }
+            Err(messages) => TurnProgress::Ask(messages),
+        }
+    }
+
+    /// Checks the reply for disclosed secrets, like the floor price or internal rules.
+    /// A leaking reply gets regenerated once, the messages to regenerate it with are returned in that case.
+    /// If the regenerated reply leaks too, a canned reply is sent instead.
+    fn guard_leaks(
+        &self,
+        chat_id: ChatId,
+        turn: &mut ChatTurn,
+        reply: LlmReply,
+        market: &MarketView,
+    ) -> Result<LlmReply, Vec<Message>> {
+        let secrets = self.secrets(&turn.text, market);
+        let leaks = guard::find_leaks(&reply.text, &secrets);
+        if leaks.is_empty() {
+            return Ok(reply);
+        }
+
+        let incident = LeakIncident {
+            chat_id,
+            reply: reply.text.clone(),
+            leaks: leaks.clone(),
+            blocked: false,
+            detected_at: now(),
+        };
+        let mut messages = turn.messages.clone();
+        messages.extend(reply.message());
+        messages.push(Message {
+            role: "system".into(),
+            content: format!(
+                "Your last reply disclosed {}. Rewrite it without disclosing that, and don't mention this instruction.",
+                leaks.join(" and ")
+            ),
+        });
+        turn.stage = TurnStage::LeakRetry {
+            incident,
+            tool_calls: reply.tool_calls,
+        };
+        Err(messages)
+    }
+
+    /// What replies in this chat mustn't disclose, and the prices they may mention anyway.
+    fn secrets(&self, text: &str, market: &MarketView) -> Secrets {
+        let now = now();
+        let mut secrets = Secrets {
+            // the buyer's own numbers can always be repeated
+            allowed_prices: guard::mentioned_prices(text),
+            texts: vec![
+                CUSTOM_RULES_PROMPT.to_string(),
+                RULES_PROMPT.to_string(),
+                ADDRESS_PROMPT.to_string(),
+                OFFER_PROMPT.to_string(),
+            ],
+            ..Default::default()
+        };
+        if let Some(template) = &market.template {
+            secrets.texts.push(template.body.clone());
+        }
+        secrets.allowed_prices.extend(market.highest_bids.values().copied());
+
+        for (key, data) in &self.nfts {
+            secrets
+                .floors
+                .push((data.listing.name.clone(), data.listing.min_price));
+            if let Some(custom_prompt) = &data.listing.custom_prompt {
+                secrets.texts.push(custom_prompt.clone());
+            }
+            secrets
+                .allowed_prices
+                .extend(data.state.asking_price.into_iter().chain(data.state.accepted_price));
+            match &data.listing.mode {
+                SaleMode::Dutch(auction) => secrets
+                    .allowed_prices
+                    .push(auction.current_price(data.listing.min_price, now)),
+                // the reserve price opens an English auction, so it's public
+                SaleMode::English(_) if !market.highest_bids.contains_key(key) => {
+                    secrets.allowed_prices.push(data.listing.min_price)
+                }
+                _ => {}
+            }
+        }
+        secrets
+    }
+
+    /// Remembers the NFT the buyer mentions by name, if any.
+    fn update_focus(&mut self, text: &str) {
+        let lowercase = text.to_lowercase();
+        if let Some((key, _)) = self
+            .nfts
+            .iter()
+            .find(|(_, data)| lowercase.contains(&data.listing.name.to_lowercase()))
+        {
+            self.focus = Some(key.clone());
+        }
+    }
+
+    /// The NFTs the chat is about, the one in focus if any, otherwise all of them.
+    fn nfts_in_context(&self) -> Vec<NFTKey> {
+        match &self.focus {
+            Some(key) if self.nfts.contains_key(key) => vec![key.clone()],
+            _ => self.nfts.keys().cloned().collect(),
+        }
+    }
+
+    /// Remembers the chatbot's reply, along with what its tool calls committed to.
+    fn remember_reply(&mut self, reply: &LlmReply) {
+        for commitment in reply.tool_calls.iter().filter_map(ToolCall::commitment) {
+            ChatSummary::add(&mut self.summary.commitments, commitment, self.summary.max_entries);
+        }
+        if let Some(message) = reply.message() {
+            self.remember(message);
+        }
+    }
+
+    /// Adds a message to the chat history, summarizing the messages that get evicted.
+    fn remember(&mut self, message: Message) {
+        for evicted in self.chat_history.push(message) {
+            let item_names: Vec<String> = self
+                .nfts
+                .values()
+                .map(|data| data.listing.name.clone())
+                .collect();
+            self.summary.absorb(&evicted, &item_names);
+        }
+    }
+
+    /// Runs every price proposed in the LLM reply through the negotiation policy.
+    /// Returns instructions for the LLM for each proposal that wasn't accepted.
+    fn apply_policy(&mut self, reply: &LlmReply, market: &MarketView) -> Vec<String> {
+        let mut decisions = Vec::new();
+        for (nft_key, price) in self.proposed_prices(reply) {
+            let Some(data) = self.nfts.get_mut(&nft_key) else {
+                continue;
+            };
+            if market.reserved_elsewhere.contains(&nft_key) {
+                decisions.push(format!(
+                    "{} is reserved for another buyer right now, don't sell it.",
+                    data.listing.name
+                ));
+                continue;
+            }
+            let decision = match &data.listing.mode {
+                SaleMode::Negotiation => {
+                    let accepted_bid =
+                        market.accepted_bids.get(&nft_key).copied().unwrap_or_default();
+                    policy::evaluate(&data.listing, &mut data.state, accepted_bid, price)
+                }
+                SaleMode::English(_) => {
+                    decisions.push(format!(
+                        "{} is sold by auction, don't make an offer, place the buyer's bid with place_bid instead.",
+                        data.listing.name
+                    ));
+                    continue;
+                }
+                SaleMode::Sealed(_) => {
+                    decisions.push(format!(
+                        "{} is sold by sealed-bid auction, don't make an offer. Tell the buyer to bid once with /bid <amount> {}, and never reveal anything about other bids.",
+                        data.listing.name, data.listing.name
+                    ));
+                    continue;
+                }
+                SaleMode::Dutch(auction) => {
+                    let current_price = auction.current_price(data.listing.min_price, now());
+                    if price < current_price {
+                        data.state.accepted_price = None;
+                        decisions.push(format!(
+                            "{} is sold by Dutch auction, the current price is {} ETH. Don't go lower, the price drops by itself over time.",
+                            data.listing.name,
+                            format_ether(current_price)
+                        ));
+                        continue;
+                    }
+                    data.state.accepted_price = Some(current_price);
+                    PolicyDecision::Accept(current_price)
+                }
+            };
+            match decision {
+                PolicyDecision::Accept(_) => {}
+                PolicyDecision::Counter(ask) => {
+                    decisions.push(format!(
+                        "Don't sell {} for {} ETH, counter with exactly {} ETH.",
+                        data.listing.name,
+                        format_ether(price),
+                        format_ether(ask)
+                    ));
+                    self.counter_offer = Some((nft_key.clone(), ask));
+                }
+                PolicyDecision::Reject => decisions.push(format!(
+                    "Reject the bid of {} ETH for {}, and don't name a lower price.",
+                    format_ether(price),
+                    data.listing.name
+                )),
+            }
+        }
+        decisions
+    }
 
-        let chat_params = create_chat_params(self.create_message_context());
-        let answer = openai_api.chat(chat_params)?;
-        self.chat_history.push(answer.clone());
-        Ok(answer)
+    /// The NFTs and prices the LLM proposes to sell at, from tool calls or the legacy passkey.
+    fn proposed_prices(&self, reply: &LlmReply) -> Vec<(NFTKey, U256)> {
+        if reply.tool_calls.is_empty() {
+            return self.legacy_offer(&reply.text).into_iter().collect();
+        }
+        reply
+            .tool_calls
+            .iter()
+            .filter_map(|tool_call| match tool_call {
+                ToolCall::MakeOffer { nft_key, price } => Some((nft_key.clone(), price.to_wei()?)),
+                _ => None,
+            })
+            .collect()
     }
 
-    /// Processes the chatbot's response to identify any tentative offers or link buyer addresses.
+    /// Processes the chatbot's reply to identify any tentative offers or link buyer addresses.
     /// Returns NFT key if updates occur, otherwise `None`.
-    fn process_llm_response(&mut self, llm_response: &str) -> Option<NFTKey> {
-        if let Some(tentative_offer) = self.handle_offer(llm_response) {
-            self.nfts.get_mut(&tentative_offer.nft_key).map(|data| {
-                data.state.tentative_offer = true;
-                if data.state.highest_bid < tentative_offer.price {
-                    data.state.highest_bid = tentative_offer.price;
+    fn process_llm_response(&mut self, reply: &LlmReply) -> Option<NFTKey> {
+        // keep addresses given without an offer too, bidding in auctions needs them
+        if self.buyer_address.is_none() {
+            self.buyer_address = reply.given_address();
+        }
+        let mut offered_nft_key = None;
+        for command in self.commands_from_response(reply) {
+            match command {
+                AuctioneerCommand::TentativeOffer(tentative_offer) => {
+                    self.nfts.get_mut(&tentative_offer.nft_key).map(|data| {
+                        data.state.tentative_offer = true;
+                        if data.state.highest_bid < tentative_offer.price {
+                            data.state.highest_bid = tentative_offer.price;
+                        }
+                    });
+                    if self.buyer_address.is_some() {
+                        offered_nft_key = Some(tentative_offer.nft_key);
+                    }
                 }
-            });
-            if self.buyer_address.is_some() {
-                return Some(tentative_offer.nft_key);
+                AuctioneerCommand::LinkAddress(link_address_cmd) => {
+                    self.buyer_address = Some(link_address_cmd.buyer_address);
+                    offered_nft_key = Some(link_address_cmd.nft_key);
+                }
+                _ => {}
             }
-        } else if let Some(link_address_cmd) = self.handle_address_linking(llm_response) {
-            self.buyer_address = Some(link_address_cmd.buyer_address);
-            return Some(link_address_cmd.nft_key);
         }
-        None
+        offered_nft_key
+    }
+
+    /// Turns the tool calls of the LLM reply into commands.
+    /// Falls back to the legacy passkey parsing if the LLM called no tools.
+    fn commands_from_response(&self, reply: &LlmReply) -> Vec<AuctioneerCommand> {
+        if reply.tool_calls.is_empty() {
+            if let Some(tentative_offer) = self.handle_offer(&reply.text) {
+                return vec![AuctioneerCommand::TentativeOffer(tentative_offer)];
+            } else if let Some(link_address_cmd) = self.handle_address_linking(&reply.text) {
+                return vec![AuctioneerCommand::LinkAddress(link_address_cmd)];
+            }
+            return vec![];
+        }
+
+        let mut commands = Vec::new();
+        for tool_call in &reply.tool_calls {
+            match tool_call {
+                ToolCall::MakeOffer { nft_key, price } => {
+                    let Some(price) = price.to_wei() else {
+                        continue;
+                    };
+                    if let Some(command) = self.tentative_offer(nft_key, price) {
+                        commands.push(AuctioneerCommand::TentativeOffer(command));
+                    }
+                }
+                ToolCall::LinkAddress { address } => {
+                    if let Some(command) = self.link_address(address, &commands) {
+                        commands.push(AuctioneerCommand::LinkAddress(command));
+                    }
+                }
+                // bids are placed by the context manager, which holds the shared auction state
+                ToolCall::PlaceBid { .. } => {}
+            }
+        }
+        commands
+    }
+
+    /// Creates a tentative offer if the NFT is in this context and the price has been accepted.
+    /// The offer is made at the accepted price, which for Dutch auctions is the price at the time of acceptance.
+    fn tentative_offer(&self, nft_key: &NFTKey, price: U256) -> Option<TentativeOfferCommand> {
+        let (current_key, nft_data) = self.nfts.get_key_value(nft_key)?;
+        let accepted_price = nft_data.state.accepted_price?;
+        if price < accepted_price || accepted_price < nft_data.listing.min_price {
+            return None;
+        }
+        Some(TentativeOfferCommand {
+            nft_key: current_key.clone(),
+            price: accepted_price,
+        })
+    }
+
+    /// Links an address to the offer made in the same response, or to the first tentative offer.
+    fn link_address(
+        &self,
+        address: &str,
+        commands: &[AuctioneerCommand],
+    ) -> Option<LinkAddressCommand> {
+        if !is_eth_address(address.trim()) {
+            return None;
+        }
+        let nft_key = commands
+            .iter()
+            .find_map(|command| match command {
+                AuctioneerCommand::TentativeOffer(offer) => Some(offer.nft_key.clone()),
+                _ => None,
+            })
+            .or_else(|| self.first_tentative_offer())?;
+        Some(LinkAddressCommand {
+            nft_key,
+            buyer_address: address.trim().to_string(),
+        })
+    }
+
+    /// The NFTs the buyer agreed on, with their price.
+    fn tentative_offers(&self) -> Vec<String> {
+        self.nfts
+            .values()
+            .filter(|data| data.state.tentative_offer)
+            .map(|data| format!("{} for {} ETH", data.listing.name, format_ether(data.state.highest_bid)))
+            .collect()
     }
 
     fn tentative_offer_exists(&self) -> bool {
@@ -248,66 +2280,58 @@ impl Context {
         tentatively_offer && no_address
     }
 
-    pub fn create_message_context(&self) -> Vec<Message> {
-        let mut messages = Vec::with_capacity(self.chat_history.buffer.len() + 1);
-        messages.push(self.create_system_prompt());
+    fn create_message_context(&self, market: &MarketView) -> Vec<Message> {
+        let mut messages = Vec::with_capacity(self.chat_history.buffer.len() + 2);
+        messages.push(self.create_system_prompt(market));
+        if let Some(summary) = self.summary.render() {
+            messages.push(Message {
+                role: "system".into(),
+                content: summary,
+            });
+        }
         messages.extend(self.chat_history.buffer.iter().cloned());
         messages
     }
 
     /// Creates the system prompt for the chatbot, parsing the listings including rules and custom descriptions.
     /// When the bot requires the address, it adjusts the system prompt to ask for it exclusively.
-    fn create_system_prompt(&self) -> Message {
-        let beginning = "You are a a chatbot auctioneer selling NFTs. ";
-
-        let middle = if self.has_offer_item_without_buyer() {
-            format!("The buyer you're chatting with has bought an NFT from you, but you don't have their ETH address. Please ask them for their public address and do not relent. Don't talk about anything else but their address. Iff they give something resembling a ETH address to you, repeat it with '{}<address>'", ADDRESS_PASSKEY)
-        } else {
-            let nft_with_prices = self
-                .nfts
-                .iter()
-                .map(|(key, data)| {
-                    let description = match &data.listing.description {
-                        Some(description) => format!(", description: {}", description),
-                        None => "".to_string(),
-                    };
-                    let custom_prompt = match &data.listing.custom_prompt {
-                        Some(custom_prompt) => format!(", and custom rules: {}", custom_prompt),
-                        None => "".to_string(),
-                    };
-                    let address_string = format!(
-                        "The address is {}, the chain id {} and the id is {}.",
-                        data.listing.address, key.chain, key.id
-                    );
-
-                    format!(
-                        "\n- {} with min bid of {} ETH{}{}.{}\n",
-                        data.listing.name,
-                        format_ether(data.listing.min_price),
-                        description,
-                        custom_prompt,
-                        address_string
-                    )
-                })
-                .collect::<Vec<String>>()
-                .join("");
-
-            let auctions = if nft_with_prices.is_empty() {
-                "Currently, there are no NFTs available for auction.".into()
+    fn create_system_prompt(&self, market: &MarketView) -> Message {
+        if let Some(template) = &market.template {
+            let address_needed = if self.has_offer_item_without_buyer() {
+                ADDRESS_PROMPT
             } else {
-                nft_with_prices
+                ""
+            };
+            let rendered = template.render(&TemplateVars {
+                listings: &self.listings_prompt(market),
+                passkey: OFFER_PROMPT,
+                buyer_address_needed: address_needed,
+            });
+            // the seller's template can't drop the rules, like never revealing the min price
+            return Message {
+                role: "system".into(),
+                content: format!("{}\n\n{}", rendered, RULES_PROMPT),
             };
+        }
 
+        let beginning = format!("{} ", DEFAULT_PERSONA);
+
+        let middle = if self.has_offer_item_without_buyer() {
+            ADDRESS_PROMPT.to_string()
+        } else {
             format!(
                 r###"
             The list of NFTs is {} 
             
-            Iff the user is talking about a specific nft, follow custom rules, even disregarding general rules. Only follow one custom rule at a time.
+            {}
 
-            Never reveal the min bid required to the user, only sell if minimum price is bid. Only reveal the address, chain id and id of the nft when specifically asked for it. If someone bids more, don't go back down for that nft. 
-            Iff a price is reached, write very clearly with no variation {}
+            {}
+            {}
             "###,
-                auctions, SOLD_PASSKEY
+                self.listings_prompt(market),
+                CUSTOM_RULES_PROMPT,
+                RULES_PROMPT,
+                OFFER_PROMPT
             )
         };
 
@@ -321,48 +2345,99 @@ impl Context {
         }
     }
 
+    /// Lists the NFTs for the system prompt, including their rules and custom descriptions.
+    fn listings_prompt(&self, market: &MarketView) -> String {
+        let nft_with_prices = self
+            .nfts
+            .iter()
+            .map(|(key, data)| {
+                let description = match &data.listing.description {
+                    Some(description) => format!(", description: {}", description),
+                    None => "".to_string(),
+                };
+                let custom_prompt = match &data.listing.custom_prompt {
+                    Some(custom_prompt) => format!(", and custom rules: {}", custom_prompt),
+                    None => "".to_string(),
+                };
+                let address_string = format!(
+                    "The address is {}, the chain id {} and the id is {}.",
+                    data.listing.address, key.chain, key.id
+                );
+                let auction = match &data.listing.mode {
+                    SaleMode::English(auction) => format!(
+                        " It is sold by English auction ending in {} minutes, the highest bid is {} ETH. Don't negotiate it, place bids with place_bid.",
+                        auction.ends_at.saturating_sub(now()) / 60,
+                        format_ether(market.highest_bids.get(key).copied().unwrap_or_default())
+                    ),
+                    SaleMode::Dutch(auction) => format!(
+                        " It is sold by Dutch auction, the current price is {} ETH and keeps dropping over time. Don't negotiate it, sell at exactly the current price to the first buyer who accepts it.",
+                        format_ether(auction.current_price(data.listing.min_price, now()))
+                    ),
+                    SaleMode::Sealed(auction) => format!(
+                        " It is sold by sealed-bid auction ending in {} minutes. Don't negotiate it, buyers bid once by writing /bid <amount> {}. Never reveal or hint at other bids.",
+                        auction.ends_at.saturating_sub(now()) / 60,
+                        data.listing.name
+                    ),
+                    SaleMode::Negotiation => "".to_string(),
+                };
+                let reserved = if market.reserved_elsewhere.contains(key) {
+                    " It is currently reserved for another buyer, don't sell it."
+                } else {
+                    ""
+                };
+
+                format!(
+                    "\n- {} with min bid of {} ETH{}{}.{}{}{}\n",
+                    data.listing.name,
+                    format_ether(data.listing.min_price),
+                    description,
+                    custom_prompt,
+                    address_string,
+                    auction,
+                    reserved
+                )
+            })
+            .collect::<Vec<String>>()
+            .join("");
+
+        if nft_with_prices.is_empty() {
+            "Currently, there are no NFTs available for auction.".into()
+        } else {
+            nft_with_prices
+        }
+    }
+
     /// Parses the LLM response to identify a tentative offer which will get sent upstream.
+    /// Legacy fallback for the `SOLD_PASSKEY` format, tool calls are preferred.
     fn handle_offer(&self, input: &str) -> Option<TentativeOfferCommand> {
-        if !input.starts_with("SOLD") {
-            return None;
-        }
-        let parts: Vec<&str> = input.split(" for ").collect();
-        if parts.len() != 2 {
+        let (nft_key, amount) = self.legacy_offer(input)?;
+        self.tentative_offer(&nft_key, amount)
+    }
+
+    /// Parses the NFT and the amount out of the legacy `SOLD_PASSKEY` format.
+    fn legacy_offer(&self, input: &str) -> Option<(NFTKey, U256)> {
+        let sold_prefix = SOLD_PASSKEY.split(' ').next().unwrap_or_default();
+        if !input.starts_with(sold_prefix) {
             return None;
         }
+        // split on the last " for ", names can contain it too
+        let (name_part, amount_part) = input.rsplit_once(" for ")?;
+        let nft_name = name_part.get(5..)?;
 
-        let nft_name_end_index = parts[0].len();
-        let nft_name = &parts[0][5..nft_name_end_index];
-
-        let amount_str = parts[1].trim_end_matches(" ETH!");
+        let amount = parse_eth_amount(amount_part)?;
 
-        let amount: U256 = parse_units(amount_str, "ether")
-            .unwrap_or(U256::ZERO.into())
-            .into();
-
-        if let Some((current_key, _)) = self
-            .nfts
+        self.nfts
             .iter()
             .find(|(_, data)| data.listing.name == nft_name)
-        {
-            let min_amount_reached = self
-                .nfts
-                .get(current_key)
-                .map(|nft_data| amount >= nft_data.listing.min_price)
-                .unwrap_or_default();
-            if min_amount_reached {
-                let command = TentativeOfferCommand {
-                    nft_key: current_key.clone(),
-                    price: amount,
-                };
-                return Some(command);
-            }
-        }
-        None
+            .map(|(key, _)| (key.clone(), amount))
     }
 
     /// Checks whether the LLM response contains a command to link a buyer's address to an NFT purchase.
+    /// Legacy fallback for the `ADDRESS_PASSKEY` format, tool calls are preferred.
     fn handle_address_linking(&self, llm_response: &str) -> Option<LinkAddressCommand> {
+        if !llm_response.contains(ADDRESS_PASSKEY) {
+            return None;
+        }
         let re = regex::Regex::new(r"0x[a-fA-F0-9]{40}").unwrap();
         if let Some(caps) = re.captures(llm_response) {
             if let Some(matched) = caps.get(0) {
@@ -394,24 +2469,485 @@ impl<T> Buffer<T> {
         }
     }
 
-    fn push(&mut self, item: T) {
-        if self.buffer.len() == self.capacity {
-            self.buffer.pop_front();
-        }
+    /// Pushes an item, returning the items evicted to stay within capacity.
+    fn push(&mut self, item: T) -> Vec<T> {
         self.buffer.push_back(item);
+        let mut evicted = Vec::new();
+        while self.buffer.len() > self.capacity {
+            evicted.extend(self.buffer.pop_front());
+        }
+        evicted
+    }
+}
+
+impl ChatSummary {
+    /// Folds an evicted message into the summary.
+    fn absorb(&mut self, message: &Message, item_names: &[String]) {
+        let speaker = if message.role == "user" { "buyer" } else { "you" };
+        let content = &message.content;
+
+        let price_re = regex::Regex::new(r"(?i)(\d+(?:[.,]\d+)?)\s*(?:eth|Ξ)").unwrap();
+        for caps in price_re.captures_iter(content) {
+            let entry = format!("{} {} ETH", speaker, &caps[1]);
+            Self::add(&mut self.prices, entry, self.max_entries);
+        }
+
+        let lowercase = content.to_lowercase();
+        for name in item_names {
+            if lowercase.contains(&name.to_lowercase()) && !self.items.contains(name) {
+                Self::add(&mut self.items, name.clone(), self.max_entries);
+            }
+        }
+    }
+
+    /// Adds an entry to a section, dropping the oldest entries beyond the maximum.
+    fn add(section: &mut VecDeque<String>, entry: String, max_entries: usize) {
+        section.push_back(entry);
+        while section.len() > max_entries {
+            section.pop_front();
+        }
+    }
+
+    /// Renders the summary for the LLM, `None` if nothing has been evicted yet.
+    fn render(&self) -> Option<String> {
+        let sections = [
+            ("Prices mentioned", &self.prices),
+            ("NFTs discussed", &self.items),
+            ("Commitments you made", &self.commitments),
+        ];
+        let lines: Vec<String> = sections
+            .iter()
+            .filter(|(_, entries)| !entries.is_empty())
+            .map(|(title, entries)| {
+                format!(
+                    "{}: {}",
+                    title,
+                    entries.iter().cloned().collect::<Vec<_>>().join("; ")
+                )
+            })
+            .collect();
+        if lines.is_empty() {
+            return None;
+        }
+        Some(format!(
+            "Summary of the earlier conversation with this buyer, stick to it:\n{}",
+            lines.join("\n")
+        ))
     }
 }
 
-/// The params to communicate with the LLM process.
-/// Max tokens is kept low to save money.
-/// Temperature is set to 0.2 to make the LLM rather predictable.
-fn create_chat_params(messages: Vec<Message>) -> ChatParams {
-    let chat_params = ChatParams {
-        model: "gpt-4-1106-preview".into(),
-        messages,
-        max_tokens: Some(150),
-        temperature: Some(0.2),
-        ..Default::default()
+/// Declares the tools available to the LLM, with the JSON schema of their arguments.
+fn tool_declarations() -> Vec<serde_json::Value> {
+    let nft_key = serde_json::json!({
+        "type": "object",
+        "properties": {
+            "address": { "type": "string" },
+            "chain": { "type": "integer" },
+            "id": { "type": "integer" }
+        },
+        "required": ["address", "chain", "id"]
+    });
+    let tools = [
+        (
+            "make_offer",
+            "Call when the buyer and you agreed on a price for an NFT.",
+            serde_json::json!({
+                "type": "object",
+                "properties": {
+                    "nft_key": nft_key,
+                    "price": { "type": "string", "description": "agreed price in ETH, e.g. \"1.5\"" }
+                },
+                "required": ["nft_key", "price"]
+            }),
+        ),
+        (
+            "place_bid",
+            "Call when the buyer bids on an NFT sold by English auction.",
+            serde_json::json!({
+                "type": "object",
+                "properties": {
+                    "nft_key": nft_key,
+                    "price": { "type": "string", "description": "bid in ETH, e.g. \"1.5\"" }
+                },
+                "required": ["nft_key", "price"]
+            }),
+        ),
+        (
+            "link_address",
+            "Call when the buyer gave you their public Ethereum address.",
+            serde_json::json!({
+                "type": "object",
+                "properties": {
+                    "address": { "type": "string" }
+                },
+                "required": ["address"]
+            }),
+        ),
+    ];
+    tools
+        .into_iter()
+        .map(|(name, description, parameters)| {
+            serde_json::json!({
+                "type": "function",
+                "function": {
+                    "name": name,
+                    "description": description,
+                    "parameters": parameters,
+                }
+            })
+        })
+        .collect()
+}
+
+/// Whether the text is exactly an Ethereum address.
+fn is_eth_address(text: &str) -> bool {
+    static ETH_ADDRESS: OnceLock<regex::Regex> = OnceLock::new();
+    ETH_ADDRESS
+        .get_or_init(|| regex::Regex::new(r"^0x[a-fA-F0-9]{40}$").unwrap())
+        .is_match(text)
+}
+
+/// Parses an ETH amount like "1.5", "1,5 ETH", "1,500" or "2 eth!" into wei.
+/// A comma followed by groups of three digits separates thousands, otherwise it's a decimal comma.
+pub(crate) fn parse_eth_amount(input: &str) -> Option<U256> {
+    let amount = input
+        .trim()
+        .trim_end_matches('!')
+        .trim_end_matches(|c: char| c.is_ascii_alphabetic())
+        .trim();
+    let (integer, fraction) = match amount.split_once('.') {
+        Some((integer, fraction)) => (integer, Some(fraction)),
+        None => (amount, None),
+    };
+    let amount = if groups_thousands(integer) {
+        let integer = integer.replace(',', "");
+        match fraction {
+            Some(fraction) => format!("{}.{}", integer, fraction),
+            None => integer,
+        }
+    } else if integer.contains(',') {
+        // a single decimal comma, like "1,5"
+        if fraction.is_some() || integer.matches(',').count() > 1 {
+            return None;
+        }
+        integer.replace(',', ".")
+    } else {
+        amount.to_string()
     };
-    chat_params
+    parse_units(&amount, "ether").ok().map(Into::into)
+}
+
+/// Whether the commas of a whole number separate its digits into thousands, like "1,500" or "12,000,000".
+fn groups_thousands(integer: &str) -> bool {
+    let mut groups = integer.split(',');
+    let first = groups.next().unwrap_or_default();
+    integer.contains(',')
+        && (1..=3).contains(&first.len())
+        && integer.chars().all(|c| c.is_ascii_digit() || c == ',')
+        && groups.all(|group| group.len() == 3)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::FunctionCall;
+
+    fn eth(amount: &str) -> Option<U256> {
+        Some(parse_units(amount, "ether").unwrap().into())
+    }
+
+    #[test]
+    fn parses_eth_amounts() {
+        assert_eq!(parse_eth_amount("1.5"), eth("1.5"));
+        assert_eq!(parse_eth_amount("1,5 ETH"), eth("1.5"));
+        assert_eq!(parse_eth_amount("2 eth!"), eth("2"));
+        assert_eq!(parse_eth_amount("0,25"), eth("0.25"));
+    }
+
+    #[test]
+    fn reads_commas_before_three_digits_as_thousands() {
+        assert_eq!(parse_eth_amount("1,500"), eth("1500"));
+        assert_eq!(parse_eth_amount("1,500.5 ETH"), eth("1500.5"));
+        assert_eq!(parse_eth_amount("12,000,000"), eth("12000000"));
+    }
+
+    #[test]
+    fn rejects_ambiguous_amounts() {
+        assert_eq!(parse_eth_amount("1,5,0"), None);
+        assert_eq!(parse_eth_amount("1,50.5"), None);
+        assert_eq!(parse_eth_amount("lots"), None);
+    }
+
+    #[test]
+    fn reads_native_tool_calls() {
+        let answer = Answer {
+            text: " Deal! ".to_string(),
+            tool_calls: vec![
+                FunctionCall {
+                    name: "make_offer".to_string(),
+                    arguments: r#"{"nft_key": {"address": "0xabc", "chain": 1, "id": 7}, "price": "1.5"}"#
+                        .to_string(),
+                },
+                FunctionCall {
+                    name: "link_address".to_string(),
+                    arguments: format!(r#"{{"address": "0x{}"}}"#, "a".repeat(40)),
+                },
+                FunctionCall {
+                    name: "unknown_tool".to_string(),
+                    arguments: "{}".to_string(),
+                },
+                FunctionCall {
+                    name: "make_offer".to_string(),
+                    arguments: "not json".to_string(),
+                },
+            ],
+        };
+        let reply = LlmReply::from_answer(answer);
+        assert_eq!(reply.text, "Deal!");
+        assert_eq!(reply.tool_calls.len(), 2);
+        let ToolCall::MakeOffer { nft_key, price } = &reply.tool_calls[0] else {
+            panic!("expected make_offer");
+        };
+        assert_eq!(nft_key.id, 7);
+        assert_eq!(price.to_wei(), eth("1.5"));
+        assert_eq!(reply.given_address(), Some(format!("0x{}", "a".repeat(40))));
+    }
+
+    fn listed_key() -> NFTKey {
+        NFTKey {
+            id: 1,
+            address: "placeholder for debugging".to_string(),
+            chain: 1,
+        }
+    }
+
+    fn with_template() -> ContextManager {
+        let mut context_manager = ContextManager::new(&[(1, "Ape", U256::from(5))]);
+        let template = PromptTemplate {
+            persona: None,
+            body: "{{persona}} Selling {{listings}} {{passkey}} {{buyer_address_needed}}".to_string(),
+        };
+        context_manager.templates.insert("terse".to_string(), template).unwrap();
+        context_manager
+    }
+
+    #[test]
+    fn renders_templates_with_the_rules() {
+        let mut context_manager = with_template();
+        context_manager.select_template(Some("terse".to_string()), None).unwrap();
+        let chat_id = ConversationId::Telegram(1);
+        let market = context_manager.market_view(chat_id);
+        let prompt = context_manager.chat_context(chat_id).create_system_prompt(&market).content;
+        assert!(prompt.starts_with(DEFAULT_PERSONA));
+        assert!(prompt.contains("Ape"));
+        assert!(prompt.contains(OFFER_PROMPT));
+        assert!(prompt.ends_with(RULES_PROMPT));
+    }
+
+    #[test]
+    fn rejects_unknown_template_selections() {
+        let mut context_manager = with_template();
+        let unlisted = NFTKey { id: 2, ..listed_key() };
+        assert!(context_manager.select_template(Some("missing".to_string()), None).is_err());
+        assert!(context_manager
+            .select_template(Some("terse".to_string()), Some(unlisted))
+            .is_err());
+        assert!(context_manager.templates.global.is_none());
+    }
+
+    #[test]
+    fn removing_a_template_unselects_it() {
+        let mut context_manager = with_template();
+        let chat_id = ConversationId::Telegram(1);
+        context_manager.chat_context(chat_id);
+        context_manager
+            .select_template(Some("terse".to_string()), Some(listed_key()))
+            .unwrap();
+        context_manager.remove_template("terse");
+        assert!(context_manager.nft_listings[&listed_key()].template.is_none());
+        let context = context_manager.chat_context(chat_id);
+        assert!(context.nfts[&listed_key()].listing.template.is_none());
+    }
+
+    #[test]
+    fn keeps_signed_reservations_on_cancel() {
+        let mut context_manager = ContextManager::new(&[(1, "Ape", U256::from(5)), (2, "Punk", U256::from(5))]);
+        let chat_id = ConversationId::Telegram(1);
+        context_manager.chat_context(chat_id);
+        let offer = |id| FinalizedOfferCommand {
+            nft_key: NFTKey { id, ..listed_key() },
+            buyer_address: format!("0x{}", "a".repeat(40)),
+            price: eth("1.5").unwrap(),
+            valid_until: now() + OFFER_VALIDITY,
+        };
+        assert!(context_manager.reserve(chat_id, &offer(1)));
+        assert!(context_manager.reserve(chat_id, &offer(2)));
+        context_manager.mark_signed(chat_id, &listed_key());
+
+        context_manager.cancel_offers(chat_id);
+        assert!(context_manager.reservations.contains_key(&listed_key()));
+        assert!(!context_manager.reservations.contains_key(&offer(2).nft_key));
+        let offers = context_manager.my_offers(chat_id);
+        assert!(offers.contains(&format!("Ape: reserved for you at {} ETH", format_ether(offer(1).price))));
+    }
+
+    #[test]
+    fn presents_each_picture_once() {
+        let mut context_manager = ContextManager::new(&[(1, "Ape", U256::from(5))]);
+        context_manager.nft_listings.get_mut(&listed_key()).unwrap().image = Some("/ape.png".to_string());
+        let chat_id = ConversationId::Telegram(1);
+        context_manager.chat_context(chat_id);
+        assert!(context_manager.images_to_present(chat_id, "Hello!").is_empty());
+        assert_eq!(context_manager.images_to_present(chat_id, "The ape is 2 ETH."), vec!["/ape.png"]);
+        assert!(context_manager.images_to_present(chat_id, "The Ape is still 2 ETH.").is_empty());
+    }
+
+    #[test]
+    fn withdraws_the_accepted_price_on_release() {
+        let mut context_manager = ContextManager::new(&[(1, "Ape", U256::from(5))]);
+        let chat_id = ConversationId::Telegram(1);
+        let data = context_manager.chat_context(chat_id).nfts.get_mut(&listed_key()).unwrap();
+        data.state.tentative_offer = true;
+        data.state.accepted_price = eth("1.5");
+        context_manager.release_reservation(chat_id, &listed_key());
+        let data = &context_manager.chat_context(chat_id).nfts[&listed_key()];
+        assert!(!data.state.tentative_offer);
+        assert!(data.state.accepted_price.is_none());
+    }
+
+    #[test]
+    fn keeps_at_least_two_messages() {
+        let mut context_manager = ContextManager::new(&[]);
+        context_manager.configure_memory(MemoryConfig {
+            history_capacity: 0,
+            summary_length: 1,
+        });
+        let context = context_manager.chat_context(ConversationId::Telegram(1));
+        assert_eq!(context.chat_history.capacity, MIN_BUFFER_CAPACITY);
+    }
+
+    /// Records what gets sent instead of sending it.
+    #[derive(Default)]
+    struct Recorder(Vec<(ChatId, String)>);
+
+    impl ChatTransport for Recorder {
+        fn send(
+            &mut self,
+            conversation: ChatId,
+            rendered: Rendered,
+            _keyboard: Option<frankenstein::InlineKeyboardMarkup>,
+        ) -> anyhow::Result<()> {
+            self.0.push((conversation, rendered.to_html()));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn delivers_notifications_over_the_transport() {
+        let mut context_manager = ContextManager::new(&[]);
+        let admin_chat = ConversationId::Telegram(1);
+        let buyer = ConversationId::Web(2);
+        context_manager.notify_admin("New chat".to_string());
+        context_manager.notifications.push(Notification {
+            chat_id: buyer,
+            text: "You've been outbid".to_string(),
+        });
+
+        let mut recorder = Recorder::default();
+        context_manager.deliver_notifications(&mut recorder, Some(admin_chat));
+        assert_eq!(
+            recorder.0,
+            vec![
+                (admin_chat, "New chat".to_string()),
+                (buyer, "You've been outbid".to_string()),
+            ]
+        );
+
+        // they're only delivered once, and the seller's are dropped without an admin chat
+        context_manager.notify_admin("Sold".to_string());
+        let mut recorder = Recorder::default();
+        context_manager.deliver_notifications(&mut recorder, None);
+        assert!(recorder.0.is_empty());
+        assert!(context_manager.admin_notifications.is_empty());
+    }
+
+    #[test]
+    fn limits_the_rounds_of_buyer_agents() {
+        let mut context_manager = ContextManager::new(&[(1, "Ape", eth("1").unwrap())]);
+        context_manager
+            .nft_listings
+            .get_mut(&listed_key())
+            .unwrap()
+            .policy
+            .max_concession_bps = 100;
+        let chat_id = ConversationId::Node(1);
+        let buyer = format!("0x{}", "a".repeat(40));
+        for _ in 0..MAX_OFFER_ROUNDS {
+            let decision = context_manager.structured_offer(chat_id, &listed_key(), U256::ZERO, &buyer);
+            assert!(matches!(decision, OfferDecision::Countered(_)));
+        }
+        let decision = context_manager.structured_offer(chat_id, &listed_key(), U256::ZERO, &buyer);
+        assert!(matches!(decision, OfferDecision::Rejected(_)));
+        // the last asking price still stands
+        let decision = context_manager.structured_offer(chat_id, &listed_key(), eth("1.8").unwrap(), &buyer);
+        assert!(matches!(decision, OfferDecision::Accepted(_)));
+    }
+
+    #[test]
+    fn caps_the_reservations_of_buyer_agents() {
+        let listings: Vec<(i64, &str, U256)> = (1..=MAX_PEER_RESERVATIONS as i64 + 1)
+            .map(|id| (id, "Ape", U256::from(5)))
+            .collect();
+        let mut context_manager = ContextManager::new(&listings);
+        let chat_id = ConversationId::Node(1);
+        let buyer = format!("0x{}", "a".repeat(40));
+        let offer = |context_manager: &mut ContextManager, id| {
+            let nft_key = NFTKey { id, ..listed_key() };
+            context_manager.structured_offer(chat_id, &nft_key, U256::from(10), &buyer)
+        };
+        for id in 1..=MAX_PEER_RESERVATIONS as u64 {
+            assert!(matches!(offer(&mut context_manager, id), OfferDecision::Accepted(_)));
+        }
+        let last = MAX_PEER_RESERVATIONS as u64 + 1;
+        assert!(matches!(offer(&mut context_manager, last), OfferDecision::Rejected(_)));
+        // offering again on a reserved NFT doesn't count against the cap
+        assert!(matches!(offer(&mut context_manager, 1), OfferDecision::Accepted(_)));
+    }
+
+    #[test]
+    fn reserves_offers_issued_without_a_conversation() {
+        let mut context_manager = ContextManager::new(&[(1, "Ape", U256::from(5))]);
+        let buyer = format!("0x{}", "a".repeat(40));
+        let offer = context_manager
+            .issue_offer(ConversationId::Api, &listed_key(), U256::from(5), &buyer)
+            .unwrap();
+        assert_eq!(offer.price, U256::from(5));
+        assert_eq!(context_manager.reservations[&listed_key()].chat_id, ConversationId::Api);
+        // nobody else gets the NFT signed to them meanwhile
+        assert!(context_manager
+            .issue_offer(ConversationId::Telegram(1), &listed_key(), U256::from(6), &buyer)
+            .is_err());
+    }
+
+    #[test]
+    fn signs_nothing_while_paused() {
+        let mut context_manager = ContextManager::new(&[(1, "Ape", U256::from(5))]);
+        context_manager.paused = true;
+        let buyer = format!("0x{}", "a".repeat(40));
+        assert!(context_manager
+            .issue_offer(ConversationId::Api, &listed_key(), U256::from(5), &buyer)
+            .is_err());
+
+        context_manager.nft_listings.get_mut(&listed_key()).unwrap().mode = SaleMode::English(EnglishAuction {
+            ends_at: 0,
+            min_increment: U256::from(1),
+            settled: false,
+        });
+        assert!(context_manager.close_auctions().is_empty());
+        assert!(!context_manager.nft_listings[&listed_key()].mode.settled());
+
+        context_manager.paused = false;
+        context_manager.close_auctions();
+        assert!(context_manager.nft_listings[&listed_key()].mode.settled());
+    }
 }
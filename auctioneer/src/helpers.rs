@@ -1,24 +1,78 @@
 use alloy_primitives::{Address as EthAddress, FixedBytes};
 use alloy_signer::LocalWallet;
+use frankenstein::GetUpdatesParams;
 use kinode_process_lib::{
     eth, println, Address, 
 };
-use llm_interface::api::openai::spawn_openai_pkg;
 use std::str::FromStr;
+use crate::approvals::ApprovalQueue;
+use crate::commands::bot_commands;
 use crate::context::ContextManager;
-use crate::tg_api::init_tg_bot;
+use crate::llm::Llm;
+use crate::protocol::Peers;
+use crate::tg_api::{init_tg_bot, init_tg_webhook, new_webhook_secret, Outbox, TgUpdateMode};
+use crate::transport::WebChat;
 use crate::State;
 use crate::InitialConfig;
 
-pub fn hydrate_state(our: &Address, config: InitialConfig, context_manager: ContextManager) -> anyhow::Result<State> {
-    let Ok(openai_api) = spawn_openai_pkg(our.clone(), &config.openai_key) else {
-        return Err(anyhow::anyhow!("openAI couldn't boot."));
+/// Chains the escrow contract logs purchases on: Sepolia, Optimism, Base and Arbitrum.
+/// The subscription to each has the position in this list plus one as id.
+pub const SALE_CHAINS: [u64; 4] = [11155111, 10, 8453, 42161];
+
+/// Current unix timestamp in seconds.
+pub fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs()
+}
+
+/// Boots everything the state needs besides its persisted parts.
+/// `tg_offset` is the id of the next Telegram update to handle, so updates aren't handled twice across restarts.
+pub fn hydrate_state(
+    our: &Address,
+    mut config: InitialConfig,
+    mut context_manager: ContextManager,
+    tg_offset: u32,
+) -> anyhow::Result<State> {
+    context_manager.configure_memory(config.memory());
+    context_manager.usage.caps = config.budget_caps();
+
+    let llm = Llm {
+        backend: config.llm_backend(),
+        settings: config.llm_settings(),
     };
-    let Ok((tg_api, tg_worker)) =
-        init_tg_bot(our.clone(), &config.telegram_bot_api_key, None)
-    else {
-        return Err(anyhow::anyhow!("tg bot couldn't boot."));
+    let (mut tg_api, tg_worker) = match config.tg_update_mode {
+        TgUpdateMode::LongPolling => {
+            let params = (tg_offset > 0).then(|| {
+                GetUpdatesParams::builder()
+                    .offset(tg_offset as i64)
+                    .build()
+            });
+            let Ok((tg_api, tg_worker)) =
+                init_tg_bot(our.clone(), &config.telegram_bot_api_key, params, bot_commands())
+            else {
+                return Err(anyhow::anyhow!("tg bot couldn't boot."));
+            };
+            (tg_api, Some(tg_worker))
+        }
+        TgUpdateMode::Webhook => {
+            let secret = config
+                .webhook_secret
+                .get_or_insert_with(new_webhook_secret)
+                .clone();
+            let tg_api = init_tg_webhook(
+                our.clone(),
+                &config.telegram_bot_api_key,
+                config.node_public_url.as_deref().unwrap_or(&config.hosted_url),
+                &secret,
+                bot_commands(),
+            )
+            .map_err(|e| anyhow::anyhow!("tg webhook couldn't be bound: {:?}", e))?;
+            (tg_api, None)
+        }
     };
+    tg_api.current_offset = tg_offset;
 
     let Ok(wallet) = config.wallet_pk.parse::<LocalWallet>() else {
         return Err(anyhow::anyhow!("couldn't parse private key."));
@@ -34,29 +88,23 @@ pub fn hydrate_state(our: &Address, config: InitialConfig, context_manager: Cont
 
     let filter = eth::Filter::new()
         .address(escrow_address)
-        .from_block(0)
         .to_block(eth::BlockNumberOrTag::Latest)
         .events(vec![
             "NFTPurchased(address,address,uint256,address,uint256)",
         ])
         .topic1(seller_topic);
 
-    let sep = eth::Provider::new(11155111, 15);
-    let op = eth::Provider::new(10, 15);
-    let base = eth::Provider::new(8453, 15);
-    let arb = eth::Provider::new(42161, 15);
-
-    if let Err(e) = sep.subscribe(1, filter.clone()) {
-        println!("Failed to subscribe to sep: {:?}", e);
-    }
-    if let Err(e) = op.subscribe(2, filter.clone()) {
-        println!("Failed to subscribe to op: {:?}", e);
-    }
-    if let Err(e) = base.subscribe(3, filter.clone()) {
-        println!("Failed to subscribe to base: {:?}", e);
-    }
-    if let Err(e) = arb.subscribe(4, filter) {
-        println!("Failed to subscribe to arb: {:?}", e);
+    for (sub_id, chain) in SALE_CHAINS.iter().enumerate() {
+        // resumes where the last run left off, the logs of the last block are recognized by their position.
+        // Purchases from before the first run aren't ours to record.
+        let from_block = match context_manager.usage.synced_blocks.get(chain) {
+            Some(block) => eth::BlockNumberOrTag::from(*block),
+            None => eth::BlockNumberOrTag::Latest,
+        };
+        let provider = eth::Provider::new(*chain, 15);
+        if let Err(e) = provider.subscribe(sub_id as u64 + 1, filter.clone().from_block(from_block)) {
+            println!("Failed to subscribe to chain {}: {:?}", chain, e);
+        }
     }
 
     Ok(State {
@@ -66,6 +114,10 @@ pub fn hydrate_state(our: &Address, config: InitialConfig, context_manager: Cont
         tg_api,
         tg_worker,
         wallet,
-        openai_api,
+        llm,
+        approvals: ApprovalQueue::default(),
+        outbox: Outbox::default(),
+        web_chat: WebChat::default(),
+        peers: Peers::default(),
     })
 }
\ No newline at end of file
@@ -0,0 +1,145 @@
+use alloy_primitives::{utils::format_ether, U256};
+use frankenstein::{InlineKeyboardButton, InlineKeyboardMarkup};
+
+/// What a buyer asks for by pressing an inline button, carried in the button's callback data.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CallbackAction {
+    /// Accept the counter-offer the bot made
+    AcceptCounter,
+    /// Make another offer instead of the counter-offer
+    Counter,
+    /// Walk away from the counter-offer
+    DeclineCounter,
+    /// Buy with the address used before
+    UseAddress,
+}
+
+impl CallbackAction {
+    const ALL: [CallbackAction; 4] = [
+        CallbackAction::AcceptCounter,
+        CallbackAction::Counter,
+        CallbackAction::DeclineCounter,
+        CallbackAction::UseAddress,
+    ];
+
+    /// The callback data of the action, Telegram allows up to 64 bytes.
+    pub fn data(&self) -> &'static str {
+        match self {
+            CallbackAction::AcceptCounter => "offer:accept",
+            CallbackAction::Counter => "offer:counter",
+            CallbackAction::DeclineCounter => "offer:decline",
+            CallbackAction::UseAddress => "address:use",
+        }
+    }
+
+    pub fn parse(data: &str) -> Option<CallbackAction> {
+        CallbackAction::ALL
+            .into_iter()
+            .find(|action| action.data() == data)
+    }
+}
+
+fn callback_button(text: String, action: CallbackAction) -> InlineKeyboardButton {
+    InlineKeyboardButton::builder()
+        .text(text)
+        .callback_data(action.data())
+        .build()
+}
+
+/// Accept/Counter/Decline buttons under a counter-offer.
+pub fn counter_offer_keyboard(price: U256) -> InlineKeyboardMarkup {
+    InlineKeyboardMarkup::builder()
+        .inline_keyboard(vec![vec![
+            callback_button(
+                format!("Accept {} ETH", format_ether(price)),
+                CallbackAction::AcceptCounter,
+            ),
+            callback_button("Counter".to_string(), CallbackAction::Counter),
+            callback_button("Decline".to_string(), CallbackAction::DeclineCounter),
+        ]])
+        .build()
+}
+
+/// Button to buy with the address the buyer used before.
+pub fn use_address_keyboard(address: &str) -> InlineKeyboardMarkup {
+    let short_address = match (address.get(..6), address.get(address.len().saturating_sub(4)..)) {
+        (Some(start), Some(end)) if address.len() > 10 => format!("{}…{}", start, end),
+        _ => address.to_string(),
+    };
+    InlineKeyboardMarkup::builder()
+        .inline_keyboard(vec![vec![callback_button(
+            format!("Use {}", short_address),
+            CallbackAction::UseAddress,
+        )]])
+        .build()
+}
+
+/// URL button to the page the buyer can buy the NFT at.
+pub fn buy_page_keyboard(link: &str) -> InlineKeyboardMarkup {
+    InlineKeyboardMarkup::builder()
+        .inline_keyboard(vec![vec![InlineKeyboardButton::builder()
+            .text("Open buy page")
+            .url(link)
+            .build()]])
+        .build()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Telegram's limit on callback data, in bytes
+    const MAX_CALLBACK_DATA: usize = 64;
+
+    fn button_texts(keyboard: &InlineKeyboardMarkup) -> Vec<&str> {
+        keyboard
+            .inline_keyboard
+            .iter()
+            .flatten()
+            .map(|button| button.text.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn callback_data_round_trips() {
+        for action in CallbackAction::ALL {
+            assert_eq!(CallbackAction::parse(action.data()), Some(action));
+        }
+        assert_eq!(CallbackAction::parse("offer:unknown"), None);
+        assert_eq!(CallbackAction::parse(""), None);
+    }
+
+    #[test]
+    fn callback_data_fits_telegram_limit() {
+        for action in CallbackAction::ALL {
+            assert!(action.data().len() <= MAX_CALLBACK_DATA, "{:?}", action);
+        }
+        let keyboard = counter_offer_keyboard(U256::from(10u64).pow(U256::from(18u64)));
+        for button in keyboard.inline_keyboard.iter().flatten() {
+            let data = button.callback_data.as_deref().unwrap_or_default();
+            assert!(data.len() <= MAX_CALLBACK_DATA);
+        }
+    }
+
+    #[test]
+    fn counter_offer_keyboard_shows_price() {
+        let price = U256::from(10u64).pow(U256::from(18u64));
+        let keyboard = counter_offer_keyboard(price);
+        let accept = format!("Accept {} ETH", format_ether(price));
+        assert_eq!(button_texts(&keyboard), vec![accept.as_str(), "Counter", "Decline"]);
+    }
+
+    #[test]
+    fn use_address_keyboard_shortens_addresses() {
+        let keyboard = use_address_keyboard("0x1234567890abcdef1234567890abcdef12345678");
+        assert_eq!(button_texts(&keyboard), vec!["Use 0x1234…5678"]);
+        let button = &keyboard.inline_keyboard[0][0];
+        assert_eq!(button.callback_data.as_deref(), Some(CallbackAction::UseAddress.data()));
+
+        // too short to shorten, or cut inside a multibyte character
+        let keyboard = use_address_keyboard("0x12345678");
+        assert_eq!(button_texts(&keyboard), vec!["Use 0x12345678"]);
+        let keyboard = use_address_keyboard("0x123é567890ab");
+        assert_eq!(button_texts(&keyboard), vec!["Use 0x123é567890ab"]);
+    }
+}
@@ -1,24 +1,55 @@
-use alloy_primitives::{utils::format_ether, Address as EthAddress};
+use alloy_primitives::{utils::format_ether, Address as EthAddress, U256};
 use alloy_sol_types::SolEvent;
+use context::{LlmReply, OfferDecision, QueuedInput, TurnStep, EMPTY_REPLY};
 use frankenstein::{
-    ChatId, SendMessageParams, TelegramApi, UpdateContent::ChannelPost as TgChannelPost,
+    AnswerCallbackQueryParams, CallbackQuery, InlineKeyboardMarkup, MaybeInaccessibleMessage, Update,
+    UpdateContent::CallbackQuery as TgCallbackQuery, UpdateContent::ChannelPost as TgChannelPost,
     UpdateContent::Message as TgMessage,
 };
+use alloy_signer::LocalWallet;
 use kinode_process_lib::{
-    await_message, call_init, eth, get_blob, http, println, Address, Message, Request,
+    await_message, call_init, eth, get_blob, http, println, timer, Address, Capability, Message,
+    Request, Response,
 };
 use std::{collections::HashMap, str::FromStr};
 
 mod tg_api;
 use tg_api::TgResponse;
 
+mod admin;
+use admin::AdminCommand;
+mod api;
+use api::{AuctioneerRequest, AuctioneerResponse};
+mod approvals;
+mod auction;
+mod commands;
+use commands::Command;
 mod context;
 mod contracts;
+mod guard;
 mod helpers;
+mod keyboards;
+use keyboards::CallbackAction;
+mod llm;
+mod policy;
+mod protocol;
+use protocol::{
+    BarterRequest, BarterResponse, BuyerNotification, BuyerRequest, BuyerResponse, SignedOffer,
+    PROTOCOL_VERSION,
+};
+mod render;
+use render::Rendered;
 
 mod structs;
 use structs::*;
 
+mod templates;
+mod transport;
+use transport::{ChatTransport, ConversationId};
+mod usage;
+use usage::SaleLog;
+use templates::PromptTemplate;
+
 wit_bindgen::generate!({
     path: "wit",
     world: "process",
@@ -42,6 +73,119 @@ fn config(body_bytes: &[u8]) -> HttpRequestOutcome {
     HttpRequestOutcome::Config(initial_config)
 }
 
+fn add_template(body_bytes: &[u8]) -> HttpRequestOutcome {
+    let add_template_args: AddTemplateArgs = match serde_json::from_slice(body_bytes) {
+        Ok(args) => args,
+        Err(e) => {
+            println!("Failed to parse AddTemplateArgs: {:?}", e);
+            return HttpRequestOutcome::None;
+        }
+    };
+    let template = PromptTemplate {
+        persona: add_template_args.persona.clone(),
+        body: add_template_args.body.clone(),
+    };
+    if let Err(e) = template.validate() {
+        http::send_response(
+            http::StatusCode::BAD_REQUEST,
+            Some(HashMap::from([(
+                "Content-Type".to_string(),
+                "application/json".to_string(),
+            )])),
+            serde_json::json!({ "message": e.to_string() })
+                .to_string()
+                .as_bytes()
+                .to_vec(),
+        );
+        return HttpRequestOutcome::None;
+    }
+    http::send_response(
+        http::StatusCode::OK,
+        Some(HashMap::from([(
+            "Content-Type".to_string(),
+            "application/json".to_string(),
+        )])),
+        b"{\"message\": \"success\"}".to_vec(),
+    );
+    HttpRequestOutcome::AddTemplate(add_template_args)
+}
+
+fn remove_template(body_bytes: &[u8]) -> HttpRequestOutcome {
+    let name: String = match serde_json::from_slice(body_bytes) {
+        Ok(name) => name,
+        Err(e) => {
+            println!("Failed to parse template name: {:?}", e);
+            return HttpRequestOutcome::None;
+        }
+    };
+    http::send_response(
+        http::StatusCode::OK,
+        Some(HashMap::from([(
+            "Content-Type".to_string(),
+            "application/json".to_string(),
+        )])),
+        b"{\"message\": \"success\"}".to_vec(),
+    );
+    HttpRequestOutcome::RemoveTemplate(name)
+}
+
+fn select_template(body_bytes: &[u8], state: &mut Option<State>) -> HttpRequestOutcome {
+    let select_template_args: SelectTemplateArgs = match serde_json::from_slice(body_bytes) {
+        Ok(args) => args,
+        Err(e) => {
+            println!("Failed to parse SelectTemplateArgs: {:?}", e);
+            return HttpRequestOutcome::None;
+        }
+    };
+    let Some(state) = state else {
+        println!("Failed to fetch state, need to have one first before selecting templates");
+        return HttpRequestOutcome::None;
+    };
+    if let Err(e) = state.context_manager.check_template_selection(
+        select_template_args.name.as_deref(),
+        select_template_args.nft_key.as_ref(),
+    ) {
+        http::send_response(
+            http::StatusCode::BAD_REQUEST,
+            Some(HashMap::from([(
+                "Content-Type".to_string(),
+                "application/json".to_string(),
+            )])),
+            serde_json::json!({ "message": e }).to_string().as_bytes().to_vec(),
+        );
+        return HttpRequestOutcome::None;
+    }
+    http::send_response(
+        http::StatusCode::OK,
+        Some(HashMap::from([(
+            "Content-Type".to_string(),
+            "application/json".to_string(),
+        )])),
+        b"{\"message\": \"success\"}".to_vec(),
+    );
+    HttpRequestOutcome::SelectTemplate(select_template_args)
+}
+
+fn list_templates(state: &mut Option<State>) -> HttpRequestOutcome {
+    let Some(state) = state else {
+        println!("Failed to fetch state, need to have one first before listing templates");
+        return HttpRequestOutcome::None;
+    };
+    let response_body = serde_json::to_string(&state.context_manager.templates)
+        .unwrap_or_else(|_| "{}".to_string());
+
+    http::send_response(
+        http::StatusCode::OK,
+        Some(HashMap::from([(
+            "Content-Type".to_string(),
+            "application/json".to_string(),
+        )])),
+        response_body.as_bytes().to_vec(),
+    );
+
+    HttpRequestOutcome::None
+}
+
 fn add_nft(body_bytes: &[u8]) -> HttpRequestOutcome {
     let add_nft_args: AddNFTArgs = match serde_json::from_slice(body_bytes) {
         Ok(args) => args,
@@ -50,179 +194,1391 @@ fn add_nft(body_bytes: &[u8]) -> HttpRequestOutcome {
             return HttpRequestOutcome::None;
         }
     };
-    http::send_response(
-        http::StatusCode::OK,
-        Some(HashMap::from([(
-            "Content-Type".to_string(),
-            "application/json".to_string(),
-        )])),
-        b"{\"message\": \"success\"}".to_vec(),
+    http::send_response(
+        http::StatusCode::OK,
+        Some(HashMap::from([(
+            "Content-Type".to_string(),
+            "application/json".to_string(),
+        )])),
+        b"{\"message\": \"success\"}".to_vec(),
+    );
+    return HttpRequestOutcome::AddNFT(add_nft_args);
+}
+
+fn remove_nft(body_bytes: &[u8]) -> HttpRequestOutcome {
+    let nft_key: NFTKey = match serde_json::from_slice(body_bytes) {
+        Ok(args) => args,
+        Err(e) => {
+            println!("Failed to parse RemoveNFTArgs: {:?}", e);
+            return HttpRequestOutcome::None;
+        }
+    };
+    http::send_response(
+        http::StatusCode::OK,
+        Some(HashMap::from([(
+            "Content-Type".to_string(),
+            "application/json".to_string(),
+        )])),
+        b"{\"message\": \"success\"}".to_vec(),
+    );
+    HttpRequestOutcome::RemoveNFT(nft_key)
+}
+
+fn list_nfts(state: &mut Option<State>) -> HttpRequestOutcome {
+    let Some(state) = state else {
+        println!("Failed to fetch state, need to have one first before listing NFTs");
+        return HttpRequestOutcome::None;
+    };
+    let context_manager = &state.context_manager;
+
+    let nft_listings: Vec<_> = context_manager
+        .nft_listings
+        .iter()
+        .map(|(key, value)| {
+            serde_json::json!({
+                "id": key.id,
+                "chain": key.chain,
+                "name": value.name,
+                "min_price": format_ether(value.min_price),
+                "address": value.address,
+                "description": value.description,
+                "custom_prompt": value.custom_prompt,
+                "opening_price": value.policy.opening_price.map(format_ether),
+                "max_concession_bps": value.policy.max_concession_bps,
+                "min_counter_offers": value.policy.min_counter_offers,
+                "mode": value.mode,
+                "template": value.template,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let response_body = serde_json::to_string(&nft_listings).unwrap_or_else(|_| "[]".to_string());
+
+    http::send_response(
+        http::StatusCode::OK,
+        Some(HashMap::from([(
+            "Content-Type".to_string(),
+            "application/json".to_string(),
+        )])),
+        response_body.as_bytes().to_vec(),
+    );
+
+    HttpRequestOutcome::None
+}
+
+fn list_bids(state: &mut Option<State>) -> HttpRequestOutcome {
+    let Some(state) = state else {
+        println!("Failed to fetch state, need to have one first before listing bids");
+        return HttpRequestOutcome::None;
+    };
+    let context_manager = &state.context_manager;
+
+    let bids: Vec<_> = context_manager
+        .bids()
+        .iter()
+        .map(|(key, bids)| {
+            let listing = context_manager.nft_listings.get(key);
+            serde_json::json!({
+                "id": key.id,
+                "chain": key.chain,
+                "address": key.address,
+                "name": listing.map(|listing| listing.name.clone()),
+                // the bids of a settled auction are its final standings
+                "settled": listing.map(|listing| listing.mode.settled()).unwrap_or_default(),
+                "bids": bids
+                    .iter()
+                    .map(|bid| {
+                        serde_json::json!({
+                            "chat_id": bid.chat_id,
+                            "buyer_address": bid.buyer_address,
+                            "amount": format_ether(bid.amount),
+                            "placed_at": bid.placed_at,
+                        })
+                    })
+                    .collect::<Vec<_>>(),
+            })
+        })
+        .collect();
+
+    let response_body = serde_json::to_string(&bids).unwrap_or_else(|_| "[]".to_string());
+
+    http::send_response(
+        http::StatusCode::OK,
+        Some(HashMap::from([(
+            "Content-Type".to_string(),
+            "application/json".to_string(),
+        )])),
+        response_body.as_bytes().to_vec(),
+    );
+
+    HttpRequestOutcome::None
+}
+
+fn list_flagged(state: &mut Option<State>) -> HttpRequestOutcome {
+    let Some(state) = state else {
+        println!("Failed to fetch state, need to have one first before listing flagged messages");
+        return HttpRequestOutcome::None;
+    };
+    let (flagged, strikes, muted) = state.context_manager.flagged();
+
+    let response_body = serde_json::to_string(&serde_json::json!({
+        "flagged": flagged,
+        "strikes": strikes,
+        "muted": muted,
+    }))
+    .unwrap_or_else(|_| "{}".to_string());
+
+    http::send_response(
+        http::StatusCode::OK,
+        Some(HashMap::from([(
+            "Content-Type".to_string(),
+            "application/json".to_string(),
+        )])),
+        response_body.as_bytes().to_vec(),
+    );
+
+    HttpRequestOutcome::None
+}
+
+fn list_leaks(state: &mut Option<State>) -> HttpRequestOutcome {
+    let Some(state) = state else {
+        println!("Failed to fetch state, need to have one first before listing leaks");
+        return HttpRequestOutcome::None;
+    };
+    let response_body =
+        serde_json::to_string(state.context_manager.leaks()).unwrap_or_else(|_| "[]".to_string());
+
+    http::send_response(
+        http::StatusCode::OK,
+        Some(HashMap::from([(
+            "Content-Type".to_string(),
+            "application/json".to_string(),
+        )])),
+        response_body.as_bytes().to_vec(),
+    );
+
+    HttpRequestOutcome::None
+}
+
+fn fetch_usage(state: &mut Option<State>) -> HttpRequestOutcome {
+    let Some(state) = state else {
+        println!("Failed to fetch state, need to have one first before fetching usage");
+        return HttpRequestOutcome::None;
+    };
+    let context_manager = &state.context_manager;
+    let usage = &context_manager.usage;
+    let now = helpers::now();
+    let usd = |micros: u64| micros as f64 / 1_000_000.0;
+
+    let listings: Vec<serde_json::Value> = usage
+        .per_listing
+        .iter()
+        .map(|(key, listing_usage)| {
+            serde_json::json!({
+                "id": key.id,
+                "chain": key.chain,
+                "address": key.address,
+                "name": context_manager.nft_listings.get(key).map(|listing| listing.name.clone()),
+                "prompt_tokens": listing_usage.prompt_tokens,
+                "completion_tokens": listing_usage.completion_tokens,
+                "cost_usd": usd(listing_usage.cost_micros),
+            })
+        })
+        .collect();
+    let chats: Vec<serde_json::Value> = usage
+        .per_chat
+        .iter()
+        .map(|(chat_id, chat_usage)| {
+            serde_json::json!({
+                "chat_id": chat_id,
+                "prompt_tokens": chat_usage.prompt_tokens,
+                "completion_tokens": chat_usage.completion_tokens,
+                "cost_usd": usd(chat_usage.cost_micros),
+            })
+        })
+        .collect();
+    let sales: Vec<serde_json::Value> = usage
+        .sales
+        .iter()
+        .map(|sale| {
+            serde_json::json!({
+                "id": sale.nft_key.id,
+                "chain": sale.nft_key.chain,
+                "address": sale.nft_key.address,
+                "name": sale.name,
+                "price": format_ether(sale.price),
+                "buyer": sale.buyer,
+                "llm_cost_usd": usd(sale.llm_cost_micros),
+                "sold_at": sale.sold_at,
+            })
+        })
+        .collect();
+    let cost_per_sale = if usage.sales.is_empty() {
+        None
+    } else {
+        let total: u64 = usage.sales.iter().map(|sale| sale.llm_cost_micros).sum();
+        Some(usd(total / usage.sales.len() as u64))
+    };
+
+    let response_body = serde_json::to_string(&serde_json::json!({
+        "today_usd": usd(usage.spend_today(now)),
+        "this_month_usd": usd(usage.spend_this_month(now)),
+        "daily_budget_usd": usage.caps.daily.map(usd),
+        "monthly_budget_usd": usage.caps.monthly.map(usd),
+        "over_budget": usage.over_budget(now),
+        "listings": listings,
+        "chats": chats,
+        "sales": sales,
+        "cost_per_sale_usd": cost_per_sale,
+    }))
+    .unwrap_or_else(|_| "{}".to_string());
+
+    http::send_response(
+        http::StatusCode::OK,
+        Some(HashMap::from([(
+            "Content-Type".to_string(),
+            "application/json".to_string(),
+        )])),
+        response_body.as_bytes().to_vec(),
+    );
+
+    HttpRequestOutcome::None
+}
+
+fn unmute(body_bytes: &[u8]) -> HttpRequestOutcome {
+    // a bare number is a Telegram chat, like before there were other transports
+    let chat_id = match serde_json::from_slice(body_bytes) {
+        Ok(serde_json::Value::Number(chat_id)) => chat_id.to_string().parse::<ConversationId>(),
+        Ok(serde_json::Value::String(chat_id)) => chat_id.parse::<ConversationId>(),
+        Ok(value) => Err(anyhow::anyhow!("not a chat id: {}", value)),
+        Err(e) => Err(e.into()),
+    };
+    let chat_id = match chat_id {
+        Ok(chat_id) => chat_id,
+        Err(e) => {
+            println!("Failed to parse chat id: {:?}", e);
+            return HttpRequestOutcome::None;
+        }
+    };
+    http::send_response(
+        http::StatusCode::OK,
+        Some(HashMap::from([(
+            "Content-Type".to_string(),
+            "application/json".to_string(),
+        )])),
+        b"{\"message\": \"success\"}".to_vec(),
+    );
+    HttpRequestOutcome::Unmute(chat_id)
+}
+
+fn list_pending(state: &mut Option<State>) -> HttpRequestOutcome {
+    let Some(state) = state else {
+        println!("Failed to fetch state, need to have one first before listing pending offers");
+        return HttpRequestOutcome::None;
+    };
+    let pending: Vec<serde_json::Value> = state
+        .approvals
+        .pending
+        .iter()
+        .map(|pending| {
+            serde_json::json!({
+                "id": pending.id,
+                "chat_id": pending.chat_id,
+                "nft_id": pending.offer.nft_key.id,
+                "chain": pending.offer.nft_key.chain,
+                "address": pending.offer.nft_key.address,
+                "name": listing_name(state, &pending.offer.nft_key),
+                "price": format_ether(pending.offer.price),
+                "buyer_address": pending.offer.buyer_address,
+                "requested_at": pending.requested_at,
+            })
+        })
+        .collect();
+    let response_body = serde_json::to_string(&pending).unwrap_or_else(|_| "[]".to_string());
+
+    http::send_response(
+        http::StatusCode::OK,
+        Some(HashMap::from([(
+            "Content-Type".to_string(),
+            "application/json".to_string(),
+        )])),
+        response_body.as_bytes().to_vec(),
+    );
+
+    HttpRequestOutcome::None
+}
+
+/// Parses the id of the pending offer the seller approves or rejects.
+fn offer_decision(body_bytes: &[u8], outcome: fn(u64) -> HttpRequestOutcome) -> HttpRequestOutcome {
+    let id: u64 = match serde_json::from_slice(body_bytes) {
+        Ok(id) => id,
+        Err(e) => {
+            println!("Failed to parse offer id: {:?}", e);
+            return HttpRequestOutcome::None;
+        }
+    };
+    http::send_response(
+        http::StatusCode::OK,
+        Some(HashMap::from([(
+            "Content-Type".to_string(),
+            "application/json".to_string(),
+        )])),
+        b"{\"message\": \"success\"}".to_vec(),
+    );
+    outcome(id)
+}
+
+/// Takes an update Telegram pushed to the webhook, once its secret token checks out.
+fn tg_webhook(
+    http_request: &http::IncomingHttpRequest,
+    body_bytes: &[u8],
+    state: &mut Option<State>,
+) -> HttpRequestOutcome {
+    let Some(state) = state else {
+        return HttpRequestOutcome::None;
+    };
+    let headers = http_request.headers();
+    let secret_token = headers
+        .get(tg_api::WEBHOOK_SECRET_HEADER)
+        .and_then(|value| value.to_str().ok());
+    if !state.tg_api.webhook_authorized(secret_token) {
+        println!("Rejected a webhook request without the secret token");
+        http::send_response(http::StatusCode::UNAUTHORIZED, None, vec![]);
+        return HttpRequestOutcome::None;
+    }
+    let update = match serde_json::from_slice(body_bytes) {
+        Ok(update) => update,
+        Err(e) => {
+            println!("Failed to parse webhook update: {:?}", e);
+            http::send_response(http::StatusCode::BAD_REQUEST, None, vec![]);
+            return HttpRequestOutcome::None;
+        }
+    };
+    // answered right away, Telegram retries requests that take too long
+    http::send_response(http::StatusCode::OK, None, vec![]);
+    HttpRequestOutcome::TgUpdate(update)
+}
+
+/// Web chat from the buy page: takes the buyer's message or button press, and answers with the replies waiting.
+/// Replies that wait on the LLM come with a later request, the page polls for them.
+fn web_chat(
+    http_request: &http::IncomingHttpRequest,
+    body_bytes: &[u8],
+    state: &mut Option<State>,
+) -> HttpRequestOutcome {
+    let Some(state) = state else {
+        http::send_response(http::StatusCode::SERVICE_UNAVAILABLE, None, vec![]);
+        return HttpRequestOutcome::None;
+    };
+    let request: WebChatRequest = match serde_json::from_slice(body_bytes) {
+        Ok(request) => request,
+        Err(e) => {
+            println!("Failed to parse web chat request: {:?}", e);
+            http::send_response(http::StatusCode::BAD_REQUEST, None, vec![]);
+            return HttpRequestOutcome::None;
+        }
+    };
+    let now = helpers::now();
+    // only web conversations can be reached from here, new ones get a random id nobody else knows
+    let id = match request.conversation {
+        Some(ConversationId::Web(id)) => id,
+        _ => {
+            // conversations the buyer's page stopped polling make room for the new one
+            state.expire_web_chats();
+            rand::random()
+        }
+    };
+    let chat_id = ConversationId::Web(id);
+
+    let action = request.callback.as_deref().and_then(CallbackAction::parse);
+    let text = request.text.filter(|text| !text.trim().is_empty());
+    if (text.is_some() || action.is_some())
+        && !state
+            .web_chat
+            .admit(http_request.source_socket_addr().ok().map(|addr| addr.ip()), now)
+    {
+        http::send_response(
+            http::StatusCode::TOO_MANY_REQUESTS,
+            Some(HashMap::from([(
+                "Content-Type".to_string(),
+                "application/json".to_string(),
+            )])),
+            serde_json::json!({ "message": "Too many messages, please wait a minute." })
+                .to_string()
+                .as_bytes()
+                .to_vec(),
+        );
+        return HttpRequestOutcome::None;
+    }
+    let outcome = match (text, action) {
+        (Some(text), _) => Some(handle_buyer_message(state, chat_id, &text)),
+        (_, Some(action)) => Some(handle_button(state, chat_id, action)),
+        _ => None,
+    };
+    let handled = outcome.is_some();
+    if let Some(outcome) = outcome {
+        let outcome = outcome.unwrap_or_else(|e| UpdateOutcome::Errored(e.to_string()));
+        println!("web chat {}: {:?}", chat_id, outcome);
+        send_notifications(state);
+    }
+    let messages = state.web_chat.take(id, now);
+    // polls that only find the mailbox empty don't change anything worth a write
+    if handled || !messages.is_empty() {
+        state.save();
+    }
+
+    let response_body = serde_json::to_string(&serde_json::json!({
+        "conversation": chat_id,
+        "messages": messages,
+    }))
+    .unwrap_or_else(|_| "{}".to_string());
+    http::send_response(
+        http::StatusCode::OK,
+        Some(HashMap::from([(
+            "Content-Type".to_string(),
+            "application/json".to_string(),
+        )])),
+        response_body.as_bytes().to_vec(),
+    );
+    HttpRequestOutcome::None
+}
+
+fn handle_internal_messages(message: &Message, state: &mut Option<State>) -> anyhow::Result<()> {
+    match message {
+        Message::Response { .. } => {
+            return Err(anyhow::anyhow!("unexpected Response: {:?}", message));
+        }
+        Message::Request {
+            ref source,
+            ref body,
+            ref capabilities,
+            ..
+        } => {
+            if let Ok(request) = serde_json::from_slice::<AuctioneerRequest>(body) {
+                return handle_api_request(source, capabilities, request, state);
+            }
+            let Some(state) = state else {
+                println!("State not found! Returning");
+                return Ok(());
+            };
+            return handle_internal_request(source, body, state);
+        }
+    }
+}
+
+/// Answers a request from another process on the node, if it holds the admin capability.
+fn handle_api_request(
+    source: &Address,
+    capabilities: &[Capability],
+    request: AuctioneerRequest,
+    state: &mut Option<State>,
+) -> anyhow::Result<()> {
+    let response = match state {
+        None => AuctioneerResponse::Error("the auctioneer isn't configured yet".to_string()),
+        Some(state) if !api::authorized(&state.our, capabilities) => {
+            println!("rejected a request from {} without the admin capability", source);
+            AuctioneerResponse::Error("the admin capability is required".to_string())
+        }
+        Some(state) => {
+            let response = run_api_request(state, request);
+            send_notifications(state);
+            state.save();
+            response
+        }
+    };
+    Response::new().body(serde_json::to_vec(&response)?).send()
+}
+
+fn run_api_request(state: &mut State, request: AuctioneerRequest) -> AuctioneerResponse {
+    match request {
+        AuctioneerRequest::AddListing(args) => AuctioneerResponse::AddListing(add_listing(state, args)),
+        AuctioneerRequest::UpdateListing(args) => {
+            AuctioneerResponse::UpdateListing(state.context_manager.update_nft(args))
+        }
+        AuctioneerRequest::RemoveListing(nft_key) => {
+            if !state.context_manager.nft_listings.contains_key(&nft_key) {
+                return AuctioneerResponse::RemoveListing(Err("That NFT isn't listed.".to_string()));
+            }
+            state.approvals.drop_nft(&nft_key);
+            state.context_manager.remove_nft(&nft_key);
+            AuctioneerResponse::RemoveListing(Ok(()))
+        }
+        AuctioneerRequest::ListListings => {
+            AuctioneerResponse::ListListings(state.context_manager.listings())
+        }
+        AuctioneerRequest::GetSales => {
+            AuctioneerResponse::GetSales(state.context_manager.usage.sales.clone())
+        }
+        AuctioneerRequest::PauseSelling(paused) => {
+            set_paused(state, paused);
+            AuctioneerResponse::PauseSelling { paused }
+        }
+        AuctioneerRequest::IssueOffer(args) => {
+            AuctioneerResponse::IssueOffer(issue_offer(state, args))
+        }
+    }
+}
+
+/// Adds a listing, with a timer for the end of its auction if it's sold by one.
+fn add_listing(state: &mut State, args: AddNFTArgs) -> Result<NFTKey, String> {
+    let auction_ends_at = args.auction_ends_at.or(args.sealed_ends_at);
+    let nft_key = state.context_manager.add_nft(args)?;
+    if let Some(ends_at) = auction_ends_at {
+        timer::set_timer(ends_at.saturating_sub(helpers::now()) * 1000, None);
+    }
+    Ok(nft_key)
+}
+
+/// Signs an offer on the seller's behalf, and sends it to the buyer's conversation if there is one.
+fn issue_offer(state: &mut State, args: api::IssueOfferArgs) -> Result<SignedOffer, String> {
+    // offers for buyers without a conversation still hold the NFT, so it can't be signed to several buyers
+    let chat_id = args.conversation.unwrap_or(ConversationId::Api);
+    let offer = state.context_manager.issue_offer(
+        chat_id,
+        &args.nft_key,
+        args.price,
+        &args.buyer_address,
+    )?;
+    let signed_offer = sign_reserved_offer(state, chat_id, &offer).map_err(|e| e.to_string())?;
+    notify_signed_offer(state, chat_id, &offer, &signed_offer.link);
+    if args.conversation.is_some() {
+        let reply = "The seller made you an offer!".to_string();
+        if let Err(e) = send_offer_link(state, chat_id, reply, &offer, signed_offer.clone()) {
+            println!("failed to send the issued offer to chat {}: {:?}", chat_id, e);
+        }
+    }
+    Ok(signed_offer)
+}
+
+fn handle_internal_request(source: &Address, body: &[u8], state: &mut State) -> anyhow::Result<()> {
+    let Ok(TgResponse::Update(tg_update)) = serde_json::from_slice(body) else {
+        return Err(anyhow::anyhow!("unexpected response: {:?}", body));
+    };
+
+    // assert update is from our worker
+    if state.tg_worker.as_ref() != Some(source) {
+        return Err(anyhow::anyhow!(
+            "unexpected source: {:?}, expected: {:?}",
+            source,
+            state.tg_worker
+        ));
+    }
+
+    handle_tg_updates(tg_update.updates, state);
+    Ok(())
+}
+
+/// Handles Telegram updates, whether long-polled by the worker or pushed to the webhook.
+fn handle_tg_updates(mut updates: Vec<Update>, state: &mut State) {
+    // handled in order of arrival, which keeps the order within each chat
+    updates.sort_by_key(|update| update.update_id);
+    for update in &updates {
+        if update.update_id < state.tg_api.current_offset {
+            println!("update {}: {:?}", update.update_id, UpdateOutcome::Skipped("already handled".to_string()));
+            continue;
+        }
+        state.tg_api.current_offset = update.update_id + 1;
+        let outcome = handle_update(update, state).unwrap_or_else(|e| UpdateOutcome::Errored(e.to_string()));
+        println!("update {}: {:?}", update.update_id, outcome);
+        // saved along with the offset after every update, so a crash mid-batch doesn't handle the update again
+        send_notifications(state);
+        state.save();
+    }
+}
+
+/// Replies to a single Telegram update.
+fn handle_update(update: &Update, state: &mut State) -> anyhow::Result<UpdateOutcome> {
+    let msg = match &update.content {
+        TgMessage(msg) | TgChannelPost(msg) => msg,
+        TgCallbackQuery(query) => return handle_callback_query(query, state),
+        _ => return Ok(UpdateOutcome::Skipped("unsupported content".to_string())),
+    };
+    let Some(text) = msg.text.clone() else {
+        return Ok(UpdateOutcome::Skipped("no text".to_string()));
+    };
+
+    if state.config.admin_chat_id == Some(msg.chat.id) {
+        return handle_admin_message(state, msg.chat.id, &text);
+    }
+    handle_buyer_message(state, ConversationId::Telegram(msg.chat.id), &text)
+}
+
+/// Answers a buyer's message, whichever transport it came in over.
+fn handle_buyer_message(state: &mut State, chat_id: ConversationId, text: &str) -> anyhow::Result<UpdateOutcome> {
+    match Command::parse(text) {
+        Some(command) => run_command(state, chat_id, command),
+        None if state.context_manager.taken_over() == Some(chat_id) => {
+            state.context_manager.remember_relayed(chat_id, true, text);
+            state
+                .context_manager
+                .notify_admin(format!("[{}] {}", chat_id, text));
+            Ok(UpdateOutcome::Relayed(chat_id))
+        }
+        None => start_turn(state, chat_id, text),
+    }
+}
+
+/// Runs an admin command from the seller, or relays their message to the chat they took over.
+fn handle_admin_message(state: &mut State, admin_chat_id: i64, text: &str) -> anyhow::Result<UpdateOutcome> {
+    let context_manager = &mut state.context_manager;
+    let reply = match AdminCommand::parse(text) {
+        None => match context_manager.taken_over() {
+            Some(chat_id) => {
+                context_manager.remember_relayed(chat_id, false, text);
+                send_reply(state, chat_id, text.to_string())?;
+                return Ok(UpdateOutcome::Relayed(chat_id));
+            }
+            None => admin::ADMIN_HELP.to_string(),
+        },
+        Some(AdminCommand::Help) | Some(AdminCommand::Invalid) => admin::ADMIN_HELP.to_string(),
+        Some(AdminCommand::Pause) => {
+            set_paused(state, true);
+            "Paused, buyers get told to come back later. /resume to start again.".to_string()
+        }
+        Some(AdminCommand::Resume) => {
+            set_paused(state, false);
+            "Resumed.".to_string()
+        }
+        Some(AdminCommand::Unmute(chat_id)) => {
+            context_manager.unmute(chat_id);
+            format!("Chat {} is unmuted.", chat_id)
+        }
+        Some(AdminCommand::Say(chat_id, message)) => {
+            context_manager.remember_relayed(chat_id, false, &message);
+            send_reply(state, chat_id, message)?;
+            format!("Sent to chat {}.", chat_id)
+        }
+        Some(AdminCommand::Takeover(chat_id)) => {
+            context_manager.take_over(chat_id);
+            format!(
+                "You're answering chat {} now, your messages get relayed to the buyer. /release to hand it back to the bot.",
+                chat_id
+            )
+        }
+        Some(AdminCommand::Release) => match context_manager.release() {
+            Some(chat_id) => format!("Chat {} is back with the bot.", chat_id),
+            None => "No chat is taken over.".to_string(),
+        },
+        Some(AdminCommand::Pending) => pending_overview(state),
+        Some(AdminCommand::Approve(id)) => approve_offer(state, id)?,
+        Some(AdminCommand::Reject(id)) => reject_offer(state, id)?,
+    };
+    let admin_chat_id = ConversationId::Telegram(admin_chat_id);
+    send_reply(state, admin_chat_id, reply)?;
+    Ok(UpdateOutcome::Replied(admin_chat_id))
+}
+
+/// Answers a command straight from the state, without the LLM.
+fn run_command(state: &mut State, chat_id: ConversationId, command: Command) -> anyhow::Result<UpdateOutcome> {
+    // muted and paused chats only get the help
+    if let Some(refusal) = state.context_manager.refusal(chat_id) {
+        if !matches!(command, Command::Start | Command::Help | Command::Unknown) {
+            send_reply(state, chat_id, refusal.to_string())?;
+            return Ok(UpdateOutcome::Replied(chat_id));
+        }
+    }
+    let context_manager = &mut state.context_manager;
+    let reply = match command {
+        Command::Start | Command::Help | Command::Unknown => commands::help_text(),
+        Command::List => {
+            let overview = render::listings_overview(&context_manager.listing_cards());
+            send_rendered(state, chat_id, overview, None)?;
+            return Ok(UpdateOutcome::Replied(chat_id));
+        }
+        Command::Item(name) => match context_manager.item_card(&name) {
+            Ok(card) => {
+                let details = render::listing_details(&card);
+                if let Some(image) = context_manager.item_image(&name) {
+                    context_manager.mark_image_shown(chat_id, &name);
+                    // details too long for a caption follow the picture instead
+                    let caption = details
+                        .fits(render::MAX_CAPTION_LEN)
+                        .then(|| details.to_html());
+                    let sent_caption = caption.is_some();
+                    match state.transports().send_photo(chat_id, &image, caption) {
+                        Ok(true) if sent_caption => return Ok(UpdateOutcome::Replied(chat_id)),
+                        Ok(_) => {}
+                        Err(e) => println!("failed to send the picture of {}: {:?}", name, e),
+                    }
+                }
+                send_rendered(state, chat_id, details, None)?;
+                return Ok(UpdateOutcome::Replied(chat_id));
+            }
+            Err(reply) => reply,
+        },
+        Command::MyOffers => context_manager.my_offers(chat_id),
+        Command::Address(address) => {
+            let (reply, finalized_offer) = context_manager.set_buyer_address(chat_id, &address);
+            send_offer_reply(state, chat_id, reply, finalized_offer)?;
+            return Ok(UpdateOutcome::Replied(chat_id));
+        }
+        Command::Bid(args) => context_manager.place_sealed_bid(chat_id, &args),
+        Command::Cancel => {
+            state.approvals.drop_chat(chat_id);
+            context_manager.cancel_offers(chat_id)
+        }
+        Command::Reset => {
+            state.approvals.drop_chat(chat_id);
+            context_manager.clear(chat_id);
+            "Reset succesful!".to_string()
+        }
+    };
+    send_reply(state, chat_id, reply)?;
+    Ok(UpdateOutcome::Replied(chat_id))
+}
+
+fn send_reply(state: &mut State, chat_id: ConversationId, text: String) -> anyhow::Result<()> {
+    send_reply_with_keyboard(state, chat_id, text, None)
+}
+
+fn send_reply_with_keyboard(
+    state: &mut State,
+    chat_id: ConversationId,
+    text: String,
+    keyboard: Option<InlineKeyboardMarkup>,
+) -> anyhow::Result<()> {
+    send_rendered(state, chat_id, Rendered::text(text), keyboard)
+}
+
+/// Sends the reply over the transport of the conversation.
+fn send_rendered(
+    state: &mut State,
+    chat_id: ConversationId,
+    rendered: Rendered,
+    keyboard: Option<InlineKeyboardMarkup>,
+) -> anyhow::Result<()> {
+    state.transports().send(chat_id, rendered, keyboard)
+}
+
+/// Replies with the link to buy at if an offer got finalized, along with a button to the buy page.
+/// Offers the listing wants approved are queued for the seller instead, and the buyer is told to wait.
+fn send_offer_reply(
+    state: &mut State,
+    chat_id: ConversationId,
+    reply: String,
+    finalized_offer: Option<FinalizedOfferCommand>,
+) -> anyhow::Result<()> {
+    let Some(finalized_offer) = finalized_offer else {
+        return send_reply(state, chat_id, reply);
+    };
+    if !state.context_manager.needs_approval(&finalized_offer) {
+        return send_signed_offer(state, chat_id, reply, &finalized_offer);
+    }
+
+    queue_for_approval(state, chat_id, &finalized_offer);
+    let text = format!(
+        "{} The seller needs to approve the offer first, I'll send you the link as soon as they do.",
+        reply
     );
-    return HttpRequestOutcome::AddNFT(add_nft_args);
+    send_reply(state, chat_id, text.trim_start().to_string())
 }
 
-fn remove_nft(body_bytes: &[u8]) -> HttpRequestOutcome {
-    let nft_key: NFTKey = match serde_json::from_slice(body_bytes) {
-        Ok(args) => args,
+/// Queues the offer for the seller's approval, and asks them for it.
+fn queue_for_approval(state: &mut State, chat_id: ConversationId, finalized_offer: &FinalizedOfferCommand) {
+    let name = listing_name(state, &finalized_offer.nft_key);
+    let id = state
+        .approvals
+        .push(chat_id, finalized_offer.clone(), helpers::now());
+    state.context_manager.notify_admin(format!(
+        "Offer {} needs your approval: {} at {} ETH to {} in chat {}. /approve {} or /reject {}",
+        id,
+        name,
+        format_ether(finalized_offer.price),
+        finalized_offer.buyer_address,
+        chat_id,
+        id,
+        id
+    ));
+}
+
+/// Signs the offer and replies with the link to buy at.
+fn send_signed_offer(
+    state: &mut State,
+    chat_id: ConversationId,
+    reply: String,
+    finalized_offer: &FinalizedOfferCommand,
+) -> anyhow::Result<()> {
+    let signed_offer = sign_reserved_offer(state, chat_id, finalized_offer)?;
+    notify_signed_offer(state, chat_id, finalized_offer, &signed_offer.link);
+    send_offer_link(state, chat_id, reply, finalized_offer, signed_offer)
+}
+
+/// Signs an offer the NFT is reserved for, releasing the reservation if that fails so the NFT doesn't stay locked.
+fn sign_reserved_offer(
+    state: &mut State,
+    chat_id: ConversationId,
+    finalized_offer: &FinalizedOfferCommand,
+) -> anyhow::Result<SignedOffer> {
+    match sign_offer(&state.wallet, &state.config, finalized_offer) {
+        Ok(signed_offer) => {
+            state
+                .context_manager
+                .mark_signed(chat_id, &finalized_offer.nft_key);
+            Ok(signed_offer)
+        }
         Err(e) => {
-            println!("Failed to parse RemoveNFTArgs: {:?}", e);
-            return HttpRequestOutcome::None;
+            state
+                .context_manager
+                .release_reservation(chat_id, &finalized_offer.nft_key);
+            Err(e)
         }
-    };
-    http::send_response(
-        http::StatusCode::OK,
-        Some(HashMap::from([(
-            "Content-Type".to_string(),
-            "application/json".to_string(),
-        )])),
-        b"{\"message\": \"success\"}".to_vec(),
-    );
-    HttpRequestOutcome::RemoveNFT(nft_key)
+    }
 }
 
-fn list_nfts(state: &mut Option<State>) -> HttpRequestOutcome {
-    let Some(state) = state else {
-        println!("Failed to fetch state, need to have one first before listing NFTs");
-        return HttpRequestOutcome::None;
-    };
-    let context_manager = &state.context_manager;
+/// Replies with the link to buy at, buyer agents get the signed offer itself instead.
+fn send_offer_link(
+    state: &mut State,
+    chat_id: ConversationId,
+    reply: String,
+    finalized_offer: &FinalizedOfferCommand,
+    signed_offer: SignedOffer,
+) -> anyhow::Result<()> {
+    if let ConversationId::Node(_) = chat_id {
+        return state.peers.push(chat_id, BuyerNotification::Signed(signed_offer));
+    }
+    let link = signed_offer.link;
+    let name = listing_name(state, &finalized_offer.nft_key);
+    let mut rendered = Rendered::text(format!("{}\n\n", reply.trim()));
+    rendered.push_html(render::buy_link(&link, &name, &format_ether(finalized_offer.price)));
+    send_rendered(state, chat_id, rendered, Some(keyboards::buy_page_keyboard(&link)))
+}
 
-    let nft_listings: Vec<_> = context_manager
+/// Tells the buyer their offer fell through, buyer agents get it as a `Rejected` notification.
+fn send_offer_rejection(
+    state: &mut State,
+    chat_id: ConversationId,
+    nft_key: &NFTKey,
+    text: String,
+) -> anyhow::Result<()> {
+    match chat_id {
+        ConversationId::Node(_) => state.peers.push(
+            chat_id,
+            BuyerNotification::Rejected {
+                nft: nft_key.clone(),
+                reason: text,
+            },
+        ),
+        _ => send_reply(state, chat_id, text),
+    }
+}
+
+fn listing_name(state: &State, nft_key: &NFTKey) -> String {
+    state
+        .context_manager
         .nft_listings
+        .get(nft_key)
+        .map(|listing| listing.name.clone())
+        .unwrap_or_default()
+}
+
+/// The offers waiting for approval, for the admin's `/pending` command.
+fn pending_overview(state: &State) -> String {
+    if state.approvals.pending.is_empty() {
+        return "No offers are waiting for approval.".to_string();
+    }
+    let lines: Vec<String> = state
+        .approvals
+        .pending
         .iter()
-        .map(|(key, value)| {
-            serde_json::json!({
-                "id": key.id,
-                "chain": key.chain,
-                "name": value.name,
-                "min_price": format_ether(value.min_price),
-                "address": value.address,
-                "description": value.description,
-                "custom_prompt": value.custom_prompt,
-            })
+        .map(|pending| {
+            format!(
+                "{}: {} at {} ETH to {} in chat {}",
+                pending.id,
+                listing_name(state, &pending.offer.nft_key),
+                format_ether(pending.offer.price),
+                pending.offer.buyer_address,
+                pending.chat_id
+            )
         })
-        .collect::<Vec<_>>();
+        .collect();
+    lines.join("\n")
+}
 
-    let response_body = serde_json::to_string(&nft_listings).unwrap_or_else(|_| "[]".to_string());
+/// Signs a pending offer the seller approved and sends the buyer the link, with a fresh validity.
+/// Returns the answer for the seller.
+fn approve_offer(state: &mut State, id: u64) -> anyhow::Result<String> {
+    let Some(mut pending) = state.approvals.take(id) else {
+        return Ok(format!("No offer {} is waiting for approval.", id));
+    };
+    if !state
+        .context_manager
+        .renew_offer(pending.chat_id, &mut pending.offer)
+    {
+        send_offer_rejection(
+            state,
+            pending.chat_id,
+            &pending.offer.nft_key,
+            "Sorry, the NFT you made an offer for isn't available anymore.".to_string(),
+        )?;
+        return Ok(format!(
+            "Offer {} can't be signed, the NFT is gone or reserved for another buyer.",
+            id
+        ));
+    }
+    send_signed_offer(
+        state,
+        pending.chat_id,
+        "The seller approved your offer!".to_string(),
+        &pending.offer,
+    )?;
+    Ok(format!("Offer {} is signed and sent.", id))
+}
 
-    http::send_response(
-        http::StatusCode::OK,
-        Some(HashMap::from([(
-            "Content-Type".to_string(),
-            "application/json".to_string(),
-        )])),
-        response_body.as_bytes().to_vec(),
+/// Turns down a pending offer, releasing the NFT reserved for it. Returns the answer for the seller.
+fn reject_offer(state: &mut State, id: u64) -> anyhow::Result<String> {
+    let Some(pending) = state.approvals.take(id) else {
+        return Ok(format!("No offer {} is waiting for approval.", id));
+    };
+    state
+        .context_manager
+        .release_reservation(pending.chat_id, &pending.offer.nft_key);
+    let text = format!(
+        "Sorry, the seller turned down your offer of {} ETH for {}.",
+        format_ether(pending.offer.price),
+        listing_name(state, &pending.offer.nft_key)
     );
+    send_offer_rejection(state, pending.chat_id, &pending.offer.nft_key, text)?;
+    Ok(format!("Offer {} is rejected.", id))
+}
 
-    HttpRequestOutcome::None
+/// Tells the seller about an offer that got signed.
+fn notify_signed_offer(state: &mut State, chat_id: ConversationId, offer: &FinalizedOfferCommand, link: &str) {
+    let name = listing_name(state, &offer.nft_key);
+    state.context_manager.notify_admin(format!(
+        "Signed an offer for {} at {} ETH to {} in chat {}: {}",
+        name,
+        format_ether(offer.price),
+        offer.buyer_address,
+        chat_id,
+        link
+    ));
 }
 
-fn handle_internal_messages(message: &Message, state: &mut Option<State>) -> anyhow::Result<()> {
-    let Some(state) = state else {
-        println!("State not found! Returning");
-        return Ok(());
+/// Handles the press of an inline button.
+fn handle_callback_query(query: &CallbackQuery, state: &mut State) -> anyhow::Result<UpdateOutcome> {
+    // stops the button's loading animation
+    let answer = AnswerCallbackQueryParams::builder()
+        .callback_query_id(query.id.clone())
+        .build();
+    match state.outbox.push_callback_answer(&answer) {
+        Ok(()) => state.flush_outbox(),
+        Err(e) => println!("failed to answer callback query: {:?}", e),
+    }
+
+    let Some(action) = query.data.as_deref().and_then(CallbackAction::parse) else {
+        return Ok(UpdateOutcome::Skipped("unknown callback data".to_string()));
     };
+    // the chat the button was sent to, which isn't the presser's id in groups
+    let chat_id = match &query.message {
+        Some(MaybeInaccessibleMessage::Message(message)) => message.chat.id,
+        Some(MaybeInaccessibleMessage::InaccessibleMessage(message)) => message.chat.id,
+        None => return Ok(UpdateOutcome::Skipped("button without a chat".to_string())),
+    };
+    handle_button(state, ConversationId::Telegram(chat_id), action)
+}
 
-    match message {
-        Message::Response { .. } => {
-            return Err(anyhow::anyhow!("unexpected Response: {:?}", message));
+/// Handles a button the buyer pressed under the same rules as their messages, whichever transport it came in over.
+/// Muted and paused chats get told so, presses while a turn is in flight wait for it,
+/// and presses in the chat the seller took over are passed on to the seller.
+fn handle_button(state: &mut State, chat_id: ConversationId, action: CallbackAction) -> anyhow::Result<UpdateOutcome> {
+    if let Some(reply) = state.context_manager.refusal(chat_id) {
+        send_reply(state, chat_id, reply.to_string())?;
+        return Ok(UpdateOutcome::Replied(chat_id));
+    }
+    if state.context_manager.taken_over() == Some(chat_id) {
+        state
+            .context_manager
+            .notify_admin(format!("[{}] pressed {:?}", chat_id, action));
+        return Ok(UpdateOutcome::Relayed(chat_id));
+    }
+    if state
+        .context_manager
+        .queue_if_busy(chat_id, &QueuedInput::Button(action.data().to_string()))
+    {
+        return Ok(UpdateOutcome::Queued(chat_id));
+    }
+    handle_callback_action(state, chat_id, action)
+}
+
+/// Acts on a button the buyer pressed.
+fn handle_callback_action(
+    state: &mut State,
+    chat_id: ConversationId,
+    action: CallbackAction,
+) -> anyhow::Result<UpdateOutcome> {
+    let context_manager = &mut state.context_manager;
+    let (reply, finalized_offer) = match action {
+        CallbackAction::AcceptCounter => context_manager.accept_counter(chat_id),
+        CallbackAction::Counter => ("Sure, what's your offer?".to_string(), None),
+        CallbackAction::DeclineCounter => (context_manager.decline_counter(chat_id), None),
+        CallbackAction::UseAddress => match context_manager.known_address(chat_id) {
+            Some(address) => context_manager.set_buyer_address(chat_id, &address),
+            None => ("Please send me your public Ethereum address.".to_string(), None),
+        },
+    };
+    send_offer_reply(state, chat_id, reply, finalized_offer)?;
+    Ok(UpdateOutcome::Replied(chat_id))
+}
+
+/// Starts a chat turn with the message, or queues it if the chat is still waiting on the LLM.
+fn start_turn(state: &mut State, chat_id: ConversationId, text: &str) -> anyhow::Result<UpdateOutcome> {
+    if state
+        .context_manager
+        .queue_if_busy(chat_id, &QueuedInput::Message(text.to_string()))
+    {
+        return Ok(UpdateOutcome::Queued(chat_id));
+    }
+    let step = state.context_manager.begin_turn(chat_id, text);
+    drive_turn(state, chat_id, step)
+}
+
+/// Runs the chat turn until it waits on the LLM or is done.
+/// Once it's done, what the chat sent in the meantime gets handled.
+fn drive_turn(state: &mut State, chat_id: ConversationId, step: TurnStep) -> anyhow::Result<UpdateOutcome> {
+    let outcome = run_turn(state, chat_id, step);
+    if !matches!(outcome, Ok(UpdateOutcome::Pending(_))) {
+        handle_queued(state, chat_id);
+    }
+    outcome
+}
+
+/// Handles the messages and button presses queued while the chat was busy, until a turn waits on the LLM again.
+fn handle_queued(state: &mut State, chat_id: ConversationId) {
+    while !state.context_manager.busy(chat_id) {
+        let Some(input) = state.context_manager.next_queued(chat_id) else {
+            return;
+        };
+        let outcome = match input {
+            QueuedInput::Message(text) => start_turn(state, chat_id, &text),
+            QueuedInput::Button(data) => match CallbackAction::parse(&data) {
+                Some(action) => handle_button(state, chat_id, action),
+                None => continue,
+            },
         }
-        Message::Request {
-            ref source,
-            ref body,
-            ..
-        } => {
-            return handle_internal_request(source, body, state);
+        .unwrap_or_else(|e| UpdateOutcome::Errored(e.to_string()));
+        println!("queued input in chat {}: {:?}", chat_id, outcome);
+    }
+}
+
+fn run_turn(state: &mut State, chat_id: ConversationId, mut step: TurnStep) -> anyhow::Result<UpdateOutcome> {
+    loop {
+        match step {
+            TurnStep::Ask {
+                turn_id,
+                messages,
+                tools,
+            } => {
+                let context =
+                    serde_json::to_vec(&RequestContext::Llm(LlmContext { chat_id, turn_id }))?;
+                match state.llm.send_chat(messages, tools, &context) {
+                    Ok(()) => return Ok(UpdateOutcome::Pending(chat_id)),
+                    // ends the turn, the buyer gets told it failed
+                    Err(e) => step = state.context_manager.advance_turn(chat_id, Err(e))?,
+                }
+            }
+            TurnStep::Done(reply) => {
+                finish_turn(state, chat_id, &reply)?;
+                return Ok(UpdateOutcome::Replied(chat_id));
+            }
         }
     }
 }
 
-fn handle_internal_request(source: &Address, body: &[u8], state: &mut State) -> anyhow::Result<()> {
-    let State {
-        context_manager,
-        config: _,
-        tg_api,
-        tg_worker,
-        openai_api,
-        ..
-    } = state;
+/// Acts on the chatbot's response and replies with it, or with the link to buy at if an offer got finalized.
+fn finish_turn(state: &mut State, chat_id: ConversationId, reply: &LlmReply) -> anyhow::Result<()> {
+    let context_manager = &mut state.context_manager;
+    let (finalized_offer_opt, bid_outcomes) = context_manager.act(chat_id, reply);
+    let mut text = if bid_outcomes.is_empty() {
+        reply.text.clone()
+    } else {
+        bid_outcomes.join("\n")
+    };
+    if let Some(additional_text) = &context_manager.additional_text(chat_id) {
+        text += additional_text;
+    }
+    if text.trim().is_empty() {
+        text = EMPTY_REPLY.to_string();
+    }
 
-    let Ok(TgResponse::Update(tg_update)) = serde_json::from_slice(body) else {
-        return Err(anyhow::anyhow!("unexpected response: {:?}", body));
+    let sent = if finalized_offer_opt.is_some() {
+        send_offer_reply(state, chat_id, String::new(), finalized_offer_opt)
+    } else {
+        let images = context_manager.images_to_present(chat_id, &text);
+        let keyboard = if let Some(price) = context_manager.counter_offer(chat_id) {
+            Some(keyboards::counter_offer_keyboard(price))
+        } else {
+            context_manager
+                .suggested_address(chat_id)
+                .map(|address| keyboards::use_address_keyboard(&address))
+        };
+        present_images(state, chat_id, &images);
+        send_reply_with_keyboard(state, chat_id, text, keyboard)
+    };
+    // what acting on the reply told the seller and other chats, like outbid notices, goes out right away
+    send_notifications(state);
+    sent
+}
+
+/// Shows the pictures of the NFTs a reply presents ahead of it, as an album if there are several.
+fn present_images(state: &mut State, chat_id: ConversationId, images: &[String]) {
+    let sent = match images {
+        [] => return,
+        [image] => state.transports().send_photo(chat_id, image, None),
+        images => state.transports().send_album(chat_id, images),
     };
+    if let Err(e) = sent {
+        println!("failed to show pictures in chat {}: {:?}", chat_id, e);
+    }
+}
 
-    let updates = tg_update.updates;
-    // assert update is from our worker
-    if source != tg_worker {
-        return Err(anyhow::anyhow!(
-            "unexpected source: {:?}, expected: {:?}",
-            source,
-            tg_worker
-        ));
+/// Hands the response to a request sent without waiting to what sent it, going by the request's context.
+/// `response` is the body of the response, or why the request failed.
+fn handle_response(context: &[u8], response: anyhow::Result<&[u8]>, state: &mut Option<State>) {
+    match serde_json::from_slice(context) {
+        Ok(RequestContext::Llm(context)) => {
+            let completion = response.and_then(llm::completion_body);
+            handle_llm_response(context, completion, state);
+        }
+        Ok(RequestContext::Telegram(id)) => {
+            let result = response.and_then(tg_api::response_result);
+            handle_tg_response(id, result, state);
+        }
+        Err(e) => println!("response with an unknown context: {:?}", e),
     }
+}
 
-    let Some(update) = updates.last() else {
-        return Ok(());
+/// Continues the chat turn a completion arrived for, or ends it if the LLM request failed.
+/// Completions of turns that aren't in flight anymore, like after a reset, are dropped.
+fn handle_llm_response(
+    LlmContext { chat_id, turn_id }: LlmContext,
+    completion: anyhow::Result<Vec<u8>>,
+    state: &mut Option<State>,
+) {
+    let Some(state) = state else {
+        return;
     };
+    if !state.context_manager.turn_in_flight(chat_id, turn_id) {
+        println!("dropped a stale completion for chat {}", chat_id);
+        return;
+    }
+    let completion = completion.and_then(|body| state.llm.parse_completion(&body));
+    let outcome = state
+        .context_manager
+        .advance_turn(chat_id, completion)
+        .and_then(|step| drive_turn(state, chat_id, step))
+        .unwrap_or_else(|e| UpdateOutcome::Errored(e.to_string()));
+    println!("completion for chat {}: {:?}", chat_id, outcome);
 
-    let msg = match &update.content {
-        TgMessage(msg) | TgChannelPost(msg) => msg,
-        _ => return Err(anyhow::anyhow!("unexpected content: {:?}", update.content)),
+    send_notifications(state);
+    state.save();
+}
+
+/// Releases the Telegram request the outbox got an answer to, or queues it again if it failed,
+/// then sends what waited for it.
+fn handle_tg_response(id: u64, result: anyhow::Result<()>, state: &mut Option<State>) {
+    let Some(state) = state else {
+        return;
     };
+    state.outbox.complete(id, result);
+    state.flush_outbox();
+    // tells the seller about messages dropped for good
+    send_notifications(state);
+    state.save();
+}
 
-    let text = msg.text.clone().unwrap_or_default();
+/// Signs the offer, along with the link the buyer can buy the NFT at.
+fn sign_offer(
+    wallet: &LocalWallet,
+    config: &InitialConfig,
+    finalized_offer: &FinalizedOfferCommand,
+) -> anyhow::Result<SignedOffer> {
+    let (uid, sig) = contracts::_create_offer(
+        wallet,
+        &EthAddress::from_str(&finalized_offer.nft_key.address)?,
+        finalized_offer.nft_key.id,
+        &EthAddress::from_str(&finalized_offer.buyer_address)?,
+        finalized_offer.price,
+        finalized_offer.valid_until,
+    )?;
+    let signature = format!("0x{}", hex::encode(sig.as_bytes()));
 
-    let mut params = SendMessageParams::builder()
-        .chat_id(ChatId::Integer(msg.chat.id))
-        .text("temp".to_string())
-        .build();
+    let link = format!(
+        "{}/buy?nft={}&id={}&price={}&valid={}&uid={}&sig={}&chain={}",
+        config.hosted_url,
+        finalized_offer.nft_key.address,
+        finalized_offer.nft_key.id,
+        finalized_offer.price,
+        finalized_offer.valid_until,
+        uid,
+        signature,
+        finalized_offer.nft_key.chain
+    );
+    Ok(SignedOffer {
+        nft: finalized_offer.nft_key.clone(),
+        price: finalized_offer.price,
+        buyer_address: finalized_offer.buyer_address.clone(),
+        valid_until: finalized_offer.valid_until,
+        uid,
+        signature,
+        link,
+    })
+}
 
-    params.text = if text == "/reset" {
-        context_manager.clear(msg.chat.id);
-        "Reset succesful!".to_string()
+/// Answers a buyer agent on another node. Messages that aren't barter requests are dropped.
+fn handle_node_request(message: &Message, state: &mut Option<State>) {
+    let Message::Request {
+        ref source,
+        ref body,
+        ..
+    } = message
+    else {
+        return;
+    };
+    // the version is read on its own first, requests of other versions may not parse as this one
+    let Some(version) = serde_json::from_slice::<serde_json::Value>(body)
+        .ok()
+        .and_then(|request| request.get("version")?.as_u64())
+    else {
+        println!("dropped a message from {}", source);
+        return;
+    };
+    let response = if version != PROTOCOL_VERSION as u64 {
+        BuyerResponse::UnsupportedVersion {
+            supported: PROTOCOL_VERSION,
+        }
     } else {
-        let mut text = context_manager.chat(msg.chat.id, &text, &openai_api)?;
-        let finalized_offer_opt = context_manager.act(msg.chat.id, &text);
-        if let Some(additional_text) = &context_manager.additional_text(msg.chat.id) {
-            text += additional_text;
-        }
-
-        if let Some(finalized_offer) = finalized_offer_opt {
-            let valid_until = std::time::SystemTime::now()
-                .duration_since(std::time::UNIX_EPOCH)
-                .expect("Time went backwards")
-                .as_secs()
-                + 3600;
-
-            let (uid, sig) = contracts::_create_offer(
-                &state.wallet,
-                &EthAddress::from_str(&finalized_offer.nft_key.address)?,
-                finalized_offer.nft_key.id,
-                &EthAddress::from_str(&finalized_offer.buyer_address)?,
-                finalized_offer.price,
-                valid_until,
-            )?;
-
-            let link = format!(
-                "{}/buy?nft={}&id={}&price={}&valid={}&uid={}&sig={}&chain={}",
-                state.config.hosted_url,
-                finalized_offer.nft_key.address,
-                finalized_offer.nft_key.id,
-                finalized_offer.price,
-                valid_until,
-                uid,
-                format!("0x{}", hex::encode(sig.as_bytes())),
-                finalized_offer.nft_key.chain
-            );
-
-            format!("buy it at the link: {}", &link)
-        } else {
-            text
+        match (serde_json::from_slice::<BarterRequest>(body), state) {
+            (Err(e), _) => BuyerResponse::Error(format!("malformed request: {}", e)),
+            (Ok(_), None) => BuyerResponse::Error("the seller isn't set up yet".to_string()),
+            (Ok(request), Some(state)) => {
+                if state.peers.admit(&source.node, helpers::now()) {
+                    let response = handle_barter_request(state, source, request.body)
+                        .unwrap_or_else(|e| BuyerResponse::Error(e.to_string()));
+                    send_notifications(state);
+                    state.save();
+                    response
+                } else {
+                    BuyerResponse::Error("too many requests, try again in a minute".to_string())
+                }
+            }
+        }
+    };
+    let response = BarterResponse {
+        version: PROTOCOL_VERSION,
+        body: response,
+    };
+    let Ok(response_body) = serde_json::to_vec(&response) else {
+        return;
+    };
+    if let Err(e) = Response::new().body(response_body).send() {
+        println!("failed to answer {}: {:?}", source, e);
+    }
+}
+
+/// Acts on a barter request, the node it came from is its own conversation.
+/// Offers go through the same policies, reservations and approvals as the chats.
+fn handle_barter_request(
+    state: &mut State,
+    source: &Address,
+    request: BuyerRequest,
+) -> anyhow::Result<BuyerResponse> {
+    let (chat_id, new) = state.peers.conversation(source);
+    if new {
+        state
+            .context_manager
+            .notify_admin(format!("Buyer agent {} started chat {}", source.node, chat_id));
+    }
+    Ok(match request {
+        BuyerRequest::ListInventory => BuyerResponse::Inventory(state.context_manager.inventory()),
+        BuyerRequest::Offer {
+            nft,
+            price,
+            buyer_address,
+        } => {
+            if state.context_manager.paused {
+                return Ok(BuyerResponse::Rejected {
+                    nft,
+                    reason: "The seller paused selling, try again later.".to_string(),
+                });
+            }
+            match state
+                .context_manager
+                .structured_offer(chat_id, &nft, price, &buyer_address)
+            {
+                OfferDecision::Accepted(offer) if state.context_manager.needs_approval(&offer) => {
+                    queue_for_approval(state, chat_id, &offer);
+                    BuyerResponse::PendingApproval {
+                        nft,
+                        price: offer.price,
+                    }
+                }
+                OfferDecision::Accepted(offer) => {
+                    let signed_offer = sign_reserved_offer(state, chat_id, &offer)?;
+                    notify_signed_offer(state, chat_id, &offer, &signed_offer.link);
+                    BuyerResponse::Signed(signed_offer)
+                }
+                OfferDecision::Countered(price) => BuyerResponse::Counter { nft, price },
+                OfferDecision::Rejected(reason) => BuyerResponse::Rejected { nft, reason },
+            }
         }
+        BuyerRequest::Withdraw => {
+            state.approvals.drop_chat(chat_id);
+            state.context_manager.cancel_offers(chat_id);
+            BuyerResponse::Withdrawn
+        }
+    })
+}
+
+/// Pushes the messages the context manager queued up for other chats, like outbid notices.
+fn send_notifications(state: &mut State) {
+    for undelivered in state.outbox.take_undelivered() {
+        // the seller can't hear about their own chat failing
+        if Some(undelivered.chat_id) != state.config.admin_chat_id {
+            state.context_manager.notify_admin(format!(
+                "A message to chat {} couldn't be delivered: {}",
+                undelivered.chat_id, undelivered.error
+            ));
+        }
+    }
+    state.send_notifications();
+}
+
+/// Closes the auctions that have ended, and sends their winners a signed offer.
+/// Timers are also set to send the messages held back by the flood limits.
+fn handle_timer_message(state: &mut Option<State>) {
+    let Some(state) = state else {
+        return;
     };
-    tg_api.send_message(&params)?;
+    state.tg_api.set_pending_webhook(helpers::now());
+    state.flush_outbox();
+    for (chat_id, finalized_offer) in state.context_manager.close_auctions() {
+        let reply = "You won the auction!".to_string();
+        if let Err(e) = send_offer_reply(state, chat_id, reply, Some(finalized_offer)) {
+            println!("failed to settle auction: {:?}", e);
+        }
+    }
+    send_notifications(state);
     state.save();
-    Ok(())
+}
+
+/// Pauses or resumes selling, which stops every offer from being signed.
+/// Auctions that ended while paused get closed once resumed.
+fn set_paused(state: &mut State, paused: bool) {
+    state.context_manager.paused = paused;
+    if !paused {
+        timer::set_timer(0, None);
+    }
+}
+
+/// Sets a timer for the end of every running auction.
+fn schedule_auctions(state: &Option<State>) {
+    let Some(state) = state else {
+        return;
+    };
+    let now = helpers::now();
+    for ends_at in state.context_manager.auction_deadlines() {
+        timer::set_timer(ends_at.saturating_sub(now) * 1000, None);
+    }
 }
 
 call_init!(init);
@@ -249,9 +1605,20 @@ fn update_state(
     http_request_outcome: HttpRequestOutcome,
 ) {
     match http_request_outcome {
-        HttpRequestOutcome::Config(config) => {
+        HttpRequestOutcome::Config(mut config) => {
             match state {
-                Some(state) => state.config = config,
+                Some(state) => {
+                    // keeps the webhook set up at boot working
+                    if config.webhook_secret.is_none() {
+                        config.webhook_secret = state.config.webhook_secret.clone();
+                    }
+                    state.context_manager.configure_memory(config.memory());
+                    state.context_manager.usage.caps = config.budget_caps();
+                    state.llm.settings = config.llm_settings();
+                    // the server or key may have changed
+                    state.llm.backend = config.llm_backend();
+                    state.config = config;
+                }
                 None => *state = Some(State::new(our, config)),
             }
             if let Some(ref mut state) = state {
@@ -260,22 +1627,141 @@ fn update_state(
         }
         HttpRequestOutcome::AddNFT(add_nft_args) => match state {
             Some(state) => {
-                state.context_manager.add_nft(add_nft_args);
+                if let Err(e) = add_listing(state, add_nft_args) {
+                    println!("Failed to add NFT: {}", e);
+                }
                 state.save();
             }
             None => println!("Failed to fetch state, need to have one first before adding NFTs"),
         },
         HttpRequestOutcome::RemoveNFT(nft_key) => match state {
             Some(state) => {
+                state.approvals.drop_nft(&nft_key);
                 state.context_manager.remove_nft(&nft_key);
                 state.save();
             }
             None => println!("Failed to fetch state, need to have one first before removing NFTs"),
         },
+        HttpRequestOutcome::AddTemplate(add_template_args) => match state {
+            Some(state) => {
+                let template = PromptTemplate {
+                    persona: add_template_args.persona,
+                    body: add_template_args.body,
+                };
+                if let Err(e) = state
+                    .context_manager
+                    .templates
+                    .insert(add_template_args.name, template)
+                {
+                    println!("Failed to add template: {:?}", e);
+                }
+                state.save();
+            }
+            None => println!("Failed to fetch state, need to have one first before adding templates"),
+        },
+        HttpRequestOutcome::RemoveTemplate(name) => match state {
+            Some(state) => {
+                state.context_manager.remove_template(&name);
+                state.save();
+            }
+            None => println!("Failed to fetch state, need to have one first before removing templates"),
+        },
+        HttpRequestOutcome::SelectTemplate(select_template_args) => match state {
+            Some(state) => {
+                if let Err(e) = state
+                    .context_manager
+                    .select_template(select_template_args.name, select_template_args.nft_key)
+                {
+                    println!("Failed to select template: {}", e);
+                }
+                state.save();
+            }
+            None => println!("Failed to fetch state, need to have one first before selecting templates"),
+        },
+        HttpRequestOutcome::TgUpdate(update) => match state {
+            Some(state) => handle_tg_updates(vec![update], state),
+            None => println!("Failed to fetch state, need to have one first before handling updates"),
+        },
+        HttpRequestOutcome::Unmute(chat_id) => match state {
+            Some(state) => {
+                state.context_manager.unmute(chat_id);
+                state.save();
+            }
+            None => println!("Failed to fetch state, need to have one first before unmuting chats"),
+        },
+        HttpRequestOutcome::ApproveOffer(id) => match state {
+            Some(state) => {
+                match approve_offer(state, id) {
+                    Ok(answer) => println!("{}", answer),
+                    Err(e) => println!("Failed to approve offer {}: {:?}", id, e),
+                }
+                send_notifications(state);
+                state.save();
+            }
+            None => println!("Failed to fetch state, need to have one first before approving offers"),
+        },
+        HttpRequestOutcome::RejectOffer(id) => match state {
+            Some(state) => {
+                match reject_offer(state, id) {
+                    Ok(answer) => println!("{}", answer),
+                    Err(e) => println!("Failed to reject offer {}: {:?}", id, e),
+                }
+                send_notifications(state);
+                state.save();
+            }
+            None => println!("Failed to fetch state, need to have one first before rejecting offers"),
+        },
+        HttpRequestOutcome::Sold {
+            nft_key,
+            price,
+            buyer,
+            log,
+        } => match state {
+            Some(state) => {
+                record_sale(state, nft_key, price, buyer, log);
+                send_notifications(state);
+                state.save();
+            }
+            None => println!("Failed to fetch state, need to have one first before recording sales"),
+        },
         HttpRequestOutcome::None => {}
     }
 }
 
+/// Records a purchase logged on chain and takes the NFT off the market.
+/// Logs delivered again, and purchases of NFTs that aren't listed, are skipped.
+fn record_sale(state: &mut State, nft_key: NFTKey, price: U256, buyer: String, log: Option<SaleLog>) {
+    let usage = &mut state.context_manager.usage;
+    if let Some(log) = &log {
+        usage.sync_block(nft_key.chain, log.block);
+        if usage.has_sale(log) {
+            println!("skipped the sale in {} again", log.tx_hash);
+            return;
+        }
+    }
+    let Some(name) = state
+        .context_manager
+        .nft_listings
+        .get(&nft_key)
+        .map(|listing| listing.name.clone())
+    else {
+        println!("skipped the sale of {:?}, it isn't listed", nft_key);
+        return;
+    };
+    state.context_manager.notify_admin(format!(
+        "Sold {} for {} ETH to {}!",
+        name,
+        format_ether(price),
+        buyer
+    ));
+    state
+        .context_manager
+        .usage
+        .record_sale(nft_key.clone(), name, price, buyer, log, helpers::now());
+    state.approvals.drop_nft(&nft_key);
+    state.context_manager.remove_nft(&nft_key);
+}
+
 fn handle_http_messages(message: &Message, state: &mut Option<State>) -> HttpRequestOutcome {
     match message {
         Message::Response { .. } => {
@@ -296,13 +1782,35 @@ fn handle_http_messages(message: &Message, state: &mut Option<State>) -> HttpReq
             let Ok(path) = http_request.path() else {
                 return HttpRequestOutcome::None;
             };
+            let webhook_path = state
+                .as_ref()
+                .and_then(|state| state.tg_api.webhook_secret.as_deref())
+                .map(tg_api::webhook_path);
+            if webhook_path.as_deref() == Some(path.as_str()) {
+                return tg_webhook(&http_request, &body.bytes, state);
+            }
             match path.as_str() {
+                "/chat" => {
+                    return web_chat(&http_request, &body.bytes, state);
+                }
                 "/status" => {
                     return fetch_status(state);
                 }
                 "/config" => {
                     return config(&body.bytes);
                 }
+                "/addtemplate" => {
+                    return add_template(&body.bytes);
+                }
+                "/removetemplate" => {
+                    return remove_template(&body.bytes);
+                }
+                "/selecttemplate" => {
+                    return select_template(&body.bytes, state);
+                }
+                "/listtemplates" => {
+                    return list_templates(state);
+                }
                 "/addnft" => {
                     return add_nft(&body.bytes);
                 }
@@ -312,6 +1820,30 @@ fn handle_http_messages(message: &Message, state: &mut Option<State>) -> HttpReq
                 "/listnfts" => {
                     return list_nfts(state);
                 }
+                "/bids" => {
+                    return list_bids(state);
+                }
+                "/flagged" => {
+                    return list_flagged(state);
+                }
+                "/unmute" => {
+                    return unmute(&body.bytes);
+                }
+                "/leaks" => {
+                    return list_leaks(state);
+                }
+                "/pending" => {
+                    return list_pending(state);
+                }
+                "/approveoffer" => {
+                    return offer_decision(&body.bytes, HttpRequestOutcome::ApproveOffer);
+                }
+                "/rejectoffer" => {
+                    return offer_decision(&body.bytes, HttpRequestOutcome::RejectOffer);
+                }
+                "/usage" => {
+                    return fetch_usage(state);
+                }
                 _ => {
                     return HttpRequestOutcome::None;
                 }
@@ -340,23 +1872,34 @@ fn handle_eth_message(message: &Message) -> HttpRequestOutcome {
                             return HttpRequestOutcome::None;
                         };
 
-                        let chain = match id {
-                            1 => 11155111,
-                            2 => 10,
-                            3 => 8453,
-                            4 => 42161,
-                            _ => 0,
+                        let chain = (id as usize)
+                            .checked_sub(1)
+                            .and_then(|index| helpers::SALE_CHAINS.get(index))
+                            .copied()
+                            .unwrap_or_default();
+                        let sale_log = match (log.transaction_hash, log.log_index, log.block_number) {
+                            (Some(tx_hash), Some(log_index), Some(block)) => Some(SaleLog {
+                                tx_hash: tx_hash.to_string(),
+                                log_index: u64::try_from(log_index).unwrap_or_default(),
+                                block: u64::try_from(block).unwrap_or_default(),
+                            }),
+                            _ => None,
                         };
 
                         println!(
                             "sell event with all of these: {:?}, {:?}, {:?}, {:?}",
                             nft, nft_id, buyer, price
                         );
-                        return HttpRequestOutcome::RemoveNFT(NFTKey {
-                            address: nft.to_string(),
-                            id: nft_id.to::<u64>(),
-                            chain,
-                        });
+                        return HttpRequestOutcome::Sold {
+                            nft_key: NFTKey {
+                                address: nft.to_string(),
+                                id: nft_id.to::<u64>(),
+                                chain,
+                            },
+                            price,
+                            buyer: buyer.to_string(),
+                            log: sale_log,
+                        };
                     }
                 }
                 _ => return HttpRequestOutcome::None,
@@ -381,9 +1924,21 @@ fn init(our: Address) {
             "/",
             "/status",
             "/config",
+            "/addtemplate",
+            "/removetemplate",
+            "/selecttemplate",
+            "/listtemplates",
             "/addnft",
             "/removenft",
             "/listnfts",
+            "/bids",
+            "/flagged",
+            "/unmute",
+            "/leaks",
+            "/usage",
+            "/pending",
+            "/approveoffer",
+            "/rejectoffer",
         ],
     )
     .expect("sell_ui serving errored!");
@@ -406,23 +1961,45 @@ fn init(our: Address) {
         .unwrap();
 
     http::serve_ui(&our, "ui/buy/", false, false, vec!["/buy"]).expect("buy_ui serving errored!");
+    // buyers chat from the buy page without logging in
+    http::bind_http_path("/chat", false, false).expect("binding /chat errored!");
 
     let mut state = State::fetch();
+    schedule_auctions(&state);
+    // messages queued before the restart, the answers to those in flight got lost with it
+    if let Some(ref mut state) = state {
+        state.outbox.requeue_in_flight();
+        state.flush_outbox();
+    }
 
     loop {
-        let Ok(message) = await_message() else {
-            continue;
+        let message = match await_message() {
+            Ok(message) => message,
+            Err(send_error) => {
+                // requests to the LLM or Telegram that timed out or couldn't be sent
+                if let Some(context) = send_error.context() {
+                    let error = anyhow::anyhow!("request failed: {:?}", send_error.kind());
+                    handle_response(context, Err(error), &mut state);
+                }
+                continue;
+            }
         };
+        // other nodes only get to talk the barter protocol
         if message.source().node != our.node {
+            handle_node_request(&message, &mut state);
             continue;
         }
 
-        if message.source().process == "http_server:distro:sys" {
+        if let (Message::Response { .. }, Some(context)) = (&message, message.context()) {
+            handle_response(context, Ok(message.body()), &mut state);
+        } else if message.source().process == "http_server:distro:sys" {
             let http_request_outcome = handle_http_messages(&message, &mut state);
             update_state(&our, &mut state, http_request_outcome);
         } else if message.source().process == "eth:distro:sys" {
             let http_request_outcome = handle_eth_message(&message);
             update_state(&our, &mut state, http_request_outcome);
+        } else if message.source().process == "timer:distro:sys" {
+            handle_timer_message(&mut state);
         } else {
             match handle_internal_messages(&message, &mut state) {
                 Ok(()) => {}
@@ -0,0 +1,269 @@
+use kinode_process_lib::http::{
+    HttpClientAction, HttpClientError, HttpClientResponse, HttpResponse, Method, OutgoingHttpRequest,
+};
+use kinode_process_lib::{get_blob, Request};
+use llm_interface::openai::Message;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::str::FromStr;
+
+/// Timeout of a completion request to an HTTP backend, in seconds
+const HTTP_TIMEOUT: u64 = 60;
+
+/// Base URL of OpenAI's API, the chats run on it unless another server is configured
+pub const OPENAI_BASE_URL: &str = "https://api.openai.com/v1";
+
+/// A chat to complete, in the format of the chat completions API.
+#[derive(Serialize, Debug, Clone)]
+pub struct ChatRequest {
+    pub model: String,
+    pub messages: Vec<Message>,
+    /// Declarations of the tools the LLM may call, as `{"type": "function", "function": {...}}`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tools: Vec<serde_json::Value>,
+    pub max_tokens: u16,
+    pub temperature: f32,
+}
+
+/// What the LLM answered, the text for the user and the tools it called.
+#[derive(Debug, Clone, Default)]
+pub struct Answer {
+    pub text: String,
+    pub tool_calls: Vec<FunctionCall>,
+}
+
+/// A tool the LLM called, with its arguments as a JSON string.
+#[derive(Deserialize, Debug, Clone)]
+pub struct FunctionCall {
+    pub name: String,
+    pub arguments: String,
+}
+
+/// A completed chat, with the tokens it took.
+pub struct Completion {
+    pub answer: Answer,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+}
+
+/// Tokens used and their estimated cost.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+pub struct Usage {
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    /// Estimated cost in millionths of a USD
+    pub cost_micros: u64,
+}
+
+impl Usage {
+    pub fn add(&mut self, other: &Usage) {
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+        self.cost_micros += other.cost_micros;
+    }
+}
+
+/// Something that can complete a chat.
+/// Chats are never waited on, so a slow completion doesn't hold up the other chats.
+pub trait LlmBackend: std::fmt::Debug {
+    /// Sends the chat without waiting, the completion arrives as a response carrying `context`.
+    fn send_chat(&self, request: &ChatRequest, context: &[u8]) -> anyhow::Result<()>;
+
+    /// Parses the completion out of the response to `send_chat`.
+    fn parse_completion(&self, body: &[u8]) -> anyhow::Result<Completion>;
+}
+
+/// Any server speaking the OpenAI chat completions API, OpenAI's own or one like a local llama.cpp or vLLM server.
+#[derive(Debug)]
+pub struct OpenaiCompatibleApi {
+    /// Base URL the `/chat/completions` path gets appended to, e.g. `http://localhost:8000/v1`
+    pub base_url: String,
+    pub api_key: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct CompletionResponse {
+    choices: Vec<CompletionChoice>,
+    usage: Option<CompletionUsage>,
+}
+
+#[derive(Deserialize)]
+struct CompletionUsage {
+    prompt_tokens: u64,
+    completion_tokens: u64,
+}
+
+#[derive(Deserialize)]
+struct CompletionChoice {
+    message: CompletionMessage,
+}
+
+#[derive(Deserialize)]
+struct CompletionMessage {
+    /// Not set when the LLM only called tools
+    #[serde(default)]
+    content: Option<String>,
+    #[serde(default)]
+    tool_calls: Vec<CompletionToolCall>,
+}
+
+#[derive(Deserialize)]
+struct CompletionToolCall {
+    function: FunctionCall,
+}
+
+impl OpenaiCompatibleApi {
+    fn completions_url(&self) -> anyhow::Result<url::Url> {
+        let url = format!("{}/chat/completions", self.base_url.trim_end_matches('/'));
+        Ok(url::Url::from_str(&url)?)
+    }
+
+    fn headers(&self) -> HashMap<String, String> {
+        let mut headers: HashMap<String, String> =
+            HashMap::from_iter([("Content-Type".into(), "application/json".into())]);
+        if let Some(api_key) = &self.api_key {
+            headers.insert("Authorization".into(), format!("Bearer {}", api_key));
+        }
+        headers
+    }
+}
+
+impl LlmBackend for OpenaiCompatibleApi {
+    fn send_chat(&self, request: &ChatRequest, context: &[u8]) -> anyhow::Result<()> {
+        let action = HttpClientAction::Http(OutgoingHttpRequest {
+            method: Method::POST.to_string(),
+            version: None,
+            url: self.completions_url()?.to_string(),
+            headers: self.headers(),
+        });
+        Request::to(("our", "http_client", "distro", "sys"))
+            .body(serde_json::to_vec(&action)?)
+            .blob_bytes(serde_json::to_vec(request)?)
+            .context(context.to_vec())
+            .expects_response(HTTP_TIMEOUT)
+            .send()?;
+        Ok(())
+    }
+
+    fn parse_completion(&self, body: &[u8]) -> anyhow::Result<Completion> {
+        let completion: CompletionResponse = serde_json::from_slice(body)
+            .map_err(|e| anyhow::anyhow!("Failed to deserialize completion: {}", e))?;
+        let (prompt_tokens, completion_tokens) = completion
+            .usage
+            .map(|usage| (usage.prompt_tokens, usage.completion_tokens))
+            .unwrap_or_default();
+        let message = completion
+            .choices
+            .into_iter()
+            .next()
+            .map(|choice| choice.message)
+            .ok_or_else(|| anyhow::anyhow!("completion without choices"))?;
+        let answer = Answer {
+            text: message.content.unwrap_or_default(),
+            tool_calls: message
+                .tool_calls
+                .into_iter()
+                .map(|tool_call| tool_call.function)
+                .collect(),
+        };
+        Ok(Completion {
+            answer,
+            prompt_tokens,
+            completion_tokens,
+        })
+    }
+}
+
+/// The body of the http_client's response to `send_chat`, if the server answered the request successfully.
+/// Error statuses are turned into errors, rather than failing to parse the error body as a completion.
+pub fn completion_body(response: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let response: Result<HttpClientResponse, HttpClientError> = serde_json::from_slice(response)?;
+    let status = match response {
+        Ok(HttpClientResponse::Http(HttpResponse { status, .. })) => status,
+        Ok(response) => return Err(anyhow::anyhow!("unexpected LLM response: {:?}", response)),
+        Err(e) => return Err(anyhow::anyhow!("LLM request failed: {:?}", e)),
+    };
+    let body = get_blob().map(|blob| blob.bytes).unwrap_or_default();
+    if !(200..300).contains(&status) {
+        return Err(anyhow::anyhow!(
+            "LLM server answered {}: {}",
+            status,
+            String::from_utf8_lossy(&body)
+        ));
+    }
+    Ok(body)
+}
+
+/// Model parameters, configurable per deployment.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LlmSettings {
+    pub model: String,
+    /// Kept low to make the LLM rather predictable
+    pub temperature: f32,
+    /// Kept low to save money
+    pub max_tokens: u16,
+    /// Price of 1000 prompt tokens, in millionths of a USD
+    pub prompt_price_per_1k: u64,
+    /// Price of 1000 completion tokens, in millionths of a USD
+    pub completion_price_per_1k: u64,
+}
+
+impl Default for LlmSettings {
+    fn default() -> Self {
+        Self {
+            model: "gpt-4-1106-preview".into(),
+            temperature: 0.2,
+            max_tokens: 150,
+            prompt_price_per_1k: 10_000,
+            completion_price_per_1k: 30_000,
+        }
+    }
+}
+
+/// The backend the chats run on, with the model parameters to use.
+#[derive(Debug)]
+pub struct Llm {
+    pub backend: Box<dyn LlmBackend>,
+    pub settings: LlmSettings,
+}
+
+impl Llm {
+    /// Sends the chat without waiting, the completion arrives as a response carrying `context`,
+    /// to be parsed with `parse_completion`. The LLM may call the `tools` declared.
+    pub fn send_chat(
+        &self,
+        messages: Vec<Message>,
+        tools: Vec<serde_json::Value>,
+        context: &[u8],
+    ) -> anyhow::Result<()> {
+        self.backend.send_chat(&self.chat_request(messages, tools), context)
+    }
+
+    /// Parses the completion out of the response to a chat.
+    pub fn parse_completion(&self, body: &[u8]) -> anyhow::Result<(Answer, Usage)> {
+        Ok(self.priced(self.backend.parse_completion(body)?))
+    }
+
+    /// The answer of the completion, and what it cost.
+    fn priced(&self, completion: Completion) -> (Answer, Usage) {
+        let usage = Usage {
+            prompt_tokens: completion.prompt_tokens,
+            completion_tokens: completion.completion_tokens,
+            cost_micros: (completion.prompt_tokens * self.settings.prompt_price_per_1k
+                + completion.completion_tokens * self.settings.completion_price_per_1k)
+                / 1000,
+        };
+        (completion.answer, usage)
+    }
+
+    /// The request to communicate with the LLM backend.
+    fn chat_request(&self, messages: Vec<Message>, tools: Vec<serde_json::Value>) -> ChatRequest {
+        ChatRequest {
+            model: self.settings.model.clone(),
+            messages,
+            tools,
+            max_tokens: self.settings.max_tokens,
+            temperature: self.settings.temperature,
+        }
+    }
+}
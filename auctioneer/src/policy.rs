@@ -0,0 +1,223 @@
+use crate::structs::{NFTListing, NFTState};
+use alloy_primitives::U256;
+use serde::{Deserialize, Serialize};
+
+/// Denominator for basis points
+const BPS: u64 = 10_000;
+
+/// Per-listing negotiation rules.
+/// These are enforced in code, the LLM only gets to word the decision.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NegotiationPolicy {
+    /// The asking price the negotiation opens with, defaults to twice the floor
+    pub opening_price: Option<U256>,
+    /// Maximum drop of the asking price per counter-offer, in basis points of the opening price
+    pub max_concession_bps: u64,
+    /// Number of counter-offers to make before the asking price may reach the floor
+    pub min_counter_offers: u32,
+}
+
+impl Default for NegotiationPolicy {
+    fn default() -> Self {
+        Self {
+            opening_price: None,
+            max_concession_bps: 1_000,
+            min_counter_offers: 2,
+        }
+    }
+}
+
+/// When an offer needs the seller's approval before it gets signed.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub enum ApprovalPolicy {
+    /// Offers get signed right away
+    #[default]
+    Auto,
+    /// Offers within this many basis points above the floor need approval
+    NearFloor { within_bps: u64 },
+    /// Every offer needs approval
+    Always,
+}
+
+impl ApprovalPolicy {
+    /// Whether an offer at the price needs the seller's approval.
+    pub fn requires_approval(&self, floor: U256, price: U256) -> bool {
+        match self {
+            ApprovalPolicy::Auto => false,
+            ApprovalPolicy::NearFloor { within_bps } => {
+                price.saturating_mul(U256::from(BPS))
+                    <= floor.saturating_mul(U256::from(BPS + within_bps))
+            }
+            ApprovalPolicy::Always => true,
+        }
+    }
+}
+
+/// What the policy decided about a proposed price.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PolicyDecision {
+    /// Sell at the proposed price
+    Accept(U256),
+    /// Don't sell, ask for this price instead
+    Counter(U256),
+    /// Don't sell, and don't come down any further
+    Reject,
+}
+
+/// Evaluates a proposed price for a listing, updating the negotiation state of the chat.
+/// `accepted_bid` is the highest bid accepted earlier for this NFT in any chat, which is never undercut.
+/// Only the price of the last decision stays accepted, a counter-offer or rejection withdraws an earlier acceptance.
+pub fn evaluate(
+    listing: &NFTListing,
+    state: &mut NFTState,
+    accepted_bid: U256,
+    bid: U256,
+) -> PolicyDecision {
+    let decision = decide(listing, state, accepted_bid, bid);
+    state.accepted_price = match decision {
+        PolicyDecision::Accept(price) => Some(price),
+        PolicyDecision::Counter(_) | PolicyDecision::Reject => None,
+    };
+    decision
+}
+
+/// Decides on the price, moving the asking price down when countering.
+fn decide(listing: &NFTListing, state: &mut NFTState, accepted_bid: U256, bid: U256) -> PolicyDecision {
+    if bid < accepted_bid {
+        return PolicyDecision::Reject;
+    }
+
+    let policy = &listing.policy;
+    let floor = listing.min_price.max(accepted_bid);
+    let opening = policy
+        .opening_price
+        .unwrap_or(listing.min_price.saturating_mul(U256::from(2)))
+        .max(floor);
+    let ask = state.asking_price.unwrap_or(opening);
+
+    if bid >= ask {
+        return PolicyDecision::Accept(bid);
+    }
+
+    let concession = opening.saturating_mul(U256::from(policy.max_concession_bps)) / U256::from(BPS);
+    // keep enough room above the floor for the remaining mandatory counter-offers
+    let rounds_left = policy
+        .min_counter_offers
+        .saturating_sub(state.counter_offers + 1);
+    let lower_bound = floor.saturating_add(concession.saturating_mul(U256::from(rounds_left)));
+    let next_ask = ask
+        .saturating_sub(concession)
+        .max(lower_bound)
+        .max(floor)
+        .min(ask);
+
+    if bid >= next_ask {
+        return PolicyDecision::Accept(bid);
+    }
+    if next_ask == ask && ask == floor {
+        return PolicyDecision::Reject;
+    }
+
+    state.asking_price = Some(next_ask);
+    state.counter_offers += 1;
+    PolicyDecision::Counter(next_ask)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auction::SaleMode;
+    use alloy_primitives::utils::parse_units;
+
+    fn eth(amount: &str) -> U256 {
+        parse_units(amount, "ether").unwrap().into()
+    }
+
+    fn listing(floor: &str) -> NFTListing {
+        NFTListing {
+            name: "Punk".to_string(),
+            min_price: eth(floor),
+            address: "0x0".to_string(),
+            description: None,
+            custom_prompt: None,
+            policy: NegotiationPolicy::default(),
+            mode: SaleMode::Negotiation,
+            template: None,
+            image: None,
+            approval: ApprovalPolicy::default(),
+        }
+    }
+
+    #[test]
+    fn accepts_the_opening_price() {
+        let mut state = NFTState::default();
+        // opens at twice the floor
+        assert_eq!(
+            evaluate(&listing("1"), &mut state, U256::ZERO, eth("2")),
+            PolicyDecision::Accept(eth("2"))
+        );
+        assert_eq!(state.accepted_price, Some(eth("2")));
+    }
+
+    #[test]
+    fn counters_down_to_the_floor_then_rejects() {
+        let listing = listing("1");
+        let mut state = NFTState::default();
+        let mut asks = Vec::new();
+        loop {
+            match evaluate(&listing, &mut state, U256::ZERO, eth("0.5")) {
+                PolicyDecision::Counter(ask) => asks.push(ask),
+                PolicyDecision::Reject => break,
+                PolicyDecision::Accept(_) => panic!("accepted below the floor"),
+            }
+        }
+        assert_eq!(asks, vec![eth("1.8"), eth("1.6"), eth("1.4"), eth("1.2"), eth("1")]);
+        assert_eq!(state.counter_offers, 5);
+        assert_eq!(state.accepted_price, None);
+    }
+
+    #[test]
+    fn accepts_a_bid_meeting_the_next_ask() {
+        let listing = listing("1");
+        let mut state = NFTState::default();
+        evaluate(&listing, &mut state, U256::ZERO, eth("1"));
+        // the next ask would be 1.6
+        assert_eq!(
+            evaluate(&listing, &mut state, U256::ZERO, eth("1.65")),
+            PolicyDecision::Accept(eth("1.65"))
+        );
+    }
+
+    #[test]
+    fn a_counter_withdraws_an_earlier_acceptance() {
+        let listing = listing("1");
+        let mut state = NFTState::default();
+        evaluate(&listing, &mut state, U256::ZERO, eth("2"));
+        assert!(matches!(
+            evaluate(&listing, &mut state, U256::ZERO, eth("1")),
+            PolicyDecision::Counter(_)
+        ));
+        assert_eq!(state.accepted_price, None);
+    }
+
+    #[test]
+    fn never_undercuts_an_accepted_bid() {
+        let mut state = NFTState {
+            accepted_price: Some(eth("3")),
+            ..Default::default()
+        };
+        assert_eq!(
+            evaluate(&listing("1"), &mut state, eth("3"), eth("2.5")),
+            PolicyDecision::Reject
+        );
+        assert_eq!(state.accepted_price, None);
+    }
+
+    #[test]
+    fn near_floor_offers_need_approval() {
+        let policy = ApprovalPolicy::NearFloor { within_bps: 500 };
+        assert!(policy.requires_approval(eth("1"), eth("1.05")));
+        assert!(!policy.requires_approval(eth("1"), eth("1.06")));
+        assert!(!ApprovalPolicy::Auto.requires_approval(eth("1"), eth("1")));
+    }
+}
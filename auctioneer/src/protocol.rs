@@ -0,0 +1,188 @@
+//! Protocol for buyer agents on other Kinode nodes.
+//! Buyers send a `BarterRequest` and get a `BarterResponse` back. Whatever happens later,
+//! like an offer the seller approved or a message from the seller, gets pushed to them as a `BarterPush`.
+//! Everything is JSON, prices are in wei.
+
+use crate::render::Rendered;
+use crate::structs::NFTKey;
+use crate::transport::{ChatTransport, ConversationId};
+use alloy_primitives::U256;
+use frankenstein::InlineKeyboardMarkup;
+use kinode_process_lib::{Address, Request};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Version of the protocol, requests of other versions get `UnsupportedVersion`.
+pub const PROTOCOL_VERSION: u32 = 1;
+/// Requests a buyer node can send per minute
+const REQUESTS_PER_MINUTE: u32 = 30;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BarterRequest {
+    pub version: u32,
+    pub body: BuyerRequest,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum BuyerRequest {
+    /// What's for sale
+    ListInventory,
+    /// Offers to buy the NFT at the price, to be signed to `buyer_address`.
+    /// Negotiated under the same policies as the chats, offering the price of a counter-offer accepts it.
+    Offer {
+        nft: NFTKey,
+        price: U256,
+        buyer_address: String,
+    },
+    /// Withdraws the pending offers and releases the NFTs reserved for them
+    Withdraw,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BarterResponse {
+    pub version: u32,
+    pub body: BuyerResponse,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum BuyerResponse {
+    Inventory(Vec<InventoryItem>),
+    /// The offer is accepted and signed, the NFT is reserved until it expires
+    Signed(SignedOffer),
+    /// The offer is accepted, but the seller approves it first. The signed offer gets pushed once they do.
+    PendingApproval { nft: NFTKey, price: U256 },
+    /// The seller asks for this price instead
+    Counter { nft: NFTKey, price: U256 },
+    Rejected { nft: NFTKey, reason: String },
+    Withdrawn,
+    UnsupportedVersion { supported: u32 },
+    /// The request couldn't be handled, like a malformed request
+    Error(String),
+}
+
+/// Sent to buyer nodes outside of a response.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BarterPush {
+    pub version: u32,
+    pub body: BuyerNotification,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum BuyerNotification {
+    /// An offer that waited for the seller's approval got signed
+    Signed(SignedOffer),
+    /// An offer that waited for the seller's approval got turned down, or the NFT is gone
+    Rejected { nft: NFTKey, reason: String },
+    /// Anything else the seller's side has to say, like messages the seller sent with /say, as HTML
+    Message { html: String },
+}
+
+/// A listing as buyer agents see it, without anything the negotiation keeps secret.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct InventoryItem {
+    pub nft: NFTKey,
+    pub name: String,
+    pub description: Option<String>,
+    /// `negotiation`, `english`, `sealed` or `dutch`
+    pub sale_mode: String,
+    /// The asking price of a negotiation, the current price of a Dutch auction,
+    /// or the highest bid of an English auction, if there is one
+    pub price: Option<U256>,
+    /// Reserved for a buyer who got a signed offer
+    pub reserved: bool,
+    /// Human-readable state of the sale
+    pub status: String,
+}
+
+/// An offer signed by the seller, the buyer completes the purchase with it on chain.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SignedOffer {
+    pub nft: NFTKey,
+    pub price: U256,
+    pub buyer_address: String,
+    /// Unix timestamp until which the offer can be used
+    pub valid_until: u64,
+    pub uid: u64,
+    /// The seller's signature, as 0x-prefixed hex
+    pub signature: String,
+    /// The buy page for the offer
+    pub link: String,
+}
+
+/// The buyer nodes that talked to us, each is its own conversation.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Peers {
+    next_id: u64,
+    ids: HashMap<String, u64>,
+    /// Where to push to, the process of the buyer agent that last sent a request
+    addresses: HashMap<u64, Address>,
+    /// Requests per node in the current minute, along with that minute
+    #[serde(skip)]
+    requests: HashMap<String, (u64, u32)>,
+}
+
+impl Peers {
+    /// The conversation of the node the request came from, and whether it's new.
+    pub fn conversation(&mut self, source: &Address) -> (ConversationId, bool) {
+        let (id, new) = match self.ids.get(&source.node) {
+            Some(id) => (*id, false),
+            None => {
+                self.next_id += 1;
+                self.ids.insert(source.node.clone(), self.next_id);
+                (self.next_id, true)
+            }
+        };
+        self.addresses.insert(id, source.clone());
+        (ConversationId::Node(id), new)
+    }
+
+    /// Counts a request from the node, false if it sent too many this minute.
+    pub fn admit(&mut self, node: &str, now: u64) -> bool {
+        let minute = now / 60;
+        self.requests.retain(|_, (counted_in, _)| *counted_in == minute);
+        let (_, count) = self
+            .requests
+            .entry(node.to_string())
+            .or_insert((minute, 0));
+        if *count >= REQUESTS_PER_MINUTE {
+            return false;
+        }
+        *count += 1;
+        true
+    }
+
+    /// Pushes a notification to the buyer node of the conversation, without waiting for an answer.
+    pub fn push(&self, conversation: ConversationId, notification: BuyerNotification) -> anyhow::Result<()> {
+        let ConversationId::Node(id) = conversation else {
+            return Err(anyhow::anyhow!("{} isn't a buyer node", conversation));
+        };
+        let Some(address) = self.addresses.get(&id) else {
+            return Err(anyhow::anyhow!("no address for {}", conversation));
+        };
+        let push = BarterPush {
+            version: PROTOCOL_VERSION,
+            body: notification,
+        };
+        Request::to(address.clone())
+            .body(serde_json::to_vec(&push)?)
+            .send()?;
+        Ok(())
+    }
+}
+
+/// Buyer nodes get the prose the chats get as a `Message`, buttons are left out.
+impl ChatTransport for Peers {
+    fn send(
+        &mut self,
+        conversation: ConversationId,
+        rendered: Rendered,
+        _keyboard: Option<InlineKeyboardMarkup>,
+    ) -> anyhow::Result<()> {
+        self.push(
+            conversation,
+            BuyerNotification::Message {
+                html: rendered.to_html(),
+            },
+        )
+    }
+}
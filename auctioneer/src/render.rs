@@ -0,0 +1,277 @@
+//! Turns what the bot has to say into Telegram messages.
+//! Messages are sent as HTML, which needs far fewer characters escaped than MarkdownV2.
+
+/// Telegram's limit on the length of a message, in UTF-16 code units of the text without markup.
+pub const MAX_MESSAGE_LEN: usize = 4096;
+/// Telegram's limit on the length of a photo caption.
+pub const MAX_CAPTION_LEN: usize = 1024;
+
+/// A listing as shown to buyers.
+#[derive(Debug, Clone)]
+pub struct ListingCard {
+    pub name: String,
+    pub chain: u64,
+    pub address: String,
+    pub id: u64,
+    pub description: Option<String>,
+    /// How it's being sold, and where the sale stands
+    pub status: String,
+}
+
+/// A message made of plain text, which gets escaped, and parts that are already HTML.
+#[derive(Debug, Clone, Default)]
+pub struct Rendered {
+    parts: Vec<Part>,
+}
+
+#[derive(Debug, Clone)]
+enum Part {
+    Text(String),
+    Html(String),
+}
+
+impl Rendered {
+    pub fn text(text: impl Into<String>) -> Self {
+        let mut rendered = Self::default();
+        rendered.push_text(text);
+        rendered
+    }
+
+    pub fn push_text(&mut self, text: impl Into<String>) -> &mut Self {
+        self.parts.push(Part::Text(text.into()));
+        self
+    }
+
+    /// Appends HTML as is, it's never split across messages.
+    pub fn push_html(&mut self, html: impl Into<String>) -> &mut Self {
+        self.parts.push(Part::Html(html.into()));
+        self
+    }
+
+    /// Whether the message fits in `max_len`, like a photo caption.
+    pub fn fits(&self, max_len: usize) -> bool {
+        self.pieces(max_len).iter().map(|(_, len)| len).sum::<usize>() <= max_len
+    }
+
+    /// The message as HTML, to be sent as a single message.
+    pub fn to_html(&self) -> String {
+        self.pieces(usize::MAX)
+            .into_iter()
+            .map(|(html, _)| html)
+            .collect()
+    }
+
+    /// The message as HTML, split into as many messages as Telegram needs.
+    pub fn into_messages(self) -> Vec<String> {
+        let mut messages = Vec::new();
+        let mut current = String::new();
+        let mut current_len = 0;
+        for (html, len) in self.pieces(MAX_MESSAGE_LEN) {
+            if current_len + len > MAX_MESSAGE_LEN && !current.is_empty() {
+                messages.push(std::mem::take(&mut current).trim().to_string());
+                current_len = 0;
+            }
+            current += &html;
+            current_len += len;
+        }
+        if !current.trim().is_empty() {
+            messages.push(current.trim().to_string());
+        }
+        messages
+    }
+
+    /// The parts as HTML along with their length as Telegram counts it, text split to at most `max_len`.
+    fn pieces(&self, max_len: usize) -> Vec<(String, usize)> {
+        let mut pieces = Vec::new();
+        for part in &self.parts {
+            match part {
+                Part::Text(text) => {
+                    for chunk in split_text(text, max_len) {
+                        let len = text_len(&chunk);
+                        pieces.push((escape_html(&chunk), len));
+                    }
+                }
+                Part::Html(html) => pieces.push((html.clone(), html_text_len(html))),
+            }
+        }
+        pieces
+    }
+}
+
+pub fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Length of plain text as Telegram counts it.
+fn text_len(text: &str) -> usize {
+    text.encode_utf16().count()
+}
+
+/// Length of HTML as Telegram counts it, without the tags and with entities as one character.
+fn html_text_len(html: &str) -> usize {
+    let mut len = 0;
+    let mut in_tag = false;
+    let mut in_entity = false;
+    for c in html.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' if in_tag => in_tag = false,
+            _ if in_tag => {}
+            '&' => {
+                in_entity = true;
+                len += 1;
+            }
+            ';' if in_entity => in_entity = false,
+            _ if in_entity => {}
+            c => len += c.len_utf16(),
+        }
+    }
+    len
+}
+
+/// Splits text into chunks of at most `max_len`, at paragraphs if possible, then at lines,
+/// sentences and words. Text without any of those gets cut wherever it has to.
+pub fn split_text(text: &str, max_len: usize) -> Vec<String> {
+    let mut chunks = Vec::new();
+    let mut rest = text;
+    while text_len(rest) > max_len {
+        // byte index of the longest prefix that fits
+        let mut limit = 0;
+        let mut len = 0;
+        for (index, c) in rest.char_indices() {
+            len += c.len_utf16();
+            if len > max_len {
+                break;
+            }
+            limit = index + c.len_utf8();
+        }
+        let window = &rest[..limit];
+        let boundary = |cut: Option<usize>| cut.filter(|cut| *cut > 0);
+        let cut = boundary(window.rfind("\n\n"))
+            .or_else(|| boundary(window.rfind('\n')))
+            .or_else(|| boundary(sentence_end(window)))
+            .or_else(|| boundary(window.rfind(' ')))
+            .unwrap_or(limit.max(rest.chars().next().map(char::len_utf8).unwrap_or(1)));
+        chunks.push(rest[..cut].to_string());
+        rest = &rest[cut..];
+    }
+    if !rest.is_empty() {
+        chunks.push(rest.to_string());
+    }
+    chunks
+}
+
+/// Byte index right after the last sentence in the text that's followed by whitespace.
+fn sentence_end(text: &str) -> Option<usize> {
+    let mut chars = text.char_indices().rev().peekable();
+    let mut end = None;
+    while let Some((index, c)) = chars.next() {
+        if c.is_whitespace() {
+            if let Some((_, '.' | '!' | '?')) = chars.peek() {
+                end = Some(index);
+                break;
+            }
+        }
+    }
+    end
+}
+
+/// Human-readable name of a chain the escrow is deployed on.
+pub fn chain_name(chain: u64) -> String {
+    match chain {
+        1 => "Ethereum".to_string(),
+        10 => "Optimism".to_string(),
+        8453 => "Base".to_string(),
+        42161 => "Arbitrum".to_string(),
+        11155111 => "Sepolia".to_string(),
+        chain => format!("chain {}", chain),
+    }
+}
+
+/// A listing in the overview of what's for sale: its name, chain and price.
+pub fn listing_card(card: &ListingCard) -> String {
+    format!(
+        "<b>{}</b> · {}\n{}",
+        escape_html(&card.name),
+        escape_html(&chain_name(card.chain)),
+        escape_html(&card.status)
+    )
+}
+
+/// A listing with all its details, for the `/item` command.
+pub fn listing_details(card: &ListingCard) -> Rendered {
+    let mut rendered = Rendered::default();
+    rendered.push_html(listing_card(card));
+    if let Some(description) = &card.description {
+        rendered.push_text(format!("\n\n{}", description));
+    }
+    rendered.push_html(format!(
+        "\n\nContract <code>{}</code>, token id {}",
+        escape_html(&card.address),
+        card.id
+    ));
+    rendered
+}
+
+/// The overview of what's for sale, for the `/list` command.
+pub fn listings_overview(cards: &[ListingCard]) -> Rendered {
+    if cards.is_empty() {
+        return Rendered::text("There's nothing for sale right now.");
+    }
+    let mut rendered = Rendered::default();
+    rendered.push_html("<b>For sale</b>");
+    for card in cards {
+        rendered.push_html(format!("\n\n{}", listing_card(card)));
+    }
+    rendered.push_text("\n\nWrite /item <name> for details.");
+    rendered
+}
+
+/// The link to the buy page, shown as what it buys rather than the long URL.
+pub fn buy_link(link: &str, name: &str, price: &str) -> String {
+    format!(
+        "<a href=\"{}\">Buy {} for {} ETH</a>",
+        escape_html(link),
+        escape_html(name),
+        escape_html(price)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_html_as_telegram_does() {
+        assert_eq!(html_text_len("<b>Ape</b> &amp; <a href=\"x\">buy</a>"), 9);
+        assert_eq!(html_text_len("&lt;3"), 2);
+        // outside the basic plane characters count twice
+        assert_eq!(html_text_len("🦍"), 2);
+    }
+
+    #[test]
+    fn splits_at_the_best_boundary() {
+        assert_eq!(split_text("short", 10), vec!["short"]);
+        assert_eq!(split_text("one\n\ntwo", 5), vec!["one", "\n\ntwo"]);
+        assert_eq!(split_text("First one. Second one.", 15), vec!["First one.", " Second one."]);
+        assert_eq!(split_text("aaaa bbbb cccc", 10), vec!["aaaa bbbb", " cccc"]);
+        assert_eq!(split_text("abcdefgh", 3), vec!["abc", "def", "gh"]);
+        for chunk in split_text(&"🦍".repeat(5), 3) {
+            assert!(text_len(&chunk) <= 3);
+        }
+    }
+
+    #[test]
+    fn splits_long_messages() {
+        let mut rendered = Rendered::text("a ".repeat(3_000));
+        rendered.push_html("<b>end</b>");
+        let messages = rendered.into_messages();
+        assert_eq!(messages.len(), 2);
+        assert!(messages.iter().all(|message| html_text_len(message) <= MAX_MESSAGE_LEN));
+        assert!(messages[1].ends_with("<b>end</b>"));
+        assert!(Rendered::text("  ").into_messages().is_empty());
+    }
+}
@@ -1,13 +1,21 @@
-use crate::context::ContextManager;
-use crate::tg_api::Api;
+use crate::context::{ContextManager, MemoryConfig};
+use crate::tg_api::{Api, Outbox, TgUpdateMode};
+use frankenstein::Update;
 use alloy_primitives::U256;
 use alloy_signer::LocalWallet;
-use kinode_process_lib::{get_state, set_state, Address};
-use llm_interface::api::openai::OpenaiApi;
+use kinode_process_lib::{get_state, println, set_state, Address};
+use std::collections::HashMap;
+use crate::llm::{Llm, LlmBackend, LlmSettings, OpenaiCompatibleApi, OPENAI_BASE_URL};
 use serde::{Deserialize, Serialize};
 use serde::Deserializer;
 use serde::Serializer;
-use crate::helpers::hydrate_state;
+use crate::helpers::{hydrate_state, now};
+use crate::policy::{ApprovalPolicy, NegotiationPolicy};
+use crate::approvals::ApprovalQueue;
+use crate::auction::{PriceDecay, SaleMode, SealedPricing};
+use crate::usage::{BudgetCaps, SaleLog};
+use crate::transport::{ConversationId, Telegram, Transports, WebChat};
+use crate::protocol::Peers;
 
 #[derive(Serialize, Deserialize, Default, Debug, Clone)]
 pub struct InitialConfig {
@@ -15,8 +23,102 @@ pub struct InitialConfig {
     pub telegram_bot_api_key: String,
     pub wallet_pk: String,
     pub hosted_url: String,
+    /// Number of messages kept verbatim per chat, at least 2, older ones get summarized
+    #[serde(default)]
+    pub history_capacity: Option<usize>,
+    /// Maximum number of entries per section of the summary of older messages
+    #[serde(default)]
+    pub summary_length: Option<usize>,
+    /// Base URL of an OpenAI-compatible server, like a local llama.cpp or vLLM server.
+    /// OpenAI's API is used if not set, `openai_key` is sent along as bearer token either way.
+    #[serde(default)]
+    pub llm_base_url: Option<String>,
+    #[serde(default)]
+    pub llm_model: Option<String>,
+    #[serde(default)]
+    pub llm_temperature: Option<f32>,
+    #[serde(default)]
+    pub llm_max_tokens: Option<u16>,
+    /// Price of 1000 prompt tokens in USD, for estimating the cost of the chats
+    #[serde(default)]
+    pub llm_prompt_price: Option<f64>,
+    /// Price of 1000 completion tokens in USD
+    #[serde(default)]
+    pub llm_completion_price: Option<f64>,
+    /// Maximum LLM spend per day in USD, the bot stops replying once it's reached
+    #[serde(default)]
+    pub daily_budget: Option<f64>,
+    /// Maximum LLM spend per calendar month in USD
+    #[serde(default)]
+    pub monthly_budget: Option<f64>,
+    /// Telegram chat of the seller, it gets notified of what's going on and takes admin commands
+    #[serde(default)]
+    pub admin_chat_id: Option<i64>,
+    /// Whether Telegram updates get long-polled or pushed to a webhook, switching takes effect on restart
+    #[serde(default)]
+    pub tg_update_mode: TgUpdateMode,
+    /// Secret token of the webhook, generated the first time it gets set up
+    #[serde(default)]
+    pub webhook_secret: Option<String>,
+    /// Public URL of the node Telegram pushes webhook updates to, defaults to `hosted_url`
+    #[serde(default)]
+    pub node_public_url: Option<String>,
 }
 
+impl InitialConfig {
+    pub fn llm_settings(&self) -> LlmSettings {
+        let default = LlmSettings::default();
+        LlmSettings {
+            model: self.llm_model.clone().unwrap_or(default.model),
+            temperature: self.llm_temperature.unwrap_or(default.temperature),
+            max_tokens: self.llm_max_tokens.unwrap_or(default.max_tokens),
+            prompt_price_per_1k: self
+                .llm_prompt_price
+                .map(usd_to_micros)
+                .unwrap_or(default.prompt_price_per_1k),
+            completion_price_per_1k: self
+                .llm_completion_price
+                .map(usd_to_micros)
+                .unwrap_or(default.completion_price_per_1k),
+        }
+    }
+
+    pub fn llm_backend(&self) -> Box<dyn LlmBackend> {
+        Box::new(OpenaiCompatibleApi {
+            base_url: self
+                .llm_base_url
+                .clone()
+                .unwrap_or_else(|| OPENAI_BASE_URL.to_string()),
+            api_key: Some(self.openai_key.clone()).filter(|key| !key.is_empty()),
+        })
+    }
+
+    pub fn budget_caps(&self) -> BudgetCaps {
+        BudgetCaps {
+            daily: self.daily_budget.map(usd_to_micros),
+            monthly: self.monthly_budget.map(usd_to_micros),
+        }
+    }
+
+    pub fn memory(&self) -> MemoryConfig {
+        let default = MemoryConfig::default();
+        MemoryConfig {
+            history_capacity: self.history_capacity.unwrap_or(default.history_capacity),
+            summary_length: self.summary_length.unwrap_or(default.summary_length),
+        }
+    }
+}
+
+fn usd_to_micros(usd: f64) -> u64 {
+    (usd * 1_000_000.0).round() as u64
+}
+
+/// Marks the persisted state as versioned, states from before versioning start with the node name instead.
+const STATE_MAGIC: [u8; 8] = *b"barter\0\0";
+/// Version of the layout of the persisted state.
+/// Bump it whenever the layout changes, and migrate the states of the previous version in `State::fetch`.
+const STATE_VERSION: u32 = 1;
+
 #[derive(Debug)]
 pub struct State {
     pub our: Address,
@@ -24,9 +126,18 @@ pub struct State {
     pub context_manager: ContextManager,
     // Non-serializable fields
     pub tg_api: Api,
-    pub tg_worker: Address,
+    /// The long-polling worker, not spawned in webhook mode
+    pub tg_worker: Option<Address>,
     pub wallet: LocalWallet,
-    pub openai_api: OpenaiApi,
+    pub llm: Llm,
+    /// Offers waiting for the seller's approval
+    pub approvals: ApprovalQueue,
+    /// Telegram messages waiting to be sent
+    pub outbox: Outbox,
+    /// Replies waiting for the buy page of web chats
+    pub web_chat: WebChat,
+    /// Buyer agents on other nodes
+    pub peers: Peers,
 }
 
 impl Serialize for State {
@@ -34,7 +145,16 @@ impl Serialize for State {
     where
         S: Serializer,
     {
-        let serializable_part = (self.our.clone(), &self.config, &self.context_manager);
+        let serializable_part = (
+            self.our.clone(),
+            &self.config,
+            &self.context_manager,
+            self.tg_api.current_offset,
+            &self.approvals,
+            &self.outbox,
+            &self.web_chat,
+            &self.peers,
+        );
         serializable_part.serialize(serializer)
     }
 }
@@ -44,30 +164,169 @@ impl<'de> Deserialize<'de> for State {
     where
         D: Deserializer<'de>,
     {
-        let (our, config, context_manager) = Deserialize::deserialize(deserializer)?;
-        Ok(hydrate_state(&our, config, context_manager).expect("Failed to hydrate state"))
+        let (our, config, context_manager, tg_offset, approvals, outbox, web_chat, peers) =
+            Deserialize::deserialize(deserializer)?;
+        let mut state =
+            hydrate_state(&our, config, context_manager, tg_offset).expect("Failed to hydrate state");
+        state.approvals = approvals;
+        state.outbox = outbox;
+        state.web_chat = web_chat;
+        state.peers = peers;
+        Ok(state)
     }
 }
 
 impl State {
     pub fn new(our: &Address, config: InitialConfig) -> Self {
-        hydrate_state(our, config, ContextManager::new(&[])).expect("Failed to hydrate state")
+        hydrate_state(our, config, ContextManager::new(&[]), 0).expect("Failed to hydrate state")
     }
 
+    /// Loads the persisted state, migrating it from older layouts.
+    /// A state that can't be read anymore keeps at least the node and the credentials of its config,
+    /// so the bot comes back up instead of asking for the config again.
     pub fn fetch() -> Option<State> {
-        if let Some(state_bytes) = get_state() {
-            bincode::deserialize(&state_bytes).ok()
-        } else {
-            None
+        let state_bytes = get_state()?;
+        match bincode::deserialize::<([u8; 8], u32)>(&state_bytes) {
+            Ok((magic, STATE_VERSION)) if magic == STATE_MAGIC => {
+                match bincode::deserialize::<([u8; 8], u32, State)>(&state_bytes) {
+                    Ok((_, _, state)) => return Some(state),
+                    Err(e) => println!("failed to read the state: {:?}", e),
+                }
+            }
+            Ok((magic, version)) if magic == STATE_MAGIC => {
+                println!("state version {} is unknown", version)
+            }
+            // from before versioning, only the layout of the first release is migrated
+            _ => match bincode::deserialize::<(Address, LegacyConfig, LegacyContextManager)>(&state_bytes) {
+                Ok((our, config, context_manager)) => {
+                    println!("migrated the state of the first release");
+                    let mut state = State::new(&our, config.into());
+                    state.context_manager.nft_listings = context_manager.listings();
+                    return Some(state);
+                }
+                Err(e) => println!("failed to migrate the state: {:?}", e),
+            },
+        }
+        Self::recover(&state_bytes)
+    }
+
+    /// Reads the node and the credentials of the config, which lead the state in every layout.
+    fn recover(state_bytes: &[u8]) -> Option<State> {
+        let versioned = bincode::deserialize::<([u8; 8], u32, Address, LegacyConfig)>(state_bytes)
+            .ok()
+            .filter(|(magic, ..)| *magic == STATE_MAGIC)
+            .map(|(_, _, our, config)| (our, config));
+        let (our, config) = versioned
+            .or_else(|| bincode::deserialize::<(Address, LegacyConfig)>(state_bytes).ok())?;
+        println!("recovered the config, listings and chats are lost");
+        Some(State::new(&our, config.into()))
+    }
+
+    /// Every transport, to reply to a conversation over the one it runs over.
+    pub fn transports(&mut self) -> Transports<'_> {
+        Transports {
+            telegram: Telegram {
+                api: &self.tg_api,
+                outbox: &mut self.outbox,
+            },
+            web: &mut self.web_chat,
+            peers: &mut self.peers,
+        }
+    }
+
+    /// Sends the Telegram messages the flood limits held back.
+    pub fn flush_outbox(&mut self) {
+        self.transports().telegram.flush();
+    }
+
+    /// Pushes the messages the context manager has for the seller and other chats.
+    pub fn send_notifications(&mut self) {
+        let admin_chat = self.config.admin_chat_id.map(ConversationId::Telegram);
+        let mut transports = Transports {
+            telegram: Telegram {
+                api: &self.tg_api,
+                outbox: &mut self.outbox,
+            },
+            web: &mut self.web_chat,
+            peers: &mut self.peers,
+        };
+        self.context_manager
+            .deliver_notifications(&mut transports, admin_chat);
+    }
+
+    /// Drops the web conversations the buyer's page stopped polling, along with their chats.
+    pub fn expire_web_chats(&mut self) {
+        for id in self.web_chat.expire_idle(now()) {
+            self.context_manager.clear(ConversationId::Web(id));
         }
     }
 
     pub fn save(&self) {
-        let serialized_state = bincode::serialize(self).expect("Failed to serialize state");
+        let serialized_state =
+            bincode::serialize(&(STATE_MAGIC, STATE_VERSION, self)).expect("Failed to serialize state");
         set_state(&serialized_state);
     }
 }
 
+/// The config of the first release, the other fields of the config were added after it.
+#[derive(Deserialize)]
+struct LegacyConfig {
+    openai_key: String,
+    telegram_bot_api_key: String,
+    wallet_pk: String,
+    hosted_url: String,
+}
+
+impl From<LegacyConfig> for InitialConfig {
+    fn from(config: LegacyConfig) -> Self {
+        InitialConfig {
+            openai_key: config.openai_key,
+            telegram_bot_api_key: config.telegram_bot_api_key,
+            wallet_pk: config.wallet_pk,
+            hosted_url: config.hosted_url,
+            ..Default::default()
+        }
+    }
+}
+
+/// The listings of the first release, its chats aren't migrated.
+#[derive(Deserialize)]
+struct LegacyContextManager {
+    nft_listings: HashMap<NFTKey, LegacyListing>,
+}
+
+#[derive(Deserialize)]
+struct LegacyListing {
+    name: String,
+    min_price: U256,
+    address: String,
+    description: Option<String>,
+    custom_prompt: Option<String>,
+}
+
+impl LegacyContextManager {
+    fn listings(self) -> HashMap<NFTKey, NFTListing> {
+        self.nft_listings
+            .into_iter()
+            .map(|(key, listing)| {
+                let listing = NFTListing {
+                    name: listing.name,
+                    min_price: listing.min_price,
+                    address: listing.address,
+                    description: listing.description,
+                    custom_prompt: listing.custom_prompt,
+                    policy: NegotiationPolicy::default(),
+                    mode: SaleMode::Negotiation,
+                    template: None,
+                    image: None,
+                    approval: ApprovalPolicy::default(),
+                };
+                (key, listing)
+            })
+            .collect()
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct AddNFTArgs {
     pub nft_name: String,
@@ -77,6 +336,82 @@ pub struct AddNFTArgs {
     pub nft_description: Option<String>,
     pub sell_prompt: Option<String>,
     pub min_price: String,
+    /// Asking price to open negotiations with, in ETH
+    #[serde(default)]
+    pub opening_price: Option<String>,
+    /// Maximum drop of the asking price per counter-offer, in basis points of the opening price
+    #[serde(default)]
+    pub max_concession_bps: Option<u64>,
+    /// Number of counter-offers to make before the asking price may reach the floor
+    #[serde(default)]
+    pub min_counter_offers: Option<u32>,
+    /// Unix timestamp at which the English auction closes, the NFT gets negotiated if not set
+    #[serde(default)]
+    pub auction_ends_at: Option<u64>,
+    /// Amount a new auction bid has to exceed the highest bid by, in ETH
+    #[serde(default)]
+    pub min_increment: Option<String>,
+    /// Price a Dutch auction starts at, in ETH, the NFT gets sold by Dutch auction if set along with a decay
+    #[serde(default)]
+    pub dutch_start_price: Option<String>,
+    #[serde(default)]
+    pub dutch_decay: Option<PriceDecay>,
+    /// Unix timestamp at which the sealed-bid auction closes, the NFT gets sold by sealed bids if set
+    #[serde(default)]
+    pub sealed_ends_at: Option<u64>,
+    #[serde(default)]
+    pub sealed_pricing: Option<SealedPricing>,
+    /// Name of the system prompt template to use for this NFT
+    #[serde(default)]
+    pub template: Option<String>,
+    /// VFS path of a picture of the NFT, sent along when presenting it
+    #[serde(default)]
+    pub image: Option<String>,
+    /// When offers for this NFT need the seller's approval, they're signed right away if not set
+    #[serde(default)]
+    pub approval: Option<ApprovalPolicy>,
+}
+
+/// Changes to a listing, the fields not set stay as they are.
+/// How the NFT is sold can't be changed, it has to be removed and added again for that.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UpdateListingArgs {
+    pub nft_key: NFTKey,
+    #[serde(default)]
+    pub nft_name: Option<String>,
+    #[serde(default)]
+    pub nft_description: Option<String>,
+    #[serde(default)]
+    pub sell_prompt: Option<String>,
+    /// In ETH
+    #[serde(default)]
+    pub min_price: Option<String>,
+    /// In ETH
+    #[serde(default)]
+    pub opening_price: Option<String>,
+    #[serde(default)]
+    pub max_concession_bps: Option<u64>,
+    #[serde(default)]
+    pub min_counter_offers: Option<u32>,
+    #[serde(default)]
+    pub image: Option<String>,
+    #[serde(default)]
+    pub approval: Option<ApprovalPolicy>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AddTemplateArgs {
+    pub name: String,
+    pub persona: Option<String>,
+    pub body: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SelectTemplateArgs {
+    /// Template to select, `None` goes back to the built-in prompt
+    pub name: Option<String>,
+    /// Listing to select the template for, selects it globally if not set
+    pub nft_key: Option<NFTKey>,
 }
 
 #[derive(Clone)]
@@ -84,9 +419,72 @@ pub enum HttpRequestOutcome {
     Config(InitialConfig),
     AddNFT(AddNFTArgs),
     RemoveNFT(NFTKey),
+    AddTemplate(AddTemplateArgs),
+    RemoveTemplate(String),
+    SelectTemplate(SelectTemplateArgs),
+    Unmute(ConversationId),
+    /// An update Telegram pushed to the webhook
+    TgUpdate(Update),
+    /// Id of a pending offer the seller approved
+    ApproveOffer(u64),
+    /// Id of a pending offer the seller rejected
+    RejectOffer(u64),
+    /// An NFT got purchased on chain
+    Sold {
+        nft_key: NFTKey,
+        price: U256,
+        buyer: String,
+        log: Option<SaleLog>,
+    },
     None,
 }
 
+/// A request to `/chat` from the buy page.
+/// Without a message or button press it just picks up the replies waiting for the conversation.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WebChatRequest {
+    /// The web conversation to continue, a new one is started if not set
+    #[serde(default)]
+    pub conversation: Option<ConversationId>,
+    #[serde(default)]
+    pub text: Option<String>,
+    /// Callback data of a button the buyer pressed
+    #[serde(default)]
+    pub callback: Option<String>,
+}
+
+/// Context of a request sent without waiting, to match the response to what it's for.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum RequestContext {
+    /// A chat sent to the LLM
+    Llm(LlmContext),
+    /// A request the outbox sent to Telegram, with its id there
+    Telegram(u64),
+}
+
+/// Context of a chat sent to the LLM without waiting, to match the completion to its chat turn.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LlmContext {
+    pub chat_id: ConversationId,
+    /// The turn the chat was sent for, completions of turns that got reset or cancelled are dropped
+    pub turn_id: u64,
+}
+
+/// What became of a buyer message or Telegram update, for the logs.
+#[derive(Debug)]
+pub enum UpdateOutcome {
+    /// Replied to the chat with this id
+    Replied(ConversationId),
+    /// Sent to the LLM, the chat gets its reply when the completion arrives
+    Pending(ConversationId),
+    /// Queued behind the turn the chat has in flight
+    Queued(ConversationId),
+    /// Relayed between the buyer and the seller of a chat taken over
+    Relayed(ConversationId),
+    Skipped(String),
+    Errored(String),
+}
+
 /// Identifier for an NFT
 #[derive(Serialize, Deserialize, Debug, Clone, Hash, Eq, PartialEq)]
 pub struct NFTKey {
@@ -108,12 +506,26 @@ pub struct NFTListing {
     pub address: String,
     pub description: Option<String>,
     pub custom_prompt: Option<String>,
+    pub policy: NegotiationPolicy,
+    pub mode: SaleMode,
+    /// Name of the system prompt template, the global one is used if not set
+    pub template: Option<String>,
+    /// VFS path of a picture of the NFT
+    pub image: Option<String>,
+    /// Which offers wait for the seller's approval before getting signed
+    pub approval: ApprovalPolicy,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct NFTState {
     pub highest_bid: U256,
     pub tentative_offer: bool,
+    /// Current asking price in the negotiation, `None` until the first counter-offer
+    pub asking_price: Option<U256>,
+    /// Number of counter-offers made so far
+    pub counter_offers: u32,
+    /// Price accepted by the negotiation policy
+    pub accepted_price: Option<U256>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -137,6 +549,21 @@ pub struct FinalizedOfferCommand {
     pub nft_key: NFTKey,
     pub buyer_address: String,
     pub price: U256,
+    /// Unix timestamp until which the signed offer is valid
+    pub valid_until: u64,
+}
+
+/// Lock on an NFT while a signed offer for it is out, so it can't be signed to several buyers at once.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Reservation {
+    pub chat_id: ConversationId,
+    pub buyer_address: String,
+    /// Price of the offer, in wei
+    pub price: U256,
+    /// Unix timestamp matching the `valid_until` of the signed offer
+    pub expires_at: u64,
+    /// Whether the signed offer went out, the buyer can use it until it expires then
+    pub signed: bool,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -0,0 +1,157 @@
+use crate::context::DEFAULT_PERSONA;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Placeholders every template has to contain, without them the bot can't sell.
+const REQUIRED_PLACEHOLDERS: [&str; 3] = ["listings", "passkey", "buyer_address_needed"];
+/// Placeholders a template may contain.
+const PLACEHOLDERS: [&str; 4] = ["listings", "passkey", "buyer_address_needed", "persona"];
+
+/// Seller-editable system prompt, with `{{placeholder}}` variables.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PromptTemplate {
+    /// Fills `{{persona}}`, defaults to the built-in persona
+    pub persona: Option<String>,
+    pub body: String,
+}
+
+/// Named templates, and the one selected for all listings that don't select their own.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct TemplateStore {
+    pub templates: HashMap<String, PromptTemplate>,
+    pub global: Option<String>,
+}
+
+/// Values the placeholders of a template get filled with.
+pub struct TemplateVars<'a> {
+    pub listings: &'a str,
+    pub passkey: &'a str,
+    pub buyer_address_needed: &'a str,
+}
+
+impl PromptTemplate {
+    /// Checks that the template only uses known placeholders, and contains all required ones.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let re = regex::Regex::new(r"\{\{\s*([a-z_]+)\s*\}\}").unwrap();
+        let used: Vec<&str> = re
+            .captures_iter(&self.body)
+            .filter_map(|caps| caps.get(1))
+            .map(|placeholder| placeholder.as_str())
+            .collect();
+
+        if let Some(unknown) = used.iter().find(|name| !PLACEHOLDERS.contains(name)) {
+            return Err(anyhow::anyhow!("unknown placeholder {{{{{}}}}}", unknown));
+        }
+        let missing: Vec<&str> = REQUIRED_PLACEHOLDERS
+            .iter()
+            .filter(|name| !used.contains(name))
+            .copied()
+            .collect();
+        if !missing.is_empty() {
+            return Err(anyhow::anyhow!(
+                "missing required placeholders: {}",
+                missing.join(", ")
+            ));
+        }
+        Ok(())
+    }
+
+    /// Fills the placeholders of the template.
+    pub fn render(&self, vars: &TemplateVars) -> String {
+        let re = regex::Regex::new(r"\{\{\s*([a-z_]+)\s*\}\}").unwrap();
+        re.replace_all(&self.body, |caps: &regex::Captures| match &caps[1] {
+            "listings" => vars.listings.to_string(),
+            "passkey" => vars.passkey.to_string(),
+            "buyer_address_needed" => vars.buyer_address_needed.to_string(),
+            "persona" => self
+                .persona
+                .clone()
+                .unwrap_or_else(|| DEFAULT_PERSONA.to_string()),
+            _ => caps[0].to_string(),
+        })
+        .into_owned()
+    }
+}
+
+impl TemplateStore {
+    /// Adds or replaces a template after validating it.
+    pub fn insert(&mut self, name: String, template: PromptTemplate) -> anyhow::Result<()> {
+        template.validate()?;
+        self.templates.insert(name, template);
+        Ok(())
+    }
+
+    /// Removes a template, unselecting it globally if it was selected.
+    pub fn remove(&mut self, name: &str) {
+        self.templates.remove(name);
+        if self.global.as_deref() == Some(name) {
+            self.global = None;
+        }
+    }
+
+    /// The template to use, the one selected by the listing if any, otherwise the global one.
+    pub fn resolve(&self, listing_template: Option<&str>) -> Option<&PromptTemplate> {
+        listing_template
+            .and_then(|name| self.templates.get(name))
+            .or_else(|| self.templates.get(self.global.as_deref()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn template(body: &str) -> PromptTemplate {
+        PromptTemplate {
+            persona: None,
+            body: body.to_string(),
+        }
+    }
+
+    fn vars() -> TemplateVars<'static> {
+        TemplateVars {
+            listings: "Ape #1",
+            passkey: "make_offer",
+            buyer_address_needed: "",
+        }
+    }
+
+    #[test]
+    fn validates_placeholders() {
+        assert!(template("{{listings}} {{ passkey }} {{buyer_address_needed}}").validate().is_ok());
+        assert!(template("{{listings}} {{passkey}}").validate().is_err());
+        assert!(template("{{listings}} {{passkey}} {{buyer_address_needed}} {{min_price}}")
+            .validate()
+            .is_err());
+    }
+
+    #[test]
+    fn renders_placeholders() {
+        let rendered = template("{{persona}} Selling {{ listings }}. {{passkey}}{{buyer_address_needed}}")
+            .render(&vars());
+        assert_eq!(rendered, format!("{} Selling Ape #1. make_offer", DEFAULT_PERSONA));
+
+        let custom = PromptTemplate {
+            persona: Some("You're a pirate.".to_string()),
+            body: "{{persona}} {{listings}}".to_string(),
+        };
+        assert_eq!(custom.render(&vars()), "You're a pirate. Ape #1");
+    }
+
+    #[test]
+    fn resolves_the_listing_template_first() {
+        let mut store = TemplateStore::default();
+        let body = "{{listings}} {{passkey}} {{buyer_address_needed}}";
+        store.insert("global".to_string(), template(&format!("global {}", body))).unwrap();
+        store.insert("listing".to_string(), template(&format!("listing {}", body))).unwrap();
+        store.global = Some("global".to_string());
+
+        assert!(store.resolve(Some("listing")).unwrap().body.starts_with("listing"));
+        assert!(store.resolve(None).unwrap().body.starts_with("global"));
+        assert!(store.resolve(Some("missing")).unwrap().body.starts_with("global"));
+
+        store.remove("global");
+        assert!(store.global.is_none());
+        assert!(store.resolve(None).is_none());
+    }
+}
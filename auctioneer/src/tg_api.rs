@@ -1,15 +1,58 @@
 /// API for the bot and the parent process.
-use frankenstein::{GetUpdatesParams, TelegramApi, Update};
+use frankenstein::{
+    AnswerCallbackQueryParams, BotCommand, ChatId, DeleteWebhookParams, ErrorResponse, FileUpload, GetUpdatesParams,
+    InputFile, InputMediaPhoto, Media, MethodResponse, ParseMode, SendDocumentParams, SendMediaGroupParams,
+    SendMessageParams, SendPhotoParams, SetMyCommandsParams, SetWebhookParams, TelegramApi, Update,
+};
 use kinode_process_lib::{
-    http::{send_request, send_request_await_response, Method},
-    our_capabilities, spawn, Address, OnExit, ProcessId, Request,
+    http::{
+        bind_http_path, send_request_await_response, HttpClientAction, HttpClientError, HttpClientResponse,
+        Method, OutgoingHttpRequest,
+    },
+    get_blob, our_capabilities, println, spawn, timer, vfs, Address, OnExit, ProcessId, Request,
 };
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::{path::PathBuf, str::FromStr};
 
+use crate::structs::RequestContext;
+
 static BASE_API_URL: &str = "https://api.telegram.org/bot";
 
+/// Timeout of a request to Telegram, in seconds
+const REQUEST_TIMEOUT: u64 = 30;
+/// Timeout of an upload to Telegram, in seconds
+const UPLOAD_TIMEOUT: u64 = 60;
+
+/// Most pictures Telegram puts in one album
+const MAX_ALBUM_LEN: usize = 10;
+
+/// Header Telegram sends the webhook's secret token in.
+pub static WEBHOOK_SECRET_HEADER: &str = "X-Telegram-Bot-Api-Secret-Token";
+
+/// Seconds before setting the webhook is tried again after it failed
+const WEBHOOK_RETRY_SECS: u64 = 60;
+
+/// How the bot receives its updates.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub enum TgUpdateMode {
+    /// A spawned worker long-polls `getUpdates`
+    #[default]
+    LongPolling,
+    /// Telegram pushes updates to a secret path on our HTTP server, the node has to be reachable at `node_public_url`
+    Webhook,
+}
+
+/// A random secret token for the webhook, Telegram accepts up to 256 of `A-Z`, `a-z`, `0-9`, `_` and `-`.
+pub fn new_webhook_secret() -> String {
+    format!("{:016x}{:016x}", rand::random::<u64>(), rand::random::<u64>())
+}
+
+/// Path the webhook is bound to, the secret keeps others from guessing it.
+pub fn webhook_path(secret: &str) -> String {
+    format!("/tg/{}", secret)
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct TgInitialize {
     pub token: String,
@@ -30,11 +73,13 @@ pub struct TgUpdate {
 
 /// function to spawn and initialize a tg bot.
 /// call this from your parent process to receive updates!
+/// `commands` get registered with Telegram, so clients can suggest them.
 #[allow(unused)]
 pub fn init_tg_bot(
     our: Address,
     token: &str,
     params: Option<GetUpdatesParams>,
+    commands: Vec<BotCommand>,
 ) -> anyhow::Result<(Api, Address)> {
     let tg_bot_wasm_path = format!("{}/pkg/tg.wasm", our.package_id());
 
@@ -52,6 +97,11 @@ pub fn init_tg_bot(
     )?;
 
     let api = Api::new(token, our.clone());
+    api.register_commands(commands);
+    // getUpdates doesn't work while a webhook is set
+    if let Err(e) = api.delete_webhook(&DeleteWebhookParams::builder().build()) {
+        println!("failed to delete webhook: {:?}", e);
+    }
     let init = TgInitialize {
         token: token.to_string(),
         params,
@@ -70,11 +120,47 @@ pub fn init_tg_bot(
     Ok((api, worker_address))
 }
 
+/// Sets up the bot to receive updates by webhook instead of long-polling.
+/// Binds the secret path on our HTTP server and points Telegram to it at `base_url`,
+/// under our process like every path we bind. If Telegram can't be reached, that's retried by timer.
+pub fn init_tg_webhook(
+    our: Address,
+    token: &str,
+    base_url: &str,
+    secret: &str,
+    commands: Vec<BotCommand>,
+) -> anyhow::Result<Api> {
+    let path = webhook_path(secret);
+    // Telegram can't log in, the secret token guards the path instead
+    bind_http_path(path.clone(), false, false)?;
+
+    let mut api = Api::new(token, our);
+    api.register_commands(commands);
+    api.webhook_secret = Some(secret.to_string());
+    api.pending_webhook = Some(format!(
+        "{}/{}{}",
+        base_url.trim_end_matches('/'),
+        api.our.process,
+        path
+    ));
+    api.set_pending_webhook(0);
+
+    Ok(api)
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Api {
     pub api_url: String,
     pub our: Address,
     pub current_offset: u32,
+    /// Secret token of the webhook, if updates come in by webhook
+    pub webhook_secret: Option<String>,
+    /// URL of the webhook while Telegram hasn't been pointed to it yet
+    #[serde(skip)]
+    pending_webhook: Option<String>,
+    /// Setting the webhook isn't tried again before this unix timestamp
+    #[serde(skip)]
+    webhook_retry_at: u64,
 }
 
 impl Api {
@@ -85,6 +171,54 @@ impl Api {
             api_url,
             our,
             current_offset: 0,
+            webhook_secret: None,
+            pending_webhook: None,
+            webhook_retry_at: 0,
+        }
+    }
+
+    /// Registers the commands with Telegram, so clients can suggest them.
+    fn register_commands(&self, commands: Vec<BotCommand>) {
+        if commands.is_empty() {
+            return;
+        }
+        let set_commands = SetMyCommandsParams::builder().commands(commands).build();
+        if let Err(e) = self.set_my_commands(&set_commands) {
+            println!("failed to register bot commands: {:?}", e);
+        }
+    }
+
+    /// Points Telegram to the webhook if that's still pending and due.
+    /// A failure is logged and tried again by timer, the bot keeps running without updates until then.
+    pub fn set_pending_webhook(&mut self, now: u64) {
+        let Some(url) = self.pending_webhook.clone() else {
+            return;
+        };
+        if now < self.webhook_retry_at {
+            return;
+        }
+        let params = SetWebhookParams::builder()
+            .url(url)
+            .secret_token(self.webhook_secret.clone().unwrap_or_default())
+            // one update at a time, so they arrive in order and none get handled twice
+            .max_connections(1u32)
+            .build();
+        match self.set_webhook(&params) {
+            Ok(_) => self.pending_webhook = None,
+            Err(e) => {
+                println!("failed to set webhook, retrying in {}s: {:?}", WEBHOOK_RETRY_SECS, e);
+                self.webhook_retry_at = now + WEBHOOK_RETRY_SECS;
+                timer::set_timer(WEBHOOK_RETRY_SECS * 1000, None);
+            }
+        }
+    }
+
+    /// Whether a webhook request carries our secret token.
+    /// Compared in constant time, so the secret can't be guessed byte by byte from response times.
+    pub fn webhook_authorized(&self, secret_token: Option<&str>) -> bool {
+        match (&self.webhook_secret, secret_token) {
+            (Some(secret), Some(token)) => constant_time_eq(secret.as_bytes(), token.as_bytes()),
+            _ => false,
         }
     }
 }
@@ -109,46 +243,899 @@ impl TelegramApi for Api {
         } else {
             Vec::new()
         };
-        let res = send_request_await_response(Method::GET, url, Some(headers), 30, body)?;
-
-        let deserialized: T2 = serde_json::from_slice(&res.body())
-            .map_err(|e| anyhow::anyhow!("Failed to deserialize response body: {}", e))?;
+        let res = send_request_await_response(Method::GET, url, Some(headers), REQUEST_TIMEOUT, body)?;
 
-        Ok(deserialized)
+        parse_response(res.body())
     }
 
+    /// Uploads files as multipart/form-data, the paths of the files are VFS paths.
     fn request_with_form_data<T1: serde::ser::Serialize, T2: serde::de::DeserializeOwned>(
         &self,
-        _method: &str,
-        _params: T1,
-        _files: Vec<(&str, PathBuf)>,
+        method: &str,
+        params: T1,
+        files: Vec<(&str, PathBuf)>,
     ) -> Result<T2, anyhow::Error> {
-        return Err(anyhow::anyhow!(
-            "tgbot doesn't support multipart uploads (yet!)"
-        ));
+        let url = format!("{}/{method}", self.api_url);
+        let url = url::Url::from_str(&url)?;
+
+        let form = form_data(method, &params, files)?;
+        let headers: HashMap<String, String> =
+            HashMap::from_iter([("Content-Type".into(), form.content_type())]);
+        let res = send_request_await_response(Method::POST, url, Some(headers), UPLOAD_TIMEOUT, form.finish())?;
+
+        parse_response(res.body())
     }
 }
 
 impl Api {
-    #[allow(unused)]
-    pub fn request_no_wait<T1: serde::ser::Serialize>(
+    /// Sends a request to Telegram without waiting, its answer arrives as a response carrying `context`.
+    fn send_no_wait(
         &self,
         method: &str,
-        params: Option<T1>,
-    ) -> Result<(), anyhow::Error> {
-        let url = format!("{}/{method}", self.api_url);
-        let url = url::Url::from_str(&url)?;
+        content_type: String,
+        body: Vec<u8>,
+        timeout: u64,
+        context: &[u8],
+    ) -> anyhow::Result<()> {
+        let url = url::Url::from_str(&format!("{}/{method}", self.api_url))?;
+        let action = HttpClientAction::Http(OutgoingHttpRequest {
+            method: Method::POST.to_string(),
+            version: None,
+            url: url.to_string(),
+            headers: HashMap::from_iter([("Content-Type".into(), content_type)]),
+        });
+        Request::to(("our", "http_client", "distro", "sys"))
+            .body(serde_json::to_vec(&action)?)
+            .blob_bytes(body)
+            .context(context.to_vec())
+            .expects_response(timeout)
+            .send()?;
+        Ok(())
+    }
 
-        // content-type application/json
-        let headers: HashMap<String, String> =
-            HashMap::from_iter([("Content-Type".into(), "application/json".into())]);
+    /// Sends params already serialized to JSON without waiting.
+    fn send_json_no_wait(&self, method: &str, params: &[u8], context: &[u8]) -> anyhow::Result<()> {
+        self.send_no_wait(
+            method,
+            "application/json".into(),
+            params.to_vec(),
+            REQUEST_TIMEOUT,
+            context,
+        )
+    }
 
-        let body = if let Some(ref params) = params {
-            serde_json::to_vec(params)?
+    /// Uploads files held in VFS along with the params without waiting.
+    fn send_form_no_wait<T: serde::ser::Serialize>(
+        &self,
+        method: &str,
+        params: &T,
+        files: Vec<(&str, PathBuf)>,
+        context: &[u8],
+    ) -> anyhow::Result<()> {
+        let form = form_data(method, params, files)?;
+        self.send_no_wait(method, form.content_type(), form.finish(), UPLOAD_TIMEOUT, context)
+    }
+}
+
+/// Whether Telegram took a request sent without waiting, going by the http_client's response to it.
+/// Telegram's errors are turned into a `TgApiError`, so they can be told apart for retrying.
+pub fn response_result(response: &[u8]) -> anyhow::Result<()> {
+    let response: Result<HttpClientResponse, HttpClientError> = serde_json::from_slice(response)?;
+    match response {
+        Ok(HttpClientResponse::Http(_)) => {}
+        Ok(response) => return Err(anyhow::anyhow!("unexpected Telegram response: {:?}", response)),
+        Err(e) => return Err(anyhow::anyhow!("Telegram request failed: {:?}", e)),
+    }
+    let body = get_blob().map(|blob| blob.bytes).unwrap_or_default();
+    parse_response::<MethodResponse<serde_json::Value>>(&body)?;
+    Ok(())
+}
+
+/// The form of an upload, the files are VFS paths that replace the params' fields of the same name.
+fn form_data<T: serde::ser::Serialize>(
+    method: &str,
+    params: &T,
+    files: Vec<(&str, PathBuf)>,
+) -> anyhow::Result<MultipartForm> {
+    let mut form = MultipartForm::new();
+    let serde_json::Value::Object(fields) = serde_json::to_value(params)? else {
+        return Err(anyhow::anyhow!("params of {} aren't an object", method));
+    };
+    for (name, value) in fields {
+        // file fields get replaced by the file itself
+        if files.iter().any(|(file_name, _)| *file_name == name) {
+            continue;
+        }
+        match value {
+            serde_json::Value::Null => {}
+            serde_json::Value::String(text) => form.add_text(&name, &text),
+            // nested objects like reply markups and media arrays are sent as JSON
+            value => form.add_text(&name, &value.to_string()),
+        }
+    }
+    for (name, path) in files {
+        let path = path.to_string_lossy();
+        let bytes = vfs::open_file(&path, false, None)?.read()?;
+        let file_name = path.rsplit('/').next().unwrap_or(name);
+        form.add_file(name, file_name, &bytes);
+    }
+    Ok(form)
+}
+
+/// Whether the bytes are equal, taking as long for every input of the same length.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+fn vfs_upload(path: &str) -> FileUpload {
+    FileUpload::InputFile(InputFile {
+        path: PathBuf::from(path),
+    })
+}
+
+/// Whether Telegram shows the file as a photo, going by its extension.
+fn is_photo(path: &str) -> bool {
+    matches!(content_type(path), "image/jpeg" | "image/png" | "image/webp")
+}
+
+/// The MIME type of a file, going by its extension.
+fn content_type(file_name: &str) -> &'static str {
+    let extension = file_name
+        .rsplit_once('.')
+        .map(|(_, extension)| extension.to_lowercase())
+        .unwrap_or_default();
+    match extension.as_str() {
+        "jpg" | "jpeg" => "image/jpeg",
+        "png" => "image/png",
+        "webp" => "image/webp",
+        "gif" => "image/gif",
+        "svg" => "image/svg+xml",
+        "mp4" => "video/mp4",
+        "pdf" => "application/pdf",
+        "json" => "application/json",
+        _ => "application/octet-stream",
+    }
+}
+
+/// An error Telegram answered a request with.
+#[derive(Debug)]
+pub struct TgApiError(pub ErrorResponse);
+
+impl std::fmt::Display for TgApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "telegram error {}: {}", self.0.error_code, self.0.description)
+    }
+}
+
+impl std::error::Error for TgApiError {}
+
+/// Deserializes a response, or the error Telegram answered with instead.
+fn parse_response<T: serde::de::DeserializeOwned>(body: &[u8]) -> anyhow::Result<T> {
+    serde_json::from_slice(body).map_err(|e| match serde_json::from_slice::<ErrorResponse>(body) {
+        Ok(error) => TgApiError(error).into(),
+        Err(_) => anyhow::anyhow!("Failed to deserialize response body: {}", e),
+    })
+}
+
+/// Messages sent per second across all chats, Telegram allows about 30.
+const GLOBAL_RATE: f64 = 25.0;
+const GLOBAL_BURST: f64 = 25.0;
+/// Messages sent per second to a single chat, Telegram allows about 1 with short bursts.
+const CHAT_RATE: f64 = 1.0;
+const CHAT_BURST: f64 = 3.0;
+/// Attempts at sending a message before it gets dropped
+const MAX_ATTEMPTS: u32 = 5;
+/// Delay before the first retry in milliseconds, doubled with every attempt
+const BACKOFF_MS: u64 = 1_000;
+const MAX_BACKOFF_MS: u64 = 60_000;
+/// Requests to Telegram awaiting their answer at once, more wait for one of them to be answered
+const MAX_IN_FLIGHT: usize = 10;
+
+/// Current unix timestamp in milliseconds.
+fn now_millis() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_millis() as u64
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct TokenBucket {
+    tokens: f64,
+    updated_at: u64,
+}
+
+impl TokenBucket {
+    fn full(burst: f64, now: u64) -> Self {
+        Self {
+            tokens: burst,
+            updated_at: now,
+        }
+    }
+
+    fn refill(&mut self, rate: f64, burst: f64, now: u64) {
+        let elapsed = now.saturating_sub(self.updated_at) as f64 / 1000.0;
+        self.tokens = (self.tokens + elapsed * rate).min(burst);
+        self.updated_at = now;
+    }
+
+    /// Milliseconds until a token is available, 0 if one is.
+    fn wait(&self, rate: f64) -> u64 {
+        if self.tokens >= 1.0 {
+            0
         } else {
-            Vec::new()
+            ((1.0 - self.tokens) / rate * 1000.0).ceil() as u64
+        }
+    }
+}
+
+/// A request to Telegram waiting to be sent.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+enum Call {
+    /// The `SendMessageParams` as JSON, bincode can't handle the fields frankenstein skips when empty
+    Message(Vec<u8>),
+    /// A picture held in VFS with an HTML caption, sent as a document if it isn't a photo
+    Photo { path: String, caption: Option<String> },
+    /// Photos held in VFS, at most `MAX_ALBUM_LEN` of them
+    Album(Vec<String>),
+    /// The `AnswerCallbackQueryParams` as JSON
+    CallbackAnswer(Vec<u8>),
+}
+
+impl Call {
+    /// The Bot API method the call goes to.
+    fn method(&self) -> &'static str {
+        match self {
+            Call::Message(_) => "sendMessage",
+            Call::Photo { path, .. } if is_photo(path) => "sendPhoto",
+            Call::Photo { .. } => "sendDocument",
+            Call::Album(_) => "sendMediaGroup",
+            Call::CallbackAnswer(_) => "answerCallbackQuery",
+        }
+    }
+
+    /// Sends the call without waiting, Telegram's answer arrives as a response carrying `context`.
+    /// Files are read from VFS right away.
+    fn dispatch(&self, api: &Api, chat_id: Option<i64>, context: &[u8]) -> anyhow::Result<()> {
+        let chat = || {
+            chat_id
+                .map(ChatId::Integer)
+                .ok_or_else(|| anyhow::anyhow!("{} without a chat", self.method()))
+        };
+        match self {
+            Call::Message(params) | Call::CallbackAnswer(params) => {
+                api.send_json_no_wait(self.method(), params, context)
+            }
+            Call::Photo { path, caption } => {
+                let parse_mode = caption.as_ref().map(|_| ParseMode::Html);
+                if is_photo(path) {
+                    let mut params = SendPhotoParams::builder()
+                        .chat_id(chat()?)
+                        .photo(vfs_upload(path))
+                        .build();
+                    params.caption = caption.clone();
+                    params.parse_mode = parse_mode;
+                    let files = vec![("photo", PathBuf::from(path))];
+                    api.send_form_no_wait(self.method(), &params, files, context)
+                } else {
+                    let mut params = SendDocumentParams::builder()
+                        .chat_id(chat()?)
+                        .document(vfs_upload(path))
+                        .build();
+                    params.caption = caption.clone();
+                    params.parse_mode = parse_mode;
+                    let files = vec![("document", PathBuf::from(path))];
+                    api.send_form_no_wait(self.method(), &params, files, context)
+                }
+            }
+            Call::Album(paths) => {
+                // each photo is attached under a field of its own
+                let names: Vec<String> = (0..paths.len()).map(|index| format!("photo{}", index)).collect();
+                let media = names
+                    .iter()
+                    .map(|name| {
+                        let attachment = FileUpload::String(format!("attach://{}", name));
+                        Media::Photo(InputMediaPhoto::builder().media(attachment).build())
+                    })
+                    .collect();
+                let params = SendMediaGroupParams::builder()
+                    .chat_id(chat()?)
+                    .media(media)
+                    .build();
+                let files = names
+                    .iter()
+                    .zip(paths)
+                    .map(|(name, path)| (name.as_str(), PathBuf::from(path)))
+                    .collect();
+                api.send_form_no_wait(self.method(), &params, files, context)
+            }
+        }
+    }
+}
+
+/// A request waiting to be sent.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Outgoing {
+    /// The chat the request shows up in, answers to button presses don't show up in any.
+    /// Those skip the chat's queue and its limit.
+    chat_id: Option<i64>,
+    call: Call,
+    attempts: u32,
+    /// Not sent before this unix timestamp in milliseconds, for backing off
+    not_before: u64,
+}
+
+impl Outgoing {
+    fn new(chat_id: Option<i64>, call: Call) -> Self {
+        Self {
+            chat_id,
+            call,
+            attempts: 0,
+            not_before: 0,
+        }
+    }
+}
+
+/// A message that was dropped after failing to send.
+#[derive(Debug, Clone)]
+pub struct Undelivered {
+    pub chat_id: i64,
+    pub error: String,
+}
+
+/// How a failed send gets dealt with.
+enum Failure {
+    /// Telegram asked us to wait this many seconds
+    RetryAfter(u64),
+    /// Worth another attempt, like timeouts and server errors
+    Transient,
+    /// Retrying won't help, like a chat that blocked the bot
+    Permanent,
+}
+
+impl Failure {
+    fn of(error: &anyhow::Error) -> Failure {
+        let Some(TgApiError(response)) = error.downcast_ref::<TgApiError>() else {
+            return Failure::Transient;
+        };
+        let retry_after = response
+            .parameters
+            .as_ref()
+            .and_then(|parameters| parameters.retry_after);
+        match (response.error_code, retry_after) {
+            (429, Some(seconds)) => Failure::RetryAfter(seconds as u64),
+            (429, None) | (500..=599, _) => Failure::Transient,
+            _ => Failure::Permanent,
+        }
+    }
+}
+
+/// Outbound messages, sent as fast as Telegram's flood limits allow.
+/// Sending is rate-limited by a global token bucket and one per chat.
+/// Requests are never waited on, a chat has one in flight at a time so its messages keep their order.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Outbox {
+    queue: VecDeque<Outgoing>,
+    /// Requests sent and awaiting Telegram's answer, by the id their response carries
+    in_flight: HashMap<u64, Outgoing>,
+    global: Option<TokenBucket>,
+    per_chat: HashMap<i64, TokenBucket>,
+    /// Nothing is sent before this unix timestamp in milliseconds, after Telegram answered with a 429
+    paused_until: u64,
+    /// Id of the last request sent
+    #[serde(skip)]
+    last_id: u64,
+    /// When the timer to flush again fires, timers don't survive restarts
+    #[serde(skip)]
+    wake_at: Option<u64>,
+    /// Messages dropped since the last time they were taken, for the seller to hear about
+    #[serde(skip)]
+    undelivered: Vec<Undelivered>,
+}
+
+impl Outbox {
+    /// Queues a message, only chats with a numeric id get their own flood limit, so others are refused.
+    pub fn push(&mut self, params: &SendMessageParams) -> anyhow::Result<()> {
+        let chat_id = match &params.chat_id {
+            ChatId::Integer(chat_id) => *chat_id,
+            ChatId::String(username) => {
+                return Err(anyhow::anyhow!("can't queue a message to {}, only to chat ids", username))
+            }
         };
-        send_request(Method::GET, url, Some(headers), Some(20), body);
+        self.queue.push_back(Outgoing::new(
+            Some(chat_id),
+            Call::Message(serde_json::to_vec(params)?),
+        ));
+        Ok(())
+    }
+
+    /// Queues a picture held in VFS, the caption is HTML.
+    pub fn push_photo(&mut self, chat_id: i64, path: &str, caption: Option<String>) {
+        let call = Call::Photo {
+            path: path.to_string(),
+            caption,
+        };
+        self.queue.push_back(Outgoing::new(Some(chat_id), call));
+    }
+
+    /// Queues pictures held in VFS as albums, or one by one if some aren't photos.
+    pub fn push_album(&mut self, chat_id: i64, paths: &[String]) {
+        if paths.len() < 2 || !paths.iter().all(|path| is_photo(path)) {
+            for path in paths {
+                self.push_photo(chat_id, path, None);
+            }
+            return;
+        }
+        for album in paths.chunks(MAX_ALBUM_LEN) {
+            self.queue
+                .push_back(Outgoing::new(Some(chat_id), Call::Album(album.to_vec())));
+        }
+    }
+
+    /// Queues the answer to a button press ahead of the messages, it stops the button's loading animation.
+    pub fn push_callback_answer(&mut self, params: &AnswerCallbackQueryParams) -> anyhow::Result<()> {
+        self.queue
+            .push_front(Outgoing::new(None, Call::CallbackAnswer(serde_json::to_vec(params)?)));
         Ok(())
     }
+
+    /// Takes the messages dropped since the last call.
+    pub fn take_undelivered(&mut self) -> Vec<Undelivered> {
+        std::mem::take(&mut self.undelivered)
+    }
+
+    /// Queues the requests that were in flight again, their answers got lost with a restart.
+    pub fn requeue_in_flight(&mut self) {
+        let mut in_flight: Vec<(u64, Outgoing)> = self.in_flight.drain().collect();
+        in_flight.sort_by_key(|(id, _)| *id);
+        for (_, outgoing) in in_flight.into_iter().rev() {
+            self.queue.push_front(outgoing);
+        }
+    }
+
+    /// Sends what the rate limits allow right now, without waiting for Telegram's answers.
+    /// Those arrive as responses carrying the request's id in a `RequestContext::Telegram`, to be passed to `complete`.
+    /// Returns in how many milliseconds to flush again, if a timer for that isn't already set.
+    pub fn flush(&mut self, api: &Api) -> Option<u64> {
+        self.flush_with(now_millis(), |id, outgoing| {
+            let context = serde_json::to_vec(&RequestContext::Telegram(id))?;
+            outgoing.call.dispatch(api, outgoing.chat_id, &context)
+        })
+    }
+
+    /// Releases the request Telegram answered, or queues it again if it failed and is worth another attempt.
+    /// Flush afterwards, the chat's next message waited for the answer.
+    pub fn complete(&mut self, id: u64, result: anyhow::Result<()>) {
+        self.complete_with(now_millis(), id, result)
+    }
+
+    fn complete_with(&mut self, now: u64, id: u64, result: anyhow::Result<()>) {
+        let Some(outgoing) = self.in_flight.remove(&id) else {
+            return;
+        };
+        let Err(e) = result else {
+            return;
+        };
+        // ahead of the chat's later messages, which waited for it
+        if let Some(outgoing) = self.failed(outgoing, e, now) {
+            self.queue.push_front(outgoing);
+        }
+    }
+
+    /// Counts the failed attempt at a request, returning the request if it's worth another one.
+    fn failed(&mut self, mut outgoing: Outgoing, error: anyhow::Error, now: u64) -> Option<Outgoing> {
+        outgoing.attempts += 1;
+        let method = outgoing.call.method();
+        match Failure::of(&error) {
+            Failure::RetryAfter(seconds) => {
+                println!("flood limit hit, pausing messages for {}s", seconds);
+                self.paused_until = self.paused_until.max(now + seconds * 1000);
+                Some(outgoing)
+            }
+            Failure::Transient if outgoing.attempts < MAX_ATTEMPTS => {
+                let backoff = (BACKOFF_MS << (outgoing.attempts - 1)).min(MAX_BACKOFF_MS);
+                println!("{} failed, retrying in {}ms: {:?}", method, backoff, error);
+                outgoing.not_before = now + backoff;
+                Some(outgoing)
+            }
+            _ => {
+                println!("dropping {}: {:?}", method, error);
+                // a button that keeps loading isn't worth telling the seller about
+                if let Some(chat_id) = outgoing.chat_id {
+                    self.undelivered.push(Undelivered {
+                        chat_id,
+                        error: error.to_string(),
+                    });
+                }
+                None
+            }
+        }
+    }
+
+    fn flush_with(
+        &mut self,
+        now: u64,
+        mut dispatch: impl FnMut(u64, &Outgoing) -> anyhow::Result<()>,
+    ) -> Option<u64> {
+        let mut global = self
+            .global
+            .take()
+            .unwrap_or_else(|| TokenBucket::full(GLOBAL_BURST, now));
+        global.refill(GLOBAL_RATE, GLOBAL_BURST, now);
+        // chats with a request in flight wait for its answer, which flushes again
+        let mut busy_chats: HashSet<i64> = self
+            .in_flight
+            .values()
+            .filter_map(|outgoing| outgoing.chat_id)
+            .collect();
+
+        let mut waiting: VecDeque<Outgoing> = VecDeque::new();
+        let mut next_attempt = u64::MAX;
+        while let Some(outgoing) = self.queue.pop_front() {
+            let chat_wait = match outgoing.chat_id {
+                // an earlier message to the chat that's in flight or still waiting goes first
+                Some(chat_id)
+                    if busy_chats.contains(&chat_id)
+                        || waiting.iter().any(|earlier| earlier.chat_id == Some(chat_id)) =>
+                {
+                    waiting.push_back(outgoing);
+                    continue;
+                }
+                Some(chat_id) => {
+                    let chat = self
+                        .per_chat
+                        .entry(chat_id)
+                        .or_insert_with(|| TokenBucket::full(CHAT_BURST, now));
+                    chat.refill(CHAT_RATE, CHAT_BURST, now);
+                    chat.wait(CHAT_RATE)
+                }
+                None => 0,
+            };
+
+            let ready_at = [
+                self.paused_until,
+                outgoing.not_before,
+                now + global.wait(GLOBAL_RATE),
+                now + chat_wait,
+            ]
+            .into_iter()
+            .max()
+            .unwrap_or(now);
+            if ready_at > now {
+                next_attempt = next_attempt.min(ready_at);
+                waiting.push_back(outgoing);
+                continue;
+            }
+            // enough requests are out, the next answer sends more
+            if self.in_flight.len() >= MAX_IN_FLIGHT {
+                waiting.push_back(outgoing);
+                continue;
+            }
+
+            global.tokens -= 1.0;
+            if let Some(chat) = outgoing.chat_id.and_then(|chat_id| self.per_chat.get_mut(&chat_id)) {
+                chat.tokens -= 1.0;
+            }
+            self.last_id += 1;
+            let id = self.last_id;
+            match dispatch(id, &outgoing) {
+                Ok(()) => {
+                    busy_chats.extend(outgoing.chat_id);
+                    self.in_flight.insert(id, outgoing);
+                }
+                Err(e) => {
+                    if let Some(outgoing) = self.failed(outgoing, e, now) {
+                        next_attempt = next_attempt.min(outgoing.not_before.max(self.paused_until));
+                        waiting.push_back(outgoing);
+                    }
+                }
+            }
+        }
+        self.queue = waiting;
+        self.global = Some(global);
+        // buckets left alone for this long are full again anyway
+        let refill_ms = (CHAT_BURST / CHAT_RATE * 1000.0) as u64;
+        self.per_chat
+            .retain(|_, bucket| bucket.updated_at + refill_ms > now);
+
+        // whatever else waits, waits for an answer
+        if self.queue.is_empty() || next_attempt == u64::MAX {
+            return None;
+        }
+        let timer_set = self
+            .wake_at
+            .map(|wake_at| wake_at > now && wake_at <= next_attempt)
+            .unwrap_or_default();
+        if timer_set {
+            return None;
+        }
+        self.wake_at = Some(next_attempt);
+        Some(next_attempt.saturating_sub(now))
+    }
+}
+
+/// Body of a multipart/form-data request.
+struct MultipartForm {
+    boundary: String,
+    body: Vec<u8>,
+}
+
+impl MultipartForm {
+    fn new() -> Self {
+        Self {
+            boundary: format!("----barter{:016x}", rand::random::<u64>()),
+            body: Vec::new(),
+        }
+    }
+
+    fn content_type(&self) -> String {
+        format!("multipart/form-data; boundary={}", self.boundary)
+    }
+
+    fn add_text(&mut self, name: &str, value: &str) {
+        self.body.extend_from_slice(
+            format!(
+                "--{}\r\nContent-Disposition: form-data; name=\"{}\"\r\n\r\n{}\r\n",
+                self.boundary,
+                escape_disposition(name),
+                value
+            )
+            .as_bytes(),
+        );
+    }
+
+    fn add_file(&mut self, name: &str, file_name: &str, bytes: &[u8]) {
+        self.body.extend_from_slice(
+            format!(
+                "--{}\r\nContent-Disposition: form-data; name=\"{}\"; filename=\"{}\"\r\nContent-Type: {}\r\n\r\n",
+                self.boundary,
+                escape_disposition(name),
+                escape_disposition(file_name),
+                content_type(file_name)
+            )
+            .as_bytes(),
+        );
+        self.body.extend_from_slice(bytes);
+        self.body.extend_from_slice(b"\r\n");
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        self.body
+            .extend_from_slice(format!("--{}--\r\n", self.boundary).as_bytes());
+        self.body
+    }
+}
+
+/// Escapes a name in a Content-Disposition header the way browsers do, so it can't break out of its quotes.
+fn escape_disposition(name: &str) -> String {
+    name.replace('"', "%22")
+        .replace('\r', "%0D")
+        .replace('\n', "%0A")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn guesses_content_types() {
+        assert_eq!(content_type("/auctioneer:barter:sys/ape.PNG"), "image/png");
+        assert_eq!(content_type("ape.jpeg"), "image/jpeg");
+        assert_eq!(content_type("ape.svg"), "image/svg+xml");
+        assert_eq!(content_type("ape"), "application/octet-stream");
+        assert!(is_photo("ape.webp"));
+        assert!(!is_photo("ape.gif"));
+    }
+
+    #[test]
+    fn authorizes_webhooks_by_secret() {
+        let mut api = Api::new("token", Address::from_str("our@main:barter:appattacc.os").unwrap());
+        assert!(!api.webhook_authorized(Some("secret")));
+        api.webhook_secret = Some("secret".to_string());
+        assert!(api.webhook_authorized(Some("secret")));
+        assert!(!api.webhook_authorized(Some("secreT")));
+        assert!(!api.webhook_authorized(Some("secret2")));
+        assert!(!api.webhook_authorized(None));
+    }
+
+    #[test]
+    fn builds_multipart_forms() {
+        let mut form = MultipartForm::new();
+        let boundary = form.boundary.clone();
+        form.add_text("chat_id", "42");
+        form.add_file("photo", "ape.png", b"png bytes");
+        assert_eq!(form.content_type(), format!("multipart/form-data; boundary={}", boundary));
+        let body = String::from_utf8(form.finish()).unwrap();
+        assert_eq!(
+            body,
+            format!(
+                "--{b}\r\nContent-Disposition: form-data; name=\"chat_id\"\r\n\r\n42\r\n\
+                 --{b}\r\nContent-Disposition: form-data; name=\"photo\"; filename=\"ape.png\"\r\n\
+                 Content-Type: image/png\r\n\r\npng bytes\r\n\
+                 --{b}--\r\n",
+                b = boundary
+            )
+        );
+    }
+
+    #[test]
+    fn escapes_field_names() {
+        let mut form = MultipartForm::new();
+        form.add_text("a\"b\r\nc", "value");
+        form.add_file("file", "ev\"il.png", b"");
+        let body = String::from_utf8(form.finish()).unwrap();
+        assert!(body.contains("name=\"a%22b%0D%0Ac\""));
+        assert!(body.contains("filename=\"ev%22il.png\""));
+    }
+
+    fn message(chat_id: i64, text: &str) -> SendMessageParams {
+        SendMessageParams::builder()
+            .chat_id(ChatId::Integer(chat_id))
+            .text(text)
+            .build()
+    }
+
+    fn tg_error(json: &str) -> anyhow::Error {
+        TgApiError(serde_json::from_str(json).unwrap()).into()
+    }
+
+    #[test]
+    fn refills_token_buckets_up_to_their_burst() {
+        let mut bucket = TokenBucket::full(3.0, 0);
+        assert_eq!(bucket.wait(1.0), 0);
+        bucket.tokens = 0.0;
+        assert_eq!(bucket.wait(1.0), 1_000);
+        bucket.refill(1.0, 3.0, 500);
+        assert_eq!(bucket.wait(1.0), 500);
+        bucket.refill(1.0, 3.0, 60_000);
+        assert_eq!(bucket.tokens, 3.0);
+    }
+
+    /// Flushes, recording the id and chat of every request dispatched.
+    fn flush_at(outbox: &mut Outbox, now: u64) -> (Vec<(u64, Option<i64>)>, Option<u64>) {
+        let mut sent = Vec::new();
+        let delay = outbox.flush_with(now, |id, outgoing| {
+            sent.push((id, outgoing.chat_id));
+            Ok(())
+        });
+        (sent, delay)
+    }
+
+    #[test]
+    fn refuses_chats_without_an_id() {
+        let mut outbox = Outbox::default();
+        let params = SendMessageParams::builder()
+            .chat_id(ChatId::String("@channel".to_string()))
+            .text("hi")
+            .build();
+        assert!(outbox.push(&params).is_err());
+        assert!(outbox.queue.is_empty());
+    }
+
+    #[test]
+    fn sends_a_chat_one_request_at_a_time() {
+        let mut outbox = Outbox::default();
+        for text in ["1", "2"] {
+            outbox.push(&message(7, text)).unwrap();
+        }
+        outbox.push(&message(8, "other chat")).unwrap();
+        let (sent, delay) = flush_at(&mut outbox, 1_000);
+        assert_eq!(sent, vec![(1, Some(7)), (2, Some(8))]);
+        // the second message waits for the answer to the first, not for a timer
+        assert_eq!(delay, None);
+        assert!(flush_at(&mut outbox, 1_000).0.is_empty());
+
+        // answers to button presses don't wait behind the chat
+        let answer = AnswerCallbackQueryParams::builder()
+            .callback_query_id("query")
+            .build();
+        outbox.push_callback_answer(&answer).unwrap();
+        assert_eq!(flush_at(&mut outbox, 1_000).0, vec![(3, None)]);
+
+        outbox.complete_with(1_000, 1, Ok(()));
+        assert_eq!(flush_at(&mut outbox, 1_000).0, vec![(4, Some(7))]);
+        assert!(outbox.queue.is_empty());
+    }
+
+    #[test]
+    fn sends_a_chat_its_burst_then_waits() {
+        let mut outbox = Outbox::default();
+        for text in ["1", "2", "3", "4"] {
+            outbox.push(&message(7, text)).unwrap();
+        }
+        for id in 1..=3 {
+            let (sent, _) = flush_at(&mut outbox, 1_000);
+            assert_eq!(sent, vec![(id, Some(7))]);
+            outbox.complete_with(1_000, id, Ok(()));
+        }
+        let (sent, delay) = flush_at(&mut outbox, 1_000);
+        assert!(sent.is_empty());
+        assert_eq!(delay, Some(1_000));
+        // the timer is already set
+        assert_eq!(flush_at(&mut outbox, 1_000).1, None);
+
+        let (sent, delay) = flush_at(&mut outbox, 2_000);
+        assert_eq!(sent, vec![(4, Some(7))]);
+        assert_eq!(delay, None);
+    }
+
+    #[test]
+    fn limits_requests_in_flight() {
+        let mut outbox = Outbox::default();
+        for chat_id in 0..MAX_IN_FLIGHT as i64 + 2 {
+            outbox.push(&message(chat_id, "hi")).unwrap();
+        }
+        let (sent, delay) = flush_at(&mut outbox, 1_000);
+        assert_eq!(sent.len(), MAX_IN_FLIGHT);
+        assert_eq!(delay, None);
+        assert_eq!(outbox.queue.len(), 2);
+
+        outbox.complete_with(1_000, 1, Ok(()));
+        assert_eq!(flush_at(&mut outbox, 1_000).0.len(), 1);
+        assert_eq!(outbox.queue.len(), 1);
+    }
+
+    #[test]
+    fn requeues_requests_in_flight() {
+        let mut outbox = Outbox::default();
+        for text in ["1", "2"] {
+            outbox.push(&message(7, text)).unwrap();
+        }
+        flush_at(&mut outbox, 1_000);
+        outbox.requeue_in_flight();
+        assert!(outbox.in_flight.is_empty());
+        assert_eq!(outbox.queue.len(), 2);
+        assert_eq!(flush_at(&mut outbox, 1_000).0, vec![(2, Some(7))]);
+        // answers to requests from before aren't mistaken for new ones
+        outbox.complete_with(1_000, 1, Ok(()));
+        assert_eq!(outbox.in_flight.len(), 1);
+    }
+
+    #[test]
+    fn retries_and_reports_failures() {
+        let mut outbox = Outbox::default();
+        outbox.push(&message(1, "flooded")).unwrap();
+        let (sent, _) = flush_at(&mut outbox, 1_000);
+        assert_eq!(sent, vec![(1, Some(1))]);
+        outbox.complete_with(
+            1_000,
+            1,
+            Err(tg_error(
+                r#"{"ok":false,"error_code":429,"description":"Too Many Requests","parameters":{"retry_after":5}}"#,
+            )),
+        );
+        assert_eq!(outbox.queue.len(), 1);
+        let (sent, delay) = flush_at(&mut outbox, 1_000);
+        assert!(sent.is_empty());
+        assert_eq!(delay, Some(5_000));
+        assert!(outbox.take_undelivered().is_empty());
+
+        let (sent, _) = flush_at(&mut outbox, 6_000);
+        assert_eq!(sent, vec![(2, Some(1))]);
+        outbox.complete_with(
+            6_000,
+            2,
+            Err(tg_error(
+                r#"{"ok":false,"error_code":403,"description":"Forbidden: bot was blocked by the user"}"#,
+            )),
+        );
+        assert!(outbox.queue.is_empty());
+        assert!(outbox.in_flight.is_empty());
+        let undelivered = outbox.take_undelivered();
+        assert_eq!(undelivered.len(), 1);
+        assert_eq!(undelivered[0].chat_id, 1);
+    }
+
+    #[test]
+    fn backs_off_when_a_request_cant_be_sent() {
+        let mut outbox = Outbox::default();
+        outbox.push_photo(1, "/missing.png", None);
+        let delay = outbox.flush_with(1_000, |_, _| Err(anyhow::anyhow!("no such file")));
+        assert_eq!(delay, Some(BACKOFF_MS));
+        assert!(outbox.in_flight.is_empty());
+        assert_eq!(outbox.queue.len(), 1);
+    }
 }
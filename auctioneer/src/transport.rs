@@ -0,0 +1,393 @@
+use crate::protocol::Peers;
+use crate::render::Rendered;
+use crate::tg_api::{Api, Outbox};
+use frankenstein::{ChatId, InlineKeyboardMarkup, ParseMode, ReplyMarkup, SendMessageParams};
+use kinode_process_lib::timer;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// Messages kept per web conversation until the buyer's page picks them up
+const WEB_MAILBOX_CAPACITY: usize = 50;
+/// Web conversations the buyer's page hasn't polled for this long are dropped, in seconds
+const WEB_IDLE_EXPIRY: u64 = 24 * 3600;
+/// Messages and button presses a client can send per minute, across all its web conversations
+const WEB_INPUTS_PER_MINUTE: u32 = 10;
+
+/// A conversation with a buyer, qualified by the transport it runs over.
+/// Written as `tg:<chat id>`, `web:<id>`, `node:<id>` or `api`, a bare number is taken as a Telegram chat.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum ConversationId {
+    Telegram(i64),
+    Web(u64),
+    /// A buyer agent on another Kinode node, talking the barter protocol
+    Node(u64),
+    /// Offers issued through the admin API for a buyer without a conversation, nothing can be sent to it
+    Api,
+}
+
+impl fmt::Display for ConversationId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConversationId::Telegram(chat_id) => write!(f, "tg:{}", chat_id),
+            ConversationId::Web(id) => write!(f, "web:{}", id),
+            ConversationId::Node(id) => write!(f, "node:{}", id),
+            ConversationId::Api => write!(f, "api"),
+        }
+    }
+}
+
+impl FromStr for ConversationId {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        Ok(match s.split_once(':') {
+            Some(("tg", chat_id)) => ConversationId::Telegram(chat_id.parse()?),
+            Some(("web", id)) => ConversationId::Web(id.parse()?),
+            Some(("node", id)) => ConversationId::Node(id.parse()?),
+            Some((transport, _)) => return Err(anyhow::anyhow!("unknown transport {}", transport)),
+            None if s == "api" => ConversationId::Api,
+            None => ConversationId::Telegram(s.parse()?),
+        })
+    }
+}
+
+// as a string, so it can key JSON maps
+impl Serialize for ConversationId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for ConversationId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// Where the replies to a conversation go.
+pub trait ChatTransport {
+    /// Sends a message, with the buttons under it if the transport shows them.
+    fn send(
+        &mut self,
+        conversation: ConversationId,
+        rendered: Rendered,
+        keyboard: Option<InlineKeyboardMarkup>,
+    ) -> anyhow::Result<()>;
+
+    /// Sends a picture held in VFS, with an HTML caption.
+    /// Returns false if the transport doesn't show pictures.
+    fn send_photo(
+        &mut self,
+        _conversation: ConversationId,
+        _path: &str,
+        _caption: Option<String>,
+    ) -> anyhow::Result<bool> {
+        Ok(false)
+    }
+
+    /// Sends several pictures held in VFS together, without captions.
+    /// Returns false if the transport doesn't show pictures.
+    fn send_album(&mut self, _conversation: ConversationId, _paths: &[String]) -> anyhow::Result<bool> {
+        Ok(false)
+    }
+}
+
+impl<T: ChatTransport + ?Sized> ChatTransport for &mut T {
+    fn send(
+        &mut self,
+        conversation: ConversationId,
+        rendered: Rendered,
+        keyboard: Option<InlineKeyboardMarkup>,
+    ) -> anyhow::Result<()> {
+        (**self).send(conversation, rendered, keyboard)
+    }
+
+    fn send_photo(
+        &mut self,
+        conversation: ConversationId,
+        path: &str,
+        caption: Option<String>,
+    ) -> anyhow::Result<bool> {
+        (**self).send_photo(conversation, path, caption)
+    }
+
+    fn send_album(&mut self, conversation: ConversationId, paths: &[String]) -> anyhow::Result<bool> {
+        (**self).send_album(conversation, paths)
+    }
+}
+
+/// Every transport, sending to each conversation over the one it runs over.
+pub struct Transports<'a> {
+    pub telegram: Telegram<'a>,
+    pub web: &'a mut WebChat,
+    pub peers: &'a mut Peers,
+}
+
+impl Transports<'_> {
+    fn get(&mut self, conversation: ConversationId) -> anyhow::Result<&mut dyn ChatTransport> {
+        let transport: &mut dyn ChatTransport = match conversation {
+            ConversationId::Telegram(_) => &mut self.telegram,
+            ConversationId::Web(_) => &mut *self.web,
+            ConversationId::Node(_) => &mut *self.peers,
+            ConversationId::Api => return Err(anyhow::anyhow!("{} has nobody to send to", conversation)),
+        };
+        Ok(transport)
+    }
+}
+
+impl ChatTransport for Transports<'_> {
+    fn send(
+        &mut self,
+        conversation: ConversationId,
+        rendered: Rendered,
+        keyboard: Option<InlineKeyboardMarkup>,
+    ) -> anyhow::Result<()> {
+        self.get(conversation)?.send(conversation, rendered, keyboard)
+    }
+
+    fn send_photo(
+        &mut self,
+        conversation: ConversationId,
+        path: &str,
+        caption: Option<String>,
+    ) -> anyhow::Result<bool> {
+        self.get(conversation)?.send_photo(conversation, path, caption)
+    }
+
+    fn send_album(&mut self, conversation: ConversationId, paths: &[String]) -> anyhow::Result<bool> {
+        self.get(conversation)?.send_album(conversation, paths)
+    }
+}
+
+/// Telegram chats, messages go through the rate-limited outbox.
+pub struct Telegram<'a> {
+    pub api: &'a Api,
+    pub outbox: &'a mut Outbox,
+}
+
+impl Telegram<'_> {
+    /// Sends the queued messages the flood limits allow, and sets a timer to send the rest.
+    pub fn flush(&mut self) {
+        if let Some(delay) = self.outbox.flush(self.api) {
+            timer::set_timer(delay, None);
+        }
+    }
+}
+
+impl ChatTransport for Telegram<'_> {
+    /// Replies too long for a single message get split, the keyboard goes under the last part.
+    fn send(
+        &mut self,
+        conversation: ConversationId,
+        rendered: Rendered,
+        keyboard: Option<InlineKeyboardMarkup>,
+    ) -> anyhow::Result<()> {
+        let ConversationId::Telegram(chat_id) = conversation else {
+            return Err(anyhow::anyhow!("{} isn't a Telegram chat", conversation));
+        };
+        let messages = rendered.into_messages();
+        let last = messages.len().saturating_sub(1);
+        let mut keyboard = keyboard.map(ReplyMarkup::InlineKeyboardMarkup);
+        for (index, html) in messages.into_iter().enumerate() {
+            let mut params = SendMessageParams::builder()
+                .chat_id(ChatId::Integer(chat_id))
+                .text(html)
+                .parse_mode(ParseMode::Html)
+                .build();
+            if index == last {
+                params.reply_markup = keyboard.take();
+            }
+            self.outbox.push(&params)?;
+        }
+        self.flush();
+        Ok(())
+    }
+
+    fn send_photo(
+        &mut self,
+        conversation: ConversationId,
+        path: &str,
+        caption: Option<String>,
+    ) -> anyhow::Result<bool> {
+        let ConversationId::Telegram(chat_id) = conversation else {
+            return Err(anyhow::anyhow!("{} isn't a Telegram chat", conversation));
+        };
+        self.outbox.push_photo(chat_id, path, caption);
+        self.flush();
+        Ok(true)
+    }
+
+    fn send_album(&mut self, conversation: ConversationId, paths: &[String]) -> anyhow::Result<bool> {
+        let ConversationId::Telegram(chat_id) = conversation else {
+            return Err(anyhow::anyhow!("{} isn't a Telegram chat", conversation));
+        };
+        self.outbox.push_album(chat_id, paths);
+        self.flush();
+        Ok(true)
+    }
+}
+
+/// A button under a web chat message, it either sends its callback data back or opens its URL.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WebButton {
+    pub text: String,
+    pub callback: Option<String>,
+    pub url: Option<String>,
+}
+
+/// A message waiting for the buyer's page to pick it up.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WebMessage {
+    /// The message in the same HTML subset Telegram gets
+    pub html: String,
+    pub buttons: Vec<WebButton>,
+}
+
+/// Web chats from the buy page, the page polls `/chat` for the replies.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct WebChat {
+    mailboxes: HashMap<u64, VecDeque<WebMessage>>,
+    /// When the buyer's page last polled each conversation, in unix seconds
+    last_seen: HashMap<u64, u64>,
+    /// Inputs per client in the current minute, along with that minute
+    #[serde(skip)]
+    inputs: HashMap<Option<IpAddr>, (u64, u32)>,
+}
+
+impl WebChat {
+    /// Takes the messages waiting for the conversation, and notes that its buyer is still around.
+    pub fn take(&mut self, id: u64, now: u64) -> Vec<WebMessage> {
+        self.last_seen.insert(id, now);
+        self.mailboxes
+            .remove(&id)
+            .map(Vec::from)
+            .unwrap_or_default()
+    }
+
+    /// Counts a message or button press from the client, false if it sent too many this minute.
+    /// Clients whose address isn't known share a count.
+    pub fn admit(&mut self, client: Option<IpAddr>, now: u64) -> bool {
+        let minute = now / 60;
+        self.inputs.retain(|_, (counted_in, _)| *counted_in == minute);
+        let (_, count) = self.inputs.entry(client).or_insert((minute, 0));
+        if *count >= WEB_INPUTS_PER_MINUTE {
+            return false;
+        }
+        *count += 1;
+        true
+    }
+
+    /// Drops the conversations the buyer's page stopped polling, returning them.
+    pub fn expire_idle(&mut self, now: u64) -> Vec<u64> {
+        let expired: Vec<u64> = self
+            .last_seen
+            .iter()
+            .filter(|(_, last_seen)| **last_seen + WEB_IDLE_EXPIRY <= now)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in &expired {
+            self.last_seen.remove(id);
+        }
+        // nobody is going to pick up the messages for conversations nobody polls
+        let last_seen = &self.last_seen;
+        self.mailboxes.retain(|id, _| last_seen.contains_key(id));
+        expired
+    }
+}
+
+impl ChatTransport for WebChat {
+    fn send(
+        &mut self,
+        conversation: ConversationId,
+        rendered: Rendered,
+        keyboard: Option<InlineKeyboardMarkup>,
+    ) -> anyhow::Result<()> {
+        let ConversationId::Web(id) = conversation else {
+            return Err(anyhow::anyhow!("{} isn't a web chat", conversation));
+        };
+        let buttons = keyboard
+            .map(|keyboard| {
+                keyboard
+                    .inline_keyboard
+                    .into_iter()
+                    .flatten()
+                    .map(|button| WebButton {
+                        text: button.text,
+                        callback: button.callback_data,
+                        url: button.url,
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        let mailbox = self.mailboxes.entry(id).or_default();
+        mailbox.push_back(WebMessage {
+            html: rendered.to_html(),
+            buttons,
+        });
+        // buyers who left don't pile up messages forever
+        while mailbox.len() > WEB_MAILBOX_CAPACITY {
+            mailbox.pop_front();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_conversation_ids() {
+        assert_eq!("tg:-42".parse::<ConversationId>().unwrap(), ConversationId::Telegram(-42));
+        assert_eq!(" web:7 ".parse::<ConversationId>().unwrap(), ConversationId::Web(7));
+        assert_eq!("node:3".parse::<ConversationId>().unwrap(), ConversationId::Node(3));
+        assert_eq!("12345".parse::<ConversationId>().unwrap(), ConversationId::Telegram(12345));
+        assert_eq!("api".parse::<ConversationId>().unwrap(), ConversationId::Api);
+        assert!("irc:1".parse::<ConversationId>().is_err());
+        assert!("web:-1".parse::<ConversationId>().is_err());
+        assert!("tg:".parse::<ConversationId>().is_err());
+
+        for id in [
+            ConversationId::Telegram(-1),
+            ConversationId::Web(2),
+            ConversationId::Node(3),
+            ConversationId::Api,
+        ] {
+            assert_eq!(id.to_string().parse::<ConversationId>().unwrap(), id);
+            let json = serde_json::to_string(&id).unwrap();
+            assert_eq!(serde_json::from_str::<ConversationId>(&json).unwrap(), id);
+        }
+    }
+
+    #[test]
+    fn throttles_clients() {
+        let mut web_chat = WebChat::default();
+        let client = Some(IpAddr::from([10, 0, 0, 1]));
+        for _ in 0..WEB_INPUTS_PER_MINUTE {
+            assert!(web_chat.admit(client, 60));
+        }
+        assert!(!web_chat.admit(client, 119));
+        assert!(web_chat.admit(Some(IpAddr::from([10, 0, 0, 2])), 119));
+        assert!(web_chat.admit(client, 120));
+    }
+
+    #[test]
+    fn expires_idle_conversations() {
+        let mut web_chat = WebChat::default();
+        web_chat.take(1, 0);
+        web_chat.take(2, WEB_IDLE_EXPIRY);
+        for id in [1, 2] {
+            web_chat
+                .send(ConversationId::Web(id), Rendered::text("hi"), None)
+                .unwrap();
+        }
+        assert_eq!(web_chat.expire_idle(WEB_IDLE_EXPIRY), vec![1]);
+        assert!(web_chat.take(1, WEB_IDLE_EXPIRY).is_empty());
+        assert_eq!(web_chat.take(2, WEB_IDLE_EXPIRY).len(), 1);
+    }
+}
@@ -0,0 +1,215 @@
+use crate::llm::Usage;
+use crate::structs::NFTKey;
+use crate::transport::ConversationId;
+use alloy_primitives::U256;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+/// Seconds per day
+const DAY: u64 = 86_400;
+
+/// Caps on the LLM spend, in millionths of a USD. Replying stops once one is exceeded.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct BudgetCaps {
+    pub daily: Option<u64>,
+    pub monthly: Option<u64>,
+}
+
+/// A completed sale, with what the LLM cost to get there.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SaleRecord {
+    pub nft_key: NFTKey,
+    pub name: String,
+    pub price: U256,
+    pub buyer: String,
+    /// LLM cost attributed to the listing up to the sale, in millionths of a USD
+    pub llm_cost_micros: u64,
+    pub sold_at: u64,
+    /// The log of the purchase, if the node delivered it with its position
+    pub log: Option<SaleLog>,
+}
+
+/// Where a purchase got logged on chain, to recognize logs that get delivered again.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SaleLog {
+    pub tx_hash: String,
+    pub log_index: u64,
+    pub block: u64,
+}
+
+/// Records of the tokens spent, per chat, per listing, per day, and per sale.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct UsageLedger {
+    pub per_chat: HashMap<ConversationId, Usage>,
+    pub per_listing: HashMap<NFTKey, Usage>,
+    /// Cost per day since the unix epoch, in millionths of a USD
+    pub spend_by_day: BTreeMap<u64, u64>,
+    pub sales: Vec<SaleRecord>,
+    pub caps: BudgetCaps,
+    /// The last block whose purchase logs got handled, per chain id. Subscriptions resume from it.
+    pub synced_blocks: HashMap<u64, u64>,
+}
+
+impl UsageLedger {
+    /// Records the usage of a chat turn, splitting it evenly across the NFTs in the chat.
+    pub fn record(&mut self, chat_id: ConversationId, nft_keys: &[NFTKey], usage: &Usage, now: u64) {
+        self.per_chat.entry(chat_id).or_default().add(usage);
+        *self.spend_by_day.entry(now / DAY).or_default() += usage.cost_micros;
+
+        if nft_keys.is_empty() {
+            return;
+        }
+        let share = nft_keys.len() as u64;
+        let usage_share = Usage {
+            prompt_tokens: usage.prompt_tokens / share,
+            completion_tokens: usage.completion_tokens / share,
+            cost_micros: usage.cost_micros / share,
+        };
+        for nft_key in nft_keys {
+            self.per_listing
+                .entry(nft_key.clone())
+                .or_default()
+                .add(&usage_share);
+        }
+    }
+
+    /// Records a completed sale along with the LLM cost of its listing.
+    pub fn record_sale(
+        &mut self,
+        nft_key: NFTKey,
+        name: String,
+        price: U256,
+        buyer: String,
+        log: Option<SaleLog>,
+        now: u64,
+    ) {
+        let llm_cost_micros = self
+            .per_listing
+            .get(&nft_key)
+            .map(|usage| usage.cost_micros)
+            .unwrap_or_default();
+        self.sales.push(SaleRecord {
+            nft_key,
+            name,
+            price,
+            buyer,
+            llm_cost_micros,
+            sold_at: now,
+            log,
+        });
+    }
+
+    /// Whether the sale of the log got recorded already.
+    pub fn has_sale(&self, log: &SaleLog) -> bool {
+        self.sales.iter().any(|sale| {
+            sale.log
+                .as_ref()
+                .is_some_and(|recorded| recorded.tx_hash == log.tx_hash && recorded.log_index == log.log_index)
+        })
+    }
+
+    /// Notes that the logs of the chain got handled up to the block.
+    pub fn sync_block(&mut self, chain: u64, block: u64) {
+        let synced = self.synced_blocks.entry(chain).or_default();
+        *synced = (*synced).max(block);
+    }
+
+    /// Whether the daily or the monthly budget has been used up.
+    pub fn over_budget(&self, now: u64) -> bool {
+        let over = |cap: Option<u64>, spend: u64| cap.map(|cap| spend >= cap).unwrap_or_default();
+        over(self.caps.daily, self.spend_today(now)) || over(self.caps.monthly, self.spend_this_month(now))
+    }
+
+    /// Cost of the current day, in millionths of a USD.
+    pub fn spend_today(&self, now: u64) -> u64 {
+        self.spend_by_day.get(&(now / DAY)).copied().unwrap_or_default()
+    }
+
+    /// Cost of the current calendar month, in millionths of a USD.
+    pub fn spend_this_month(&self, now: u64) -> u64 {
+        let today = now / DAY;
+        let month = civil_month(today);
+        self.spend_by_day
+            .range(..=today)
+            .rev()
+            .take_while(|(day, _)| civil_month(**day) == month)
+            .map(|(_, cost)| cost)
+            .sum()
+    }
+}
+
+/// The year and month of a day since the unix epoch, in the proleptic Gregorian calendar.
+fn civil_month(days_since_epoch: u64) -> (i64, u64) {
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days_since_epoch as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Days since the unix epoch of a date
+    fn day(year: i64, month: u64, day: u64) -> u64 {
+        // http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+        let year = if month <= 2 { year - 1 } else { year };
+        let era = year.div_euclid(400);
+        let yoe = year.rem_euclid(400);
+        let mp = (month as i64 + 9) % 12;
+        let doy = (153 * mp + 2) / 5 + day as i64 - 1;
+        let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+        (era * 146_097 + doe - 719_468) as u64
+    }
+
+    #[test]
+    fn finds_the_month_of_a_day() {
+        assert_eq!(civil_month(0), (1970, 1));
+        assert_eq!(civil_month(30), (1970, 1));
+        assert_eq!(civil_month(31), (1970, 2));
+        assert_eq!(civil_month(day(2024, 2, 29)), (2024, 2));
+        assert_eq!(civil_month(day(2024, 3, 1)), (2024, 3));
+        assert_eq!(civil_month(day(2023, 12, 31)), (2023, 12));
+        assert_eq!(civil_month(day(2024, 1, 1)), (2024, 1));
+        assert_eq!(civil_month(day(2100, 3, 1) - 1), (2100, 2));
+    }
+
+    #[test]
+    fn sums_the_spend_of_the_current_month() {
+        let mut ledger = UsageLedger::default();
+        ledger.spend_by_day.insert(day(2024, 1, 31), 5);
+        ledger.spend_by_day.insert(day(2024, 2, 1), 7);
+        ledger.spend_by_day.insert(day(2024, 2, 29), 11);
+        let now = day(2024, 2, 29) * DAY + 3_600;
+        assert_eq!(ledger.spend_today(now), 11);
+        assert_eq!(ledger.spend_this_month(now), 18);
+    }
+
+    #[test]
+    fn recognizes_recorded_sales() {
+        let mut ledger = UsageLedger::default();
+        let log = SaleLog {
+            tx_hash: "0xabc".to_string(),
+            log_index: 2,
+            block: 100,
+        };
+        let nft_key = NFTKey {
+            id: 1,
+            address: "0xdef".to_string(),
+            chain: 10,
+        };
+        ledger.record_sale(nft_key, "Ape".to_string(), U256::from(1), "0x123".to_string(), Some(log.clone()), 0);
+        assert!(ledger.has_sale(&log));
+        assert!(!ledger.has_sale(&SaleLog { log_index: 3, ..log }));
+
+        ledger.sync_block(10, 100);
+        ledger.sync_block(10, 90);
+        assert_eq!(ledger.synced_blocks[&10], 100);
+    }
+}
@@ -0,0 +1,157 @@
+// Chat with the seller's bot from the buy page, over the auctioneer's /chat endpoint.
+// Self-contained so it works next to the bundled app: it adds a button that opens a chat panel.
+(function () {
+  const script = document.currentScript
+  const base = script ? script.src.replace(/[^/]*$/, '') : '/main:barter:appattacc.os/'
+  const endpoint = base + 'chat'
+  const storageKey = 'barter-web-chat'
+  // poll quickly while waiting on a reply, slowly otherwise
+  const FAST_POLL_MS = 1500
+  const SLOW_POLL_MS = 15000
+  const REPLY_WAIT_MS = 60000
+
+  let conversation = localStorage.getItem(storageKey)
+  let waitingSince = 0
+  let pollTimer = null
+  let open = false
+
+  const style = document.createElement('style')
+  style.textContent = `
+    .barter-chat-toggle { position: fixed; right: 20px; bottom: 20px; z-index: 1000; border: 0; border-radius: 24px;
+      padding: 12px 20px; background: #111; color: #fff; font: 600 15px 'Varela Round', sans-serif; cursor: pointer; }
+    .barter-chat { position: fixed; right: 20px; bottom: 76px; z-index: 1000; width: 340px; max-width: calc(100vw - 40px);
+      height: 460px; max-height: calc(100vh - 100px); display: none; flex-direction: column; background: #fff;
+      border-radius: 12px; box-shadow: 0 8px 30px rgba(0, 0, 0, 0.25); font: 14px 'Varela Round', sans-serif; color: #111; }
+    .barter-chat.open { display: flex; }
+    .barter-chat-log { flex: 1; overflow-y: auto; padding: 12px; display: flex; flex-direction: column; gap: 8px; }
+    .barter-chat-message { max-width: 85%; padding: 8px 12px; border-radius: 12px; white-space: pre-wrap; word-wrap: break-word; }
+    .barter-chat-message.bot { align-self: flex-start; background: #f0f0f0; }
+    .barter-chat-message.buyer { align-self: flex-end; background: #111; color: #fff; }
+    .barter-chat-buttons { display: flex; flex-wrap: wrap; gap: 6px; margin-top: 6px; }
+    .barter-chat-buttons button { border: 1px solid #111; border-radius: 8px; background: #fff; padding: 4px 10px; cursor: pointer; }
+    .barter-chat-form { display: flex; border-top: 1px solid #eee; }
+    .barter-chat-form input { flex: 1; border: 0; padding: 12px; font: inherit; outline: none; border-radius: 0 0 0 12px; }
+    .barter-chat-form button { border: 0; background: none; padding: 0 16px; font: inherit; font-weight: 600; cursor: pointer; }
+  `
+  document.head.appendChild(style)
+
+  const toggle = document.createElement('button')
+  toggle.className = 'barter-chat-toggle'
+  toggle.textContent = 'Chat with the seller'
+
+  const panel = document.createElement('div')
+  panel.className = 'barter-chat'
+  const log = document.createElement('div')
+  log.className = 'barter-chat-log'
+  const form = document.createElement('form')
+  form.className = 'barter-chat-form'
+  const input = document.createElement('input')
+  input.placeholder = 'Make an offer...'
+  input.maxLength = 1000
+  const send = document.createElement('button')
+  send.type = 'submit'
+  send.textContent = 'Send'
+  form.append(input, send)
+  panel.append(log, form)
+
+  function show(className, build) {
+    const message = document.createElement('div')
+    message.className = 'barter-chat-message ' + className
+    build(message)
+    log.appendChild(message)
+    log.scrollTop = log.scrollHeight
+  }
+
+  function showReply(reply) {
+    show('bot', (message) => {
+      // the bot's HTML is the subset Telegram shows, with the buyer's text escaped
+      message.innerHTML = reply.html
+      message.querySelectorAll('a').forEach((link) => {
+        link.target = '_blank'
+        link.rel = 'noopener'
+      })
+      if (!reply.buttons || reply.buttons.length === 0) return
+      const buttons = document.createElement('div')
+      buttons.className = 'barter-chat-buttons'
+      reply.buttons.forEach((button) => {
+        const element = document.createElement('button')
+        element.textContent = button.text
+        element.onclick = () => {
+          if (button.url) {
+            window.open(button.url, '_blank', 'noopener')
+          } else if (button.callback) {
+            buttons.remove()
+            post({ callback: button.callback })
+          }
+        }
+        buttons.appendChild(element)
+      })
+      message.appendChild(buttons)
+    })
+  }
+
+  async function post(body) {
+    const sending = body.text || body.callback
+    if (sending) waitingSince = Date.now()
+    try {
+      const response = await fetch(endpoint, {
+        method: 'POST',
+        headers: { 'Content-Type': 'application/json' },
+        body: JSON.stringify(conversation ? { ...body, conversation } : body),
+      })
+      const answer = await response.json().catch(() => ({}))
+      if (!response.ok) {
+        show('bot', (message) => (message.textContent = answer.message || 'Something went wrong, please try again.'))
+        waitingSince = 0
+        return
+      }
+      if (answer.conversation && answer.conversation !== conversation) {
+        conversation = answer.conversation
+        localStorage.setItem(storageKey, conversation)
+      }
+      if (answer.messages && answer.messages.length > 0) {
+        answer.messages.forEach(showReply)
+        waitingSince = 0
+      }
+    } catch (e) {
+      waitingSince = 0
+    }
+    schedulePoll()
+  }
+
+  function schedulePoll() {
+    clearTimeout(pollTimer)
+    if (!open || !conversation) return
+    const waiting = waitingSince && Date.now() - waitingSince < REPLY_WAIT_MS
+    pollTimer = setTimeout(() => post({}), waiting ? FAST_POLL_MS : SLOW_POLL_MS)
+  }
+
+  toggle.onclick = () => {
+    open = !open
+    panel.classList.toggle('open', open)
+    if (open) {
+      input.focus()
+      if (conversation) post({})
+    } else {
+      clearTimeout(pollTimer)
+    }
+  }
+
+  form.onsubmit = (event) => {
+    event.preventDefault()
+    const text = input.value.trim()
+    if (!text) return
+    input.value = ''
+    show('buyer', (message) => (message.textContent = text))
+    post({ text })
+  }
+
+  function mount() {
+    document.body.append(panel, toggle)
+  }
+  if (document.body) {
+    mount()
+  } else {
+    document.addEventListener('DOMContentLoaded', mount)
+  }
+})()